@@ -1,3 +1,4 @@
+use commons::session::SessionLimits;
 use serde::Deserialize;
 use std::{collections::HashMap, fs, path::PathBuf};
 use thiserror::Error;
@@ -19,11 +20,68 @@ pub struct Config {
 #[derive(Deserialize, Clone)]
 pub struct ClientConfig {
     pub private_key: String,
+    #[serde(default)]
+    pub compression: CompressionMode,
+    #[serde(default)]
+    pub links: LinkPolicies,
+    #[serde(default)]
+    pub session: SessionLimits,
+}
+
+/// What a backup does with the entries that aren't plain files or directories.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct LinkPolicies {
+    pub symlinks: LinkPolicy,
+    /// `follow` uploads every name of a file as its own copy.
+    pub hardlinks: LinkPolicy,
+    /// FIFOs and device nodes, they have no content to follow.
+    pub special_files: SpecialFilePolicy,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkPolicy {
+    #[default]
+    Preserve,
+    Follow,
+    Skip,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialFilePolicy {
+    #[default]
+    Preserve,
+    Skip,
+}
+
+/// Codec asked for on file transfers, `lz4` trades ratio for speed.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionMode {
+    #[default]
+    Zstd,
+    Lz4,
+    None,
+}
+
+impl From<CompressionMode> for protos::Compression {
+    fn from(mode: CompressionMode) -> Self {
+        match mode {
+            CompressionMode::Zstd => protos::Compression::Zstd,
+            CompressionMode::Lz4 => protos::Compression::Lz4,
+            CompressionMode::None => protos::Compression::None,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Peer {
     pub public_key: String,
+    /// Command whose stdin and stdout carry the session instead of a TCP connection, such as
+    /// `ssh backup-host zen-sync-server --stdio`.
+    pub command: Option<String>,
 }
 
 pub fn get_config(path: &PathBuf) -> Result<Config, ConfigParsingError> {
@@ -1,14 +1,15 @@
+use commons::{
+    packeter::{self, ReadPacketError, WritePacketError},
+    session::{self, Capabilities},
+};
 use snow::{HandshakeState, TransportState};
 use thiserror::Error;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 #[derive(Error, Debug)]
 pub enum ReadInitiatorError {
     #[error("Failed to read packet: {0}")]
-    ReadError(#[from] tokio::io::Error),
+    ReadError(#[from] ReadPacketError),
     #[error("Noise protocol error: {0}")]
     NoiseError(#[from] snow::Error),
 }
@@ -31,39 +32,81 @@ pub enum HandshakeError {
     TransportModeError(#[from] snow::Error),
 }
 
+#[derive(Error, Debug)]
+pub enum HelloError {
+    #[error("Failed to send hello: {0}")]
+    WriteError(#[from] WritePacketError),
+    #[error("Failed to read hello acknowledgement: {0}")]
+    ReadError(#[from] ReadPacketError),
+    #[error("Invalid hello acknowledgement: {0}")]
+    DecodeError(#[from] prost::DecodeError),
+    #[error("Server rejected the session: {0}")]
+    Rejected(String),
+    #[error("Server speaks unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
+}
+
 async fn read_receiver(
-    buf_reader: &mut BufReader<TcpStream>,
+    stream: &mut (impl AsyncRead + Unpin),
     noise: &mut HandshakeState,
 ) -> Result<(), ReadInitiatorError> {
-    let length = buf_reader.read_u32().await?;
-    let mut buffer = vec![0u8; length as usize];
-
-    buf_reader.read_exact(&mut buffer).await?;
-    noise.read_message(&mut buffer, &mut [])?;
+    let message = packeter::read_handshake_message(stream).await?;
+    noise.read_message(&message, &mut [])?;
 
     Ok(())
 }
 
 async fn do_initiator(
-    buf_reader: &mut BufReader<TcpStream>,
+    stream: &mut (impl AsyncWrite + Unpin),
     noise: &mut HandshakeState,
 ) -> Result<(), DoReceiverError> {
     let mut write_buffer = vec![0u8; 65535];
     let len = noise.write_message(&[], &mut write_buffer)?;
 
-    buf_reader.write_u32(len as u32).await?;
-    buf_reader.write_all(&write_buffer[..len]).await?;
+    stream.write_u32(len as u32).await?;
+    stream.write_all(&write_buffer[..len]).await?;
 
     Ok(())
 }
 
 pub async fn handle_handshake(
-    buf_reader: &mut BufReader<TcpStream>,
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
     mut noise: HandshakeState,
 ) -> Result<TransportState, HandshakeError> {
-    do_initiator(buf_reader, &mut noise).await?;
+    do_initiator(stream, &mut noise).await?;
 
-    read_receiver(buf_reader, &mut noise).await?;
+    read_receiver(stream, &mut noise).await?;
 
     Ok(noise.into_transport_mode()?)
 }
+
+pub async fn handle_hello(
+    handler: &mut packeter::Handler<impl AsyncRead + AsyncWrite + Unpin>,
+) -> Result<Capabilities, HelloError> {
+    let supported = Capabilities::supported();
+    let hello = protos::create_hello(
+        session::PROTOCOL_VERSION,
+        String::from(session::SOFTWARE_VERSION),
+        supported.to_wire(),
+    );
+    handler
+        .write_packet(&protos::serialize_hello(hello))
+        .await?;
+
+    let hello_ack = handler.read_packet().await?;
+    let hello_ack = protos::deserialize_hello_ack(&hello_ack)?;
+    if !hello_ack.accepted {
+        return Err(HelloError::Rejected(hello_ack.reason));
+    }
+
+    if !session::is_compatible(hello_ack.protocol_version) {
+        return Err(HelloError::UnsupportedVersion(hello_ack.protocol_version));
+    }
+
+    println!(
+        "Connected to zen-sync-server {} (protocol v{})",
+        hello_ack.software_version, hello_ack.protocol_version
+    );
+
+    Ok(supported.intersection(&Capabilities::from_wire(&hello_ack.capabilities)))
+}
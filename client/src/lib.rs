@@ -1,36 +1,163 @@
 mod config;
 mod handshake_handler;
+mod server_error;
+
+pub use server_error::ServerError;
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    io::SeekFrom,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use std::{collections::VecDeque, path::PathBuf};
-
-use anyhow::Context;
+use anyhow::{bail, Context};
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
+use config::{LinkPolicies, LinkPolicy, SpecialFilePolicy};
 use dirs_next::config_dir;
 use tokio::{
     fs,
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::TcpStream,
+    process::Command,
     sync::mpsc,
+    task::JoinSet,
 };
 
-use commons::{file_manager, packeter};
+use commons::{
+    chunker,
+    compression::{self, Compressor},
+    delta, envelope, file_manager,
+    integrity::{HashingWriter, IntegrityError},
+    mux::{self, Multiplexer, Side},
+    packeter,
+    session::Capabilities,
+};
 use protos::{
-    create_file_get, create_file_sync, File, FileGet, FileType, ResponseGet, ResponseSync,
+    create_file_get, create_file_sync, Body, Capability, Chunk, Compression, ConflictPolicy, File,
+    FileGet, FileMove, FileType, PartialTransfer, RequestList, ResponseGet, ResponseList,
+    ResponseMove, ResponseSnapshots, ResponseStat, ResponseSync, ResponseTrash, ResponseVersions,
+    Snapshot,
 };
 
-async fn send_file(handler: &mut packeter::Handler, file_path: &PathBuf) -> anyhow::Result<()> {
-    let file = tokio::fs::File::open(&file_path).await?;
-    let metadata = file.metadata().await?;
-    let file_stats =
-        file_manager::create_from_metadata(&file_path, Some(&file_path), &metadata).await?;
-    let size = file_stats.size.clone();
-    let request_add = protos::create_request_add(file_stats, size);
+/// Files transferred at once when adding or syncing a folder.
+const MAX_CONCURRENT_TRANSFERS: usize = 8;
+/// Uploads from this size on are worth resuming after an interruption.
+const RESUMABLE_THRESHOLD: u64 = 1024 * 1024;
+/// Smaller files are sent right away, offering them would cost about as much as sending them.
+const DEDUP_THRESHOLD: u64 = 4096;
+
+struct Session {
+    mux: Multiplexer,
+    capabilities: Capabilities,
+    /// Codec asked for on every transfer, dropped for files that won't shrink.
+    compression: Compression,
+    links: LinkPolicies,
+    last_request_id: AtomicU64,
+    established: Instant,
+}
 
-    let request = protos::serialize_request_add(request_add);
-    handler.write_packet(&request).await?;
+impl Session {
+    fn new(
+        mux: Multiplexer,
+        capabilities: Capabilities,
+        compression: Compression,
+        links: LinkPolicies,
+    ) -> Self {
+        Self {
+            mux,
+            capabilities,
+            compression,
+            links,
+            last_request_id: AtomicU64::new(0),
+            established: Instant::now(),
+        }
+    }
 
-    let mut file_reader = BufReader::new(file);
+    fn next_request_id(&self) -> u64 {
+        self.last_request_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Transfers in flight, `MAX_CONCURRENT_TRANSFERS` at most.
+#[derive(Default)]
+struct TransferQueue {
+    tasks: JoinSet<anyhow::Result<()>>,
+}
+
+impl TransferQueue {
+    /// Starts `transfer` once there is room for it, failing if a finished one did.
+    async fn push<Fut>(&mut self, transfer: Fut) -> anyhow::Result<()>
+    where
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        if self.tasks.len() >= MAX_CONCURRENT_TRANSFERS {
+            if let Some(result) = self.tasks.join_next().await {
+                result??;
+            }
+        }
+
+        self.tasks.spawn(transfer);
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> anyhow::Result<()> {
+        while let Some(result) = self.tasks.join_next().await {
+            result??;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `transfer` on every item, keeping at most `MAX_CONCURRENT_TRANSFERS` in flight.
+async fn run_concurrently<T, F, Fut>(items: Vec<T>, transfer: F) -> anyhow::Result<()>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut queue = TransferQueue::default();
+    for item in items {
+        queue.push(transfer(item)).await?;
+    }
+
+    queue.finish().await
+}
+
+fn unexpected_message(expected: &str, body: &Body) -> anyhow::Error {
+    anyhow::anyhow!("Expected {}, got {}", expected, envelope::name(body))
+}
+
+async fn wait_for_ack(stream: &mut mux::Stream, request_id: u64) -> anyhow::Result<()> {
+    match server_error::read_response(stream, request_id).await? {
+        Body::ResponseAck(_) => Ok(()),
+        body => Err(unexpected_message("an acknowledgement", &body)),
+    }
+}
+
+async fn send_delta(
+    stream: &mut mux::Stream,
+    file_reader: &mut BufReader<fs::File>,
+    compressor: &mut Compressor,
+    request_id: u64,
+) -> anyhow::Result<()> {
+    let response = match server_error::read_response(stream, request_id).await? {
+        Body::ResponseSignature(response) => response,
+        body => return Err(unexpected_message("a signature", &body)),
+    };
+    let header = response.signature.context("No signature in response")?;
+    let signature = delta::receive_signature_blocks(stream, &header).await?;
+
+    let mut generator = delta::DeltaGenerator::new(signature);
 
     let mut read_buf = [0u8; 32768];
     loop {
@@ -39,42 +166,498 @@ async fn send_file(handler: &mut packeter::Handler, file_path: &PathBuf) -> anyh
             break;
         }
 
-        handler.write_packet(&read_buf[..n]).await?;
+        for operation in generator.update(&read_buf[..n]) {
+            let operation = compressor.encode_operation(operation);
+            envelope::send(stream, Body::DeltaOperation(operation)).await?;
+        }
     }
 
+    for operation in generator.finish() {
+        let operation = compressor.encode_operation(operation);
+        envelope::send(stream, Body::DeltaOperation(operation)).await?;
+    }
+
+    envelope::send(stream, Body::EndOfFile(protos::EndOfFile {})).await?;
+
     Ok(())
 }
+
+/// Uploads of the same path share a transfer id, so a later session can pick up where
+/// an interrupted one stopped.
+fn transfer_id(file_path: &Path) -> String {
+    blake3::hash(file_path.as_os_str().as_bytes())
+        .to_hex()
+        .to_string()
+}
+
+/// Asks the server how much of an interrupted upload it kept, 0 if it doesn't match the file.
+async fn resume_offset(
+    session: &Session,
+    transfer_id: &str,
+    file_path: &Path,
+    size: u64,
+) -> anyhow::Result<u64> {
+    let request_id = session.next_request_id();
+    let request_resume = protos::create_request_resume(String::from(transfer_id), request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestResume(request_resume)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseResume(response) => response,
+        body => return Err(unexpected_message("a resume response", &body)),
+    };
+    let partial = match response.partial {
+        Some(partial) if partial.offset > 0 && partial.offset <= size => partial,
+        _ => return Ok(0),
+    };
+
+    if file_manager::hash_prefix(file_path, partial.offset).await? != partial.hash {
+        return Ok(0);
+    }
+
+    println!(
+        "Resuming upload of {} at {} bytes",
+        file_path.display(),
+        partial.offset
+    );
+
+    Ok(partial.offset)
+}
+
+/// Offers the hash of a file to the server, `true` if it stored the file from content it had.
+async fn offer_content(session: &Session, file: File) -> anyhow::Result<bool> {
+    let request_id = session.next_request_id();
+    let request_offer = protos::create_request_offer(file, request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestOffer(request_offer)).await?;
+
+    match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseOffer(response) => Ok(response.present),
+        body => Err(unexpected_message("an offer response", &body)),
+    }
+}
+
+/// Uploads a file as chunks, sending only the ones the server doesn't have yet.
+async fn send_chunks(
+    session: &Session,
+    file_path: &Path,
+    mut file: fs::File,
+    file_stats: File,
+    chunks: Vec<Chunk>,
+) -> anyhow::Result<()> {
+    let compression = compression::choose(session.compression, &session.capabilities, file_path);
+
+    let request_id = session.next_request_id();
+    let request_chunks =
+        protos::create_request_chunks(file_stats, chunks.len() as u64, compression, request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestChunks(request_chunks)).await?;
+    chunker::send_chunk_list(&mut stream, &chunks).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseChunks(response) => response,
+        body => return Err(unexpected_message("a chunks response", &body)),
+    };
+
+    let offsets: Vec<u64> = chunks
+        .iter()
+        .scan(0, |offset, chunk| {
+            let start = *offset;
+            *offset += chunk.size as u64;
+            Some(start)
+        })
+        .collect();
+
+    let mut compressor = Compressor::new(compression);
+    let mut data = Vec::new();
+    for index in &response.missing {
+        let index = *index as usize;
+        let chunk = chunks
+            .get(index)
+            .context("Server asked for a chunk the file doesn't have")?;
+
+        data.resize(chunk.size as usize, 0);
+        file.seek(SeekFrom::Start(offsets[index])).await?;
+        file.read_exact(&mut data).await?;
+
+        for frame in data.chunks(32768) {
+            envelope::send(&mut stream, Body::Data(compressor.encode(frame))).await?;
+        }
+    }
+
+    envelope::send(&mut stream, Body::EndOfFile(protos::EndOfFile {})).await?;
+
+    println!(
+        "Sent {} of the {} chunks of {}",
+        response.missing.len(),
+        chunks.len(),
+        file_path.display()
+    );
+
+    // Only report the file as backed up once the server has committed it
+    wait_for_ack(&mut stream, request_id).await
+}
+
+async fn send_file(session: &Session, file_path: &PathBuf) -> anyhow::Result<()> {
+    let mut file = file_manager::open_noatime(file_path).await?;
+    let metadata = file.metadata().await?;
+    let chunked = session.capabilities.has(Capability::Chunks)
+        && metadata.len() >= chunker::CHUNKING_THRESHOLD;
+
+    // Cutting the file into chunks hashes it along the way
+    let (file_stats, chunks) = if chunked {
+        let chunked_file = chunker::chunk_file(file_path).await?;
+        let mut file_stats =
+            file_manager::create_from_metadata(&file_path, Some(&file_path), &metadata, false)
+                .await?;
+        file_stats.hash = Some(chunked_file.hash);
+        (file_stats, chunked_file.chunks)
+    } else {
+        let file_stats =
+            file_manager::create_from_metadata(&file_path, Some(&file_path), &metadata, true)
+                .await?;
+        (file_stats, Vec::new())
+    };
+    let size = file_stats.size;
+
+    if session.capabilities.has(Capability::Dedup)
+        && size >= DEDUP_THRESHOLD
+        && offer_content(session, file_stats.clone()).await?
+    {
+        println!("{} already present on the server", file_path.display());
+        return Ok(());
+    }
+
+    if chunked {
+        return send_chunks(session, file_path, file, file_stats, chunks).await;
+    }
+
+    let use_delta = session.capabilities.has(Capability::Delta) && size >= delta::DELTA_THRESHOLD;
+
+    let (transfer_id, offset) =
+        if session.capabilities.has(Capability::Resume) && size >= RESUMABLE_THRESHOLD {
+            let transfer_id = transfer_id(file_path);
+            let offset = resume_offset(session, &transfer_id, file_path, size).await?;
+            (transfer_id, offset)
+        } else {
+            (String::new(), 0)
+        };
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let compression = compression::choose(session.compression, &session.capabilities, file_path);
+
+    let request_id = session.next_request_id();
+    let request_add = protos::create_request_add(
+        file_stats,
+        size - offset,
+        use_delta,
+        transfer_id,
+        offset,
+        compression,
+        request_id,
+    );
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestAdd(request_add)).await?;
+
+    let mut file_reader = BufReader::new(file);
+    let mut compressor = Compressor::new(compression);
+
+    if use_delta {
+        send_delta(&mut stream, &mut file_reader, &mut compressor, request_id).await?;
+    } else {
+        let mut read_buf = vec![0u8; 32768];
+        loop {
+            let n = file_reader.read(&mut read_buf).await?;
+            if n == 0 {
+                break;
+            }
+
+            envelope::send(&mut stream, Body::Data(compressor.encode(&read_buf[..n]))).await?;
+        }
+
+        envelope::send(&mut stream, Body::EndOfFile(protos::EndOfFile {})).await?;
+    }
+
+    // Only report the file as backed up once the server has committed it
+    wait_for_ack(&mut stream, request_id).await
+}
+
+/// Uploads an entry that is recreated from its metadata alone.
+async fn send_node(session: &Session, file: File) -> anyhow::Result<()> {
+    let request_id = session.next_request_id();
+    let request_add = protos::create_request_add(
+        file,
+        0,
+        false,
+        String::new(),
+        0,
+        Compression::None,
+        request_id,
+    );
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestAdd(request_add)).await?;
+
+    wait_for_ack(&mut stream, request_id).await
+}
+
+/// What a backup is made of, nodes go once the files their hardlinks point to are stored.
+#[derive(Default)]
+struct Backup {
+    files: Vec<PathBuf>,
+    nodes: Vec<File>,
+    /// First name seen of every file that has several, by device and inode.
+    hardlinks: HashMap<(u64, u64), PathBuf>,
+    /// Directories already walked, so that followed symlinks can't loop.
+    visited: HashSet<(u64, u64)>,
+}
+
 // TODO: SPACES
-async fn send_folder(handler: &mut packeter::Handler, folder: &PathBuf) -> anyhow::Result<()> {
-    let mut dir = fs::read_dir(folder).await?;
-    while let Ok(entry) = dir.next_entry().await {
-        let entry = match entry {
-            Some(entry) => entry,
-            None => break,
+/// Adds `path` to the backup as the link policies say, walking it if it is a directory.
+async fn plan_entry(session: &Session, path: PathBuf, backup: &mut Backup) -> anyhow::Result<()> {
+    let links = session.capabilities.has(Capability::Links);
+    let mut metadata = fs::symlink_metadata(&path).await?;
+
+    if metadata.is_symlink() {
+        match session.links.symlinks {
+            LinkPolicy::Preserve if links => {
+                // Link targets are sent as strings
+                if fs::read_link(&path).await?.to_str().is_none() {
+                    println!(
+                        "Skipping symlink {}, its target isn't valid UTF-8",
+                        path.display()
+                    );
+                    return Ok(());
+                }
+
+                let file = file_manager::create_from_metadata(&path, Some(&path), &metadata, false)
+                    .await?;
+                backup.nodes.push(file);
+                return Ok(());
+            }
+            LinkPolicy::Follow => match fs::metadata(&path).await {
+                Ok(target_metadata) => metadata = target_metadata,
+                Err(e) => {
+                    println!("Skipping dangling symlink {}: {}", path.display(), e);
+                    return Ok(());
+                }
+            },
+            _ => {
+                println!("Skipping symlink {}", path.display());
+                return Ok(());
+            }
+        }
+    }
+
+    if metadata.is_dir() {
+        if backup.visited.insert((metadata.dev(), metadata.ino())) {
+            let mut dir = fs::read_dir(&path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                Box::pin(plan_entry(session, entry.path(), backup)).await?;
+            }
+        }
+    } else if metadata.is_file() {
+        // Without the server's support, preserved hardlinks are uploaded as copies
+        let group_hardlinks = match session.links.hardlinks {
+            LinkPolicy::Preserve => links,
+            LinkPolicy::Follow => false,
+            LinkPolicy::Skip => true,
         };
 
-        let path = entry.path();
-        if path.is_dir() {
-            Box::pin(send_folder(handler, &path)).await?;
-        } else {
-            send_file(handler, &path).await?;
+        if group_hardlinks && metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(first) = backup.hardlinks.get(&key) {
+                if session.links.hardlinks == LinkPolicy::Skip {
+                    println!("Skipping hardlink {}", path.display());
+                    return Ok(());
+                }
+
+                let Some(first) = first.to_str() else {
+                    println!(
+                        "Skipping hardlink {}, the path it links to isn't valid UTF-8",
+                        path.display()
+                    );
+                    return Ok(());
+                };
+
+                let mut file =
+                    file_manager::create_from_metadata(&path, None, &metadata, false).await?;
+                file.set_file_type(FileType::Hardlink);
+                file.size = 0;
+                file.link_target = Some(String::from(first));
+                backup.nodes.push(file);
+                return Ok(());
+            }
+
+            backup.hardlinks.insert(key, path.clone());
         }
+
+        backup.files.push(path);
+    } else if links
+        && session.links.special_files == SpecialFilePolicy::Preserve
+        && file_manager::file_type_convert(metadata.file_type()).is_ok()
+    {
+        let file = file_manager::create_from_metadata(&path, Some(&path), &metadata, false).await?;
+        backup.nodes.push(file);
+    } else {
+        println!("Skipping special file {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Backs up a file or a whole folder.
+async fn send_path(session: &Arc<Session>, path: &Path) -> anyhow::Result<()> {
+    let mut backup = Backup::default();
+    plan_entry(session, path.to_path_buf(), &mut backup).await?;
+
+    run_concurrently(backup.files, |file| {
+        let session = session.clone();
+        async move { send_file(&session, &file).await }
+    })
+    .await?;
+
+    run_concurrently(backup.nodes, |file| {
+        let session = session.clone();
+        async move { send_node(&session, file).await }
+    })
+    .await
+}
+
+async fn receive_delta(
+    stream: &mut mux::Stream,
+    file_path: &PathBuf,
+    output: &mut (impl AsyncWrite + Unpin),
+    size: u64,
+    compression: Compression,
+) -> anyhow::Result<()> {
+    let mut base = fs::File::open(&file_path).await?;
+    let block_size = delta::block_size_for(base.metadata().await?.len());
+
+    let mut remaining_bytes = size;
+    loop {
+        let operation = match envelope::receive(stream).await? {
+            Body::DeltaOperation(operation) => {
+                compression::decode_operation(compression, operation)?
+            }
+            Body::EndOfFile(_) => break,
+            body => return Err(unexpected_message("a delta operation", &body)),
+        };
+        let written =
+            delta::apply_operation(Some(&mut base), block_size, operation, output).await?;
+
+        remaining_bytes = remaining_bytes
+            .checked_sub(written)
+            .context("Delta is larger than announced size")?;
+    }
+
+    if remaining_bytes > 0 {
+        bail!("Delta is smaller than announced size");
+    }
+
+    Ok(())
+}
+
+async fn receive_payload(
+    stream: &mut mux::Stream,
+    output: &mut (impl AsyncWrite + Unpin),
+    size: u64,
+    compression: Compression,
+) -> anyhow::Result<()> {
+    let mut remaining_bytes = size;
+    loop {
+        let frame = match envelope::receive(stream).await? {
+            Body::Data(frame) => frame,
+            Body::EndOfFile(_) => break,
+            body => return Err(unexpected_message("a data chunk", &body)),
+        };
+        let payload_chunk = compression::decode(compression, &frame)?;
+
+        println!("Received chunk of {} bytes", payload_chunk.len());
+
+        output.write_all(&payload_chunk).await?;
+        remaining_bytes = remaining_bytes
+            .checked_sub(payload_chunk.len() as u64)
+            .context("Payload is larger than announced size")?;
+    }
+
+    if remaining_bytes > 0 {
+        bail!("Payload is smaller than announced size");
+    }
+
+    Ok(())
+}
+
+/// Receives a download into `temp_file`, checking it against the hash the server announced.
+async fn receive_verified(
+    stream: &mut mux::Stream,
+    response: &ResponseSync,
+    response_file: &File,
+    file_path: &PathBuf,
+    temp_file: &mut fs::File,
+    temp_path: &Path,
+) -> anyhow::Result<()> {
+    let mut output = if response.offset > 0 {
+        HashingWriter::resume(temp_file, temp_path, response.offset).await?
+    } else {
+        HashingWriter::new(temp_file)
+    };
+
+    let remaining_bytes = response_file.size - response.offset;
+    let compression = response.compression();
+    if response.delta {
+        receive_delta(stream, file_path, &mut output, remaining_bytes, compression).await?;
+    } else {
+        receive_payload(stream, &mut output, remaining_bytes, compression).await?;
     }
 
+    output.flush().await?;
+    output.verify(response_file.size, response_file.hash.as_deref())?;
+
     Ok(())
 }
 
+/// Recreates an entry that has no content from its metadata.
+async fn restore_node(file: &File) -> anyhow::Result<()> {
+    let file_path = PathBuf::from(&file.path);
+    if let Some(parent) = file_path.parent() {
+        if !fs::try_exists(&parent).await? {
+            fs::create_dir_all(&parent).await?;
+        }
+    }
+
+    if file.file_type() == FileType::Hardlink {
+        let source = file
+            .link_target
+            .as_ref()
+            .context("Hardlink without a target")?;
+        file_manager::create_hardlink(Path::new(source), &file_path).await
+    } else {
+        file_manager::create_node(file, &file_path).await
+    }
+}
+
 async fn receive_file(
-    handler: &mut packeter::Handler,
+    stream: &mut mux::Stream,
     response: ResponseSync,
+    resumable: bool,
 ) -> anyhow::Result<()> {
-    let response_file = if let Some(file) = response.file {
+    let response_file = if let Some(file) = &response.file {
         file
     } else {
         return Err(anyhow::anyhow!("No file in request"));
     };
 
-    let file_path = PathBuf::from(response_file.path);
+    // Nothing follows the response of an entry without content
+    if file_manager::is_node(response_file.file_type()) {
+        return restore_node(response_file).await;
+    }
+
+    let file_path = PathBuf::from(&response_file.path);
 
     if let Some(parent) = file_path.parent() {
         if !fs::try_exists(&parent).await? {
@@ -84,110 +667,681 @@ async fn receive_file(
         return Err(anyhow::anyhow!("No parent path"));
     }
 
-    let (mut temp_file, temp_path) = file_manager::open_temporary_file(&file_path).await?;
+    if response.offset > response_file.size {
+        bail!("Offset is past the end of the file");
+    }
 
-    let mut remaining_bytes = response_file.size;
-    loop {
-        if remaining_bytes == 0 {
-            break;
-        }
+    let (mut temp_file, temp_path) = if resumable {
+        file_manager::open_partial_file(&file_path, response.offset).await?
+    } else if response.offset == 0 {
+        file_manager::open_temporary_file(&file_path).await?
+    } else {
+        bail!("Server resumed a download that wasn't asked to be");
+    };
 
-        let payload_chunk = match handler.read_packet().await {
-            Ok(chunk) => chunk,
-            Err(e) => match e {
-                packeter::ReadPacketError::ReadError(_) => {
-                    continue;
-                }
-                _ => break,
-            },
-        };
+    let result = receive_verified(
+        stream,
+        &response,
+        response_file,
+        &file_path,
+        &mut temp_file,
+        &temp_path,
+    )
+    .await;
 
-        println!("Received chunk of {} bytes", payload_chunk.len());
+    // A stream closed mid-transfer must not replace the local copy
+    if let Err(e) = result {
+        // Content that failed verification is no base to resume from
+        if resumable && e.downcast_ref::<IntegrityError>().is_none() {
+            let _ = temp_file.flush().await;
+        } else {
+            let _ = fs::remove_file(&temp_path).await;
+        }
 
-        temp_file.write_all(&payload_chunk).await?;
-        remaining_bytes -= payload_chunk.len() as u64;
+        return Err(e);
     }
 
     let std_temp_file = temp_file.into_std().await;
-    file_manager::close_temporary_file(
-        std_temp_file,
-        &temp_path,
-        &file_path,
-        response_file.last_modified,
-        response_file.file_permissions,
-        response_file.file_owner,
-        response_file.file_group,
-    )
-    .await?;
+    let result =
+        file_manager::close_temporary_file(std_temp_file, &temp_path, &file_path, response_file)
+            .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+    result?;
 
     Ok(())
 }
 
-async fn send_request_get(
-    handler: &mut packeter::Handler,
+/// Describes the entries at the given paths and everything under them, in the snapshot
+/// `snapshot` if there is one.
+async fn request_get(
+    session: &Session,
     files: Vec<FileGet>,
-) -> anyhow::Result<()> {
-    let request_get = protos::create_request_get(files);
-    let request = protos::serialize_request_get(request_get);
-    handler.write_packet(&request).await?;
+    snapshot: Option<String>,
+) -> anyhow::Result<ResponseGet> {
+    if snapshot.is_some() && !session.capabilities.has(Capability::Snapshots) {
+        bail!("Server doesn't keep snapshots");
+    }
 
-    Ok(())
+    let request_id = session.next_request_id();
+    let mut request_get = protos::create_request_get(files, request_id);
+    request_get.snapshot = snapshot;
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestGet(request_get)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseGet(response) => response,
+        body => return Err(unexpected_message("a get response", &body)),
+    };
+
+    Ok(response)
 }
 
-async fn receive_response_get(handler: &mut packeter::Handler) -> anyhow::Result<ResponseGet> {
-    let response = handler.read_packet().await?;
-    let response = protos::deserialize_response_get(&response)?;
+async fn request_stat(
+    session: &Session,
+    paths: Vec<String>,
+    hash: bool,
+    children: bool,
+    snapshot: Option<String>,
+) -> anyhow::Result<ResponseStat> {
+    if !session.capabilities.has(Capability::Stat) {
+        bail!("Server doesn't support stat requests");
+    }
+    if snapshot.is_some() && !session.capabilities.has(Capability::Snapshots) {
+        bail!("Server doesn't keep snapshots");
+    }
+
+    let files = paths.into_iter().map(protos::create_file_stat).collect();
+    let request_id = session.next_request_id();
+    let mut request_stat = protos::create_request_stat(files, hash, children, request_id);
+    request_stat.snapshot = snapshot;
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestStat(request_stat)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseStat(response) => response,
+        body => return Err(unexpected_message("a stat response", &body)),
+    };
+
+    Ok(response)
+}
+
+async fn request_versions(session: &Session, path: String) -> anyhow::Result<ResponseVersions> {
+    if !session.capabilities.has(Capability::Versions) {
+        bail!("Server doesn't keep versions");
+    }
+
+    let request_id = session.next_request_id();
+    let request_versions = protos::create_request_versions(path, request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestVersions(request_versions)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseVersions(response) => response,
+        body => return Err(unexpected_message("a versions response", &body)),
+    };
+
+    Ok(response)
+}
+
+/// Has the server record the whole tree as it is now.
+async fn request_snapshot(session: &Session) -> anyhow::Result<Snapshot> {
+    if !session.capabilities.has(Capability::Snapshots) {
+        bail!("Server doesn't keep snapshots");
+    }
+
+    let request_id = session.next_request_id();
+    let request_snapshot = protos::create_request_snapshot(request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestSnapshot(request_snapshot)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseSnapshot(response) => response,
+        body => return Err(unexpected_message("a snapshot response", &body)),
+    };
+
+    response
+        .snapshot
+        .context("Snapshot response without a snapshot")
+}
+
+async fn request_snapshots(session: &Session) -> anyhow::Result<ResponseSnapshots> {
+    if !session.capabilities.has(Capability::Snapshots) {
+        bail!("Server doesn't keep snapshots");
+    }
+
+    let request_id = session.next_request_id();
+    let request_snapshots = protos::create_request_snapshots(request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestSnapshots(request_snapshots)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseSnapshots(response) => response,
+        body => return Err(unexpected_message("a snapshots response", &body)),
+    };
+
+    Ok(response)
+}
+
+/// The last snapshot taken at or before `at`, since the Unix epoch.
+async fn snapshot_at(session: &Session, at: Duration) -> anyhow::Result<Snapshot> {
+    let response = request_snapshots(session).await?;
+
+    response
+        .snapshots
+        .into_iter()
+        .rev()
+        .find(|snapshot| Duration::new(snapshot.created, snapshot.created_nanos) <= at)
+        .context("No snapshot was taken by then")
+}
+
+async fn request_trash(session: &Session) -> anyhow::Result<ResponseTrash> {
+    if !session.capabilities.has(Capability::Trash) {
+        bail!("Server doesn't keep a trash");
+    }
+
+    let request_id = session.next_request_id();
+    let request_trash = protos::create_request_trash(request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestTrash(request_trash)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseTrash(response) => response,
+        body => return Err(unexpected_message("a trash response", &body)),
+    };
+
+    Ok(response)
+}
+
+/// Puts a removed entry back on the server, where it was unless `path` is given, returning
+/// where it ended up.
+async fn request_restore_trash(
+    session: &Session,
+    id: String,
+    path: Option<String>,
+    conflict: ConflictPolicy,
+) -> anyhow::Result<String> {
+    if !session.capabilities.has(Capability::Trash) {
+        bail!("Server doesn't keep a trash");
+    }
+
+    let request_id = session.next_request_id();
+    let request_restore = protos::create_request_restore_trash(id, path, conflict, request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestRestoreTrash(request_restore)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseRestoreTrash(response) => response,
+        body => return Err(unexpected_message("a trash restore response", &body)),
+    };
+
+    Ok(response.path)
+}
+
+/// Drops removed entries on the server for good, the whole trash without ids.
+async fn request_empty_trash(session: &Session, ids: Vec<String>) -> anyhow::Result<()> {
+    if !session.capabilities.has(Capability::Trash) {
+        bail!("Server doesn't keep a trash");
+    }
+
+    let request_id = session.next_request_id();
+    let request_empty = protos::create_request_empty_trash(ids, request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestEmptyTrash(request_empty)).await?;
+
+    wait_for_ack(&mut stream, request_id).await
+}
+
+/// Moves entries on the server all at once, none of them is moved if one can't be.
+async fn request_move(
+    session: &Session,
+    files: Vec<FileMove>,
+    conflict: ConflictPolicy,
+) -> anyhow::Result<ResponseMove> {
+    if !session.capabilities.has(Capability::AtomicMove) {
+        bail!("Server doesn't support atomic moves");
+    }
+
+    let request_id = session.next_request_id();
+    let request_move = protos::create_request_move(files, conflict, request_id);
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestMove(request_move)).await?;
+
+    let response = match server_error::read_response(&mut stream, request_id).await? {
+        Body::ResponseMove(response) => response,
+        body => return Err(unexpected_message("a move response", &body)),
+    };
 
     Ok(response)
 }
 
-async fn send_request_sync(handler: &mut packeter::Handler, path: String) -> anyhow::Result<()> {
+async fn local_signature(path: &PathBuf) -> Option<delta::Signature> {
+    let metadata = fs::metadata(path).await.ok()?;
+    if !metadata.is_file() || metadata.len() < delta::DELTA_THRESHOLD {
+        return None;
+    }
+
+    let block_size = delta::block_size_for(metadata.len());
+    delta::compute_signature(path, block_size).await.ok()
+}
+
+/// What an interrupted download of `path` left behind, for the server to carry on from.
+async fn local_partial(path: &Path) -> Option<PartialTransfer> {
+    let partial_path = file_manager::partial_file_path(path).ok()?;
+    let offset = fs::metadata(&partial_path).await.ok()?.len();
+    if offset == 0 {
+        return None;
+    }
+
+    let hash = file_manager::hash_prefix(&partial_path, offset)
+        .await
+        .ok()?;
+    Some(protos::create_partial_transfer(offset, hash))
+}
+
+async fn send_request_sync(
+    session: &Session,
+    stream: &mut mux::Stream,
+    path: String,
+    version: Option<String>,
+    snapshot: Option<String>,
+) -> anyhow::Result<u64> {
+    let signature = if session.capabilities.has(Capability::Delta) {
+        local_signature(&PathBuf::from(&path)).await
+    } else {
+        None
+    };
+    let header = signature.as_ref().map(|signature| {
+        protos::create_signature(signature.block_size, signature.blocks.len() as u64)
+    });
+
+    let partial = if session.capabilities.has(Capability::Resume) {
+        local_partial(&PathBuf::from(&path)).await
+    } else {
+        None
+    };
+
+    let request_id = session.next_request_id();
     let file_sync = create_file_sync(path);
-    let request_sync = protos::create_request_sync(file_sync);
-    let request = protos::serialize_request_sync(request_sync);
-    handler.write_packet(&request).await?;
+    let mut request_sync = protos::create_request_sync(
+        file_sync,
+        header,
+        partial,
+        session.compression,
+        version,
+        request_id,
+    );
+    request_sync.snapshot = snapshot;
+    envelope::send(stream, Body::RequestSync(request_sync)).await?;
+
+    if let Some(signature) = signature {
+        delta::send_signature_blocks(stream, signature).await?;
+    }
 
-    Ok(())
+    Ok(request_id)
 }
 
-async fn process_response_get(
-    mut handler: &mut packeter::Handler,
-    files: Vec<File>,
+/// Downloads the file at `path`, or an earlier version of it, or the one in a snapshot.
+async fn sync_file(
+    session: &Session,
+    path: String,
+    version: Option<String>,
+    snapshot: Option<String>,
 ) -> anyhow::Result<()> {
-    for file in files {
-        if file.file_type == FileType::Directory as i32 {
-            Box::pin(process_response_get(handler, file.childrens)).await?;
-        } else {
-            send_request_sync(&mut handler, file.path).await?;
+    if version.is_some() && !session.capabilities.has(Capability::Versions) {
+        bail!("Server doesn't keep versions");
+    }
+    if snapshot.is_some() && !session.capabilities.has(Capability::Snapshots) {
+        bail!("Server doesn't keep snapshots");
+    }
 
-            receive_response_sync(&mut handler).await?;
+    let mut stream = session.mux.open()?;
+    let request_id = send_request_sync(session, &mut stream, path, version, snapshot).await?;
+    let resumable = session.capabilities.has(Capability::Resume);
+
+    receive_response_sync(&mut stream, request_id, resumable).await
+}
+
+fn collect_remote_files(files: Vec<File>, paths: &mut Vec<String>, nodes: &mut Vec<File>) {
+    for file in files {
+        match file.file_type() {
+            FileType::Directory => collect_remote_files(file.childrens, paths, nodes),
+            FileType::File => paths.push(file.path),
+            _ => nodes.push(file),
         }
     }
+}
 
-    Ok(())
+/// Starts a listing, its pages are then read with `next_page` as the server walks the tree.
+async fn open_listing(session: &Session, request: RequestList) -> anyhow::Result<mux::Stream> {
+    if !session.capabilities.has(Capability::Listing) {
+        bail!("Server doesn't support listings");
+    }
+
+    let mut stream = session.mux.open()?;
+    envelope::send(&mut stream, Body::RequestList(request)).await?;
+
+    Ok(stream)
+}
+
+async fn next_page(stream: &mut mux::Stream, request_id: u64) -> anyhow::Result<ResponseList> {
+    match server_error::read_response(stream, request_id).await? {
+        Body::ResponseList(page) => Ok(page),
+        body => Err(unexpected_message("a list response", &body)),
+    }
 }
 
+/// Downloads everything under `folder`, as it is in the snapshot `snapshot` if there is one.
 async fn send_folder_request_sync(
-    handler: &mut packeter::Handler,
+    session: &Arc<Session>,
     folder: &PathBuf,
+    snapshot: Option<String>,
 ) -> anyhow::Result<()> {
-    let file_get = create_file_get(folder.to_str().unwrap().to_string());
-    let mut files = Vec::new();
-    files.push(file_get);
-    send_request_get(handler, files).await?;
+    let path = folder.to_str().unwrap().to_string();
+    let mut transfers = TransferQueue::default();
+    let mut nodes = Vec::new();
+
+    // Listings only walk the current tree
+    if session.capabilities.has(Capability::Listing) && snapshot.is_none() {
+        // Files are synced as soon as they are listed, without waiting for the whole tree
+        let request_id = session.next_request_id();
+        let mut request = protos::create_request_list(path, request_id);
+        request.flat = true;
+        let mut stream = open_listing(session, request).await?;
+
+        loop {
+            let page = next_page(&mut stream, request_id).await?;
+            for file in page.files {
+                match file.file_type() {
+                    FileType::File => {
+                        let session = session.clone();
+                        transfers
+                            .push(async move { sync_file(&session, file.path, None, None).await })
+                            .await?;
+                    }
+                    FileType::Directory => {}
+                    _ => nodes.push(file),
+                }
+            }
 
-    let response = receive_response_get(handler).await?;
+            if page.last {
+                break;
+            }
+        }
+    } else {
+        let response = request_get(session, vec![create_file_get(path)], snapshot.clone()).await?;
+
+        let mut paths = Vec::new();
+        collect_remote_files(response.files, &mut paths, &mut nodes);
+        for path in paths {
+            let session = session.clone();
+            let snapshot = snapshot.clone();
+            transfers
+                .push(async move { sync_file(&session, path, None, snapshot).await })
+                .await?;
+        }
+    }
+
+    transfers.finish().await?;
+
+    // Hardlinks need the files they point to
+    for node in nodes {
+        restore_node(&node).await?;
+    }
+
+    Ok(())
+}
+
+/// Prints a listing page by page, options are given as `--flat`, `--hash`, `--depth=N`,
+/// `--page=N`, `--limit=N` and `--cursor=PATH`.
+async fn print_listing(session: &Session, path: &str, options: &[&str]) -> anyhow::Result<()> {
+    let request_id = session.next_request_id();
+    let mut request = protos::create_request_list(String::from(path), request_id);
+    for option in options {
+        match option.split_once('=') {
+            Some(("--depth", depth)) => request.max_depth = depth.parse()?,
+            Some(("--page", page_size)) => request.page_size = page_size.parse()?,
+            Some(("--limit", limit)) => request.limit = limit.parse()?,
+            Some(("--cursor", cursor)) => request.cursor = Some(String::from(cursor)),
+            _ if *option == "--flat" => request.flat = true,
+            _ if *option == "--hash" => request.hash = true,
+            _ => bail!("Unknown listing option {}", option),
+        }
+    }
+
+    let mut stream = open_listing(session, request).await?;
+    loop {
+        let page = next_page(&mut stream, request_id).await?;
+        println!("{:?}", page);
+
+        if page.last {
+            return Ok(());
+        }
+    }
+}
+
+async fn receive_response_sync(
+    stream: &mut mux::Stream,
+    request_id: u64,
+    resumable: bool,
+) -> anyhow::Result<()> {
+    let response = match server_error::read_response(stream, request_id).await? {
+        Body::ResponseSync(response) => response,
+        body => return Err(unexpected_message("a sync response", &body)),
+    };
 
-    process_response_get(handler, response.files).await?;
+    receive_file(stream, response, resumable).await?;
 
     Ok(())
 }
 
-async fn receive_response_sync(handler: &mut packeter::Handler) -> anyhow::Result<()> {
-    let response = handler.read_packet().await?;
-    let response = protos::deserialize_response_sync(&response)?;
+async fn handle_message(
+    session: &Arc<Session>,
+    message: &str,
+    tx_sync: &mut mpsc::Sender<String>,
+    tx_add: &mut mpsc::Sender<String>,
+) -> anyhow::Result<()> {
+    let mut parts = message.split(" ").collect::<VecDeque<&str>>();
+    match parts.pop_front().unwrap() {
+        "sync" => {
+            println!("Sending sync request for {:?}", parts);
+            let pop = parts.pop_front().unwrap();
+            let file = String::from(pop);
+            sync_file(session, file, None, None).await?;
+            tx_sync.send(String::from(pop)).await?;
+        }
+        "sync_version" => {
+            println!("Sending sync request for {:?}", parts);
+            let path = parts.pop_front().unwrap();
+            let version = parts.pop_front().context("No version to sync")?;
+            sync_file(
+                session,
+                String::from(path),
+                Some(String::from(version)),
+                None,
+            )
+            .await?;
+            tx_sync.send(String::from(path)).await?;
+        }
+        "versions" => {
+            println!("Sending versions request for {:?}", parts);
+            let path = parts.pop_front().unwrap();
+
+            println!("{:?}", request_versions(session, String::from(path)).await?);
+        }
+        "sync_folder" => {
+            println!("Sending sync_folder request for {:?}", parts);
+            let pop = parts.pop_front().unwrap();
+            let folder = PathBuf::from(pop);
+
+            send_folder_request_sync(session, &folder, None).await?;
+            tx_sync.send(String::from(pop)).await?;
+        }
+        "snapshot" => {
+            println!("Sending snapshot request");
+
+            println!("{:?}", request_snapshot(session).await?);
+        }
+        "snapshots" => {
+            println!("Sending snapshots request");
+
+            println!("{:?}", request_snapshots(session).await?);
+        }
+        "restore" => {
+            println!("Sending restore request for {:?}", parts);
+            let pop = parts.pop_front().unwrap();
+            let folder = PathBuf::from(pop);
+            // Either a snapshot id, or `@` and a Unix time to restore the folder as it was then
+            let snapshot = parts.pop_front().context("No snapshot to restore")?;
+            let id = match snapshot.strip_prefix('@') {
+                Some(at) => {
+                    let at = Duration::from_secs(at.parse()?);
+                    snapshot_at(session, at).await?.id
+                }
+                None => String::from(snapshot),
+            };
+
+            println!("Restoring {} from snapshot {}", pop, id);
+            send_folder_request_sync(session, &folder, Some(id)).await?;
+            tx_sync.send(String::from(pop)).await?;
+        }
+        "get_all" => {
+            println!("Sending get request for {:?}", parts);
+            let files = parts
+                .iter()
+                .map(|path| create_file_get(String::from(*path)))
+                .collect::<Vec<FileGet>>();
+
+            println!("Sending request get for {:?}", files);
+
+            println!("{:?}", request_get(session, files, None).await?);
+        }
+        "list" => {
+            println!("Sending list request for {:?}", parts);
+            let path = parts.pop_front().unwrap();
+            let options = parts.iter().copied().collect::<Vec<&str>>();
+
+            print_listing(session, path, &options).await?;
+        }
+        "ping" => {
+            let round_trip = session.mux.ping().await?;
+            println!("Server answered in {:?}", round_trip);
+        }
+        "stat" => {
+            println!("Sending stat request for {:?}", parts);
+            let hash = parts.contains(&"--hash");
+            let children = parts.contains(&"--children");
+            let snapshot = parts
+                .iter()
+                .find_map(|part| part.strip_prefix("--snapshot="))
+                .map(String::from);
+            let paths = parts
+                .iter()
+                .filter(|part| !part.starts_with("--"))
+                .map(|path| String::from(*path))
+                .collect();
+
+            println!(
+                "{:?}",
+                request_stat(session, paths, hash, children, snapshot).await?
+            );
+        }
+        "add" => {
+            println!("Sending add request for {:?}", parts);
+            let pop = parts.pop_front().unwrap();
+            let file = PathBuf::from(pop);
+            send_path(session, &file).await?;
+            tx_add.send(String::from(pop)).await?;
+        }
+        "add_folder" => {
+            println!("Sending add_file request for {:?}", parts);
+            let pop = parts.pop_front().unwrap();
+            let folder = PathBuf::from(pop);
+
+            send_path(session, &folder).await?;
+            tx_add.send(String::from(pop)).await?;
+        }
+        "remove" => {
+            println!("Sending remove request for {:?}", parts);
+            let pop = parts.pop_front().unwrap();
+            let file_remove = protos::create_file_remove(String::from(pop));
+            let files = Vec::from([file_remove]);
+            let request_id = session.next_request_id();
+            let request_remove = protos::create_request_remove(files, request_id);
+            let mut stream = session.mux.open()?;
+            envelope::send(&mut stream, Body::RequestRemove(request_remove)).await?;
+            wait_for_ack(&mut stream, request_id).await?;
+        }
+        "trash" => {
+            println!("Sending trash request");
 
-    receive_file(handler, response).await?;
+            println!("{:?}", request_trash(session).await?);
+        }
+        "restore_trash" => {
+            println!("Sending trash restore request for {:?}", parts);
+            let conflict = if parts.contains(&"--overwrite") {
+                ConflictPolicy::Overwrite
+            } else if parts.contains(&"--rename") {
+                ConflictPolicy::Rename
+            } else {
+                ConflictPolicy::Fail
+            };
+            let mut args = parts.iter().filter(|part| !part.starts_with("--"));
+            let id = args.next().context("No trash entry to restore")?;
+            let path = args.next().map(|path| String::from(*path));
+
+            let restored =
+                request_restore_trash(session, String::from(*id), path, conflict).await?;
+            println!("Restored to {}", restored);
+        }
+        "empty_trash" => {
+            println!("Sending empty trash request for {:?}", parts);
+            let ids = parts.iter().map(|id| String::from(*id)).collect();
+
+            request_empty_trash(session, ids).await?;
+        }
+        "move" => {
+            println!("Sending move request for {:?}", parts);
+            let conflict = if parts.contains(&"--overwrite") {
+                ConflictPolicy::Overwrite
+            } else if parts.contains(&"--rename") {
+                ConflictPolicy::Rename
+            } else {
+                ConflictPolicy::Fail
+            };
+            let paths = parts
+                .iter()
+                .filter(|part| !part.starts_with("--"))
+                .copied()
+                .collect::<Vec<&str>>();
+            let files = paths
+                .chunks(2)
+                .map(|pair| match pair {
+                    [old_path, new_path] => Ok(protos::create_file_move(
+                        String::from(*old_path),
+                        String::from(*new_path),
+                    )),
+                    _ => Err(anyhow::anyhow!("Move of {} without a new path", pair[0])),
+                })
+                .collect::<anyhow::Result<Vec<FileMove>>>()?;
+
+            println!("{:?}", request_move(session, files, conflict).await?);
+        }
+        _ => {
+            println!("Unknown message: {}", message);
+        }
+    }
 
     Ok(())
 }
@@ -207,89 +1361,105 @@ pub async fn handle(
     let config = config::get_config(&config_path.join("zen-sync").join("config.toml"))
         .with_context(|| format!("Failed to parse config file at {}", "config.toml"))?;
 
-    let home_config = config.peer.get("home").unwrap();
+    // Renewed halfway through, ahead of the server ending it
+    let max_lifetime = config.client.session.max_lifetime() / 2;
+    let mut session = connect(&config).await?;
+    let result = async {
+        while let Some(message) = rx.recv().await {
+            // Sessions are replaced between requests once they are too old
+            if session.mux.is_closed() || session.established.elapsed() >= max_lifetime {
+                if let Some(ending) = session.mux.ending() {
+                    println!("Session ended: {}, reconnecting", ending);
+                }
+                session.mux.close();
+                session = connect(&config).await?;
+            }
 
-    let local_private_key = BASE64_STANDARD.decode(&config.client.private_key)?;
-    let remote_public_key = BASE64_STANDARD.decode(&home_config.public_key)?;
+            if let Err(e) = handle_message(&session, &message, tx_sync, tx_add).await {
+                match e.downcast_ref::<ServerError>() {
+                    Some(error) => eprintln!("Request \"{}\" failed: {}", message, error),
+                    None => return Err(e),
+                }
+            }
+        }
 
-    let stream: TcpStream = TcpStream::connect("localhost:8080")
-        .await
-        .with_context(|| format!("Failed to connect to {}", "localhost:8080"))?;
+        Ok(())
+    }
+    .await;
 
-    let mut buf_reader = BufReader::new(stream);
+    session.mux.close();
+    result
+}
 
-    let noise = snow::Builder::new(commons::NOISE_PARAMS.clone())
-        .local_private_key(local_private_key.as_slice())
-        .remote_public_key(remote_public_key.as_slice())
-        .build_initiator()?;
+async fn connect(config: &config::Config) -> anyhow::Result<Arc<Session>> {
+    let home_config = config.peer.get("home").unwrap();
 
-    let noise = handshake_handler::handle_handshake(&mut buf_reader, noise).await?;
+    match &home_config.command {
+        Some(command) => {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("Failed to run {}", command))?;
+            let transport =
+                tokio::io::join(child.stdout.take().unwrap(), child.stdin.take().unwrap());
+
+            open_session(BufReader::new(transport), config).await
+        }
+        None => {
+            let stream: TcpStream = TcpStream::connect("localhost:8080")
+                .await
+                .with_context(|| format!("Failed to connect to {}", "localhost:8080"))?;
 
-    let mut handler = packeter::Handler::new(buf_reader, noise);
+            open_session(BufReader::new(stream), config).await
+        }
+    }
+}
 
-    while let Some(message) = rx.recv().await {
-        let mut parts = message.split(" ").collect::<VecDeque<&str>>();
-        match parts.pop_front().unwrap() {
-            "sync" => {
-                println!("Sending sync request for {:?}", parts);
-                let pop = parts.pop_front().unwrap();
-                let file = String::from(pop);
-                send_request_sync(&mut handler, file).await?;
+/// Sets up a session with the home peer over any byte stream.
+async fn open_session(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    config: &config::Config,
+) -> anyhow::Result<Arc<Session>> {
+    let home_config = config.peer.get("home").unwrap();
 
-                receive_response_sync(&mut handler).await?;
-                tx_sync.send(String::from(pop)).await?;
-            }
-            "sync_folder" => {
-                println!("Sending sync_folder request for {:?}", parts);
-                let pop = parts.pop_front().unwrap();
-                let folder = PathBuf::from(pop);
+    let local_private_key = BASE64_STANDARD.decode(&config.client.private_key)?;
+    let remote_public_key = BASE64_STANDARD.decode(&home_config.public_key)?;
 
-                send_folder_request_sync(&mut handler, &folder).await?;
-                tx_sync.send(String::from(pop)).await?;
-            }
-            "get_all" => {
-                println!("Sending get request for {:?}", parts);
-                let files = parts
-                    .iter()
-                    .map(|path| create_file_get(String::from(*path)))
-                    .collect::<Vec<FileGet>>();
+    let noise = snow::Builder::new(commons::NOISE_PARAMS.clone())
+        .local_private_key(local_private_key.as_slice())
+        .remote_public_key(remote_public_key.as_slice())
+        .build_initiator()?;
 
-                println!("Sending request get for {:?}", files);
+    let (handler, capabilities) =
+        tokio::time::timeout(config.client.session.handshake_timeout(), async {
+            let noise = handshake_handler::handle_handshake(&mut stream, noise).await?;
 
-                send_request_get(&mut handler, files).await?;
-                println!("{:?}", receive_response_get(&mut handler).await?);
-            }
-            "add" => {
-                println!("Sending add request for {:?}", parts);
-                let pop = parts.pop_front().unwrap();
-                let file = PathBuf::from(pop);
-                send_file(&mut handler, &file).await?;
-                tx_add.send(String::from(pop)).await?;
-            }
-            "add_folder" => {
-                println!("Sending add_file request for {:?}", parts);
-                let pop = parts.pop_front().unwrap();
-                let folder = PathBuf::from(pop);
+            let mut handler = packeter::Handler::new(stream, noise)
+                .with_rekey_policy(config.client.session.rekey_policy());
+            let capabilities = handshake_handler::handle_hello(&mut handler).await?;
 
-                send_folder(&mut handler, &folder).await?;
-                tx_add.send(String::from(pop)).await?;
-            }
-            "remove" => {
-                println!("Sending remove request for {:?}", parts);
-                let pop = parts.pop_front().unwrap();
-                let file_remove = protos::create_file_remove(String::from(pop));
-                let files = Vec::from([file_remove]);
-                let request_remove = protos::create_request_remove(files);
-                let request = protos::serialize_request_remove(request_remove);
-                handler.write_packet(&request).await?;
-            }
-            _ => {
-                println!("Unknown message: {}", message);
-            }
-        }
-    }
+            anyhow::Ok((handler, capabilities))
+        })
+        .await
+        .context("Server didn't complete the handshake in time")??;
+    // A server that doesn't answer pings is still given up on once it goes silent
+    let keepalive = config.client.session.keepalive();
+    let mux = Multiplexer::new(handler, Side::Initiator);
+    let mux = if capabilities.has(Capability::Keepalive) {
+        mux.with_keepalive(keepalive)
+    } else {
+        mux.with_timeouts(keepalive)
+    };
 
-    Ok(())
+    Ok(Arc::new(Session::new(
+        mux,
+        capabilities,
+        config.client.compression.into(),
+        config.client.links,
+    )))
 }
 
 // pub struct ZsyncClient {
@@ -0,0 +1,62 @@
+use anyhow::bail;
+use commons::{envelope, mux};
+use protos::{Body, ErrorCode};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Invalid path: {0}")]
+    PathInvalid(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Hash mismatch: {0}")]
+    HashMismatch(String),
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Server error: {0}")]
+    Unknown(String),
+}
+
+impl From<protos::ResponseError> for ServerError {
+    fn from(response: protos::ResponseError) -> Self {
+        let message = response.message;
+        match ErrorCode::try_from(response.code) {
+            Ok(ErrorCode::NotFound) => ServerError::NotFound(message),
+            Ok(ErrorCode::PermissionDenied) => ServerError::PermissionDenied(message),
+            Ok(ErrorCode::PathInvalid) => ServerError::PathInvalid(message),
+            Ok(ErrorCode::QuotaExceeded) => ServerError::QuotaExceeded(message),
+            Ok(ErrorCode::HashMismatch) => ServerError::HashMismatch(message),
+            Ok(ErrorCode::AlreadyExists) => ServerError::AlreadyExists(message),
+            Ok(ErrorCode::InvalidRequest) => ServerError::InvalidRequest(message),
+            Ok(ErrorCode::Unknown) | Err(_) => ServerError::Unknown(message),
+        }
+    }
+}
+
+/// Reads the response to `request_id`, turning a `ResponseError` from the server into a
+/// `ServerError`.
+pub async fn read_response(stream: &mut mux::Stream, request_id: u64) -> anyhow::Result<Body> {
+    let response = envelope::receive(stream).await?;
+
+    match envelope::request_id(&response) {
+        Some(id) if id == request_id => {}
+        Some(id) => bail!(
+            "Received response to request {} while waiting for request {}",
+            id,
+            request_id
+        ),
+        None => bail!("Expected a response, got {}", envelope::name(&response)),
+    }
+
+    if let Body::ResponseError(error) = response {
+        return Err(ServerError::from(error).into());
+    }
+
+    Ok(response)
+}
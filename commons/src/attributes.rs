@@ -0,0 +1,194 @@
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io,
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    },
+    path::Path,
+};
+
+use nix::libc;
+use protos::ExtendedAttribute;
+
+pub const FS_IMMUTABLE_FL: u32 = 0x0000_0010;
+pub const FS_APPEND_FL: u32 = 0x0000_0020;
+/// Inode flags chattr can set, the others are managed by the filesystem.
+pub const RESTORABLE_FLAGS: u32 = 0x0000_00ff // secrm to noatime
+    | 0x0000_4000 // journal data
+    | 0x0000_8000 // notail
+    | 0x0001_0000 // dirsync
+    | 0x0002_0000 // topdir
+    | 0x0080_0000 // nocow
+    | 0x2000_0000; // project inherit
+
+nix::ioctl_read_bad!(
+    get_inode_flags,
+    nix::request_code_read!(b'f', 1, std::mem::size_of::<libc::c_long>()),
+    libc::c_int
+);
+nix::ioctl_write_ptr_bad!(
+    set_inode_flags,
+    nix::request_code_write!(b'f', 2, std::mem::size_of::<libc::c_long>()),
+    libc::c_int
+);
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|_| io::ErrorKind::InvalidInput.into())
+}
+
+/// Runs a libc getter first for the size then for the value, again if it grew in between.
+fn read_buffer(mut call: impl FnMut(*mut u8, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    loop {
+        let len = call(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        let len = call(buf.as_mut_ptr(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) {
+            return Err(error);
+        }
+    }
+}
+
+/// Extended attributes of `path` itself, the ones we aren't allowed to read are left out.
+pub fn read_xattrs(path: &Path) -> io::Result<Vec<ExtendedAttribute>> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let names = match read_buffer(|buf, len| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf as *mut libc::c_char, len)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = Vec::new();
+    for name in names
+        .split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        // The wire carries names as strings, no known namespace uses anything else
+        let Ok(utf8_name) = std::str::from_utf8(name) else {
+            continue;
+        };
+
+        let c_name = c_string(name)?;
+        let value = match read_buffer(|buf, len| unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                buf as *mut libc::c_void,
+                len,
+            )
+        }) {
+            Ok(value) => value,
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::ENODATA | libc::EACCES | libc::EPERM)
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        xattrs.push(protos::create_extended_attribute(
+            String::from(utf8_name),
+            value,
+        ));
+    }
+
+    Ok(xattrs)
+}
+
+/// Value of one extended attribute of `path` itself, `None` if it isn't set.
+pub fn read_xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let c_name = c_string(name.as_bytes())?;
+
+    match read_buffer(|buf, len| unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            buf as *mut libc::c_void,
+            len,
+        )
+    }) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENODATA | libc::ENOTSUP)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sets every attribute on `path` itself, trying them all before reporting the first failure.
+pub fn write_xattrs(path: &Path, xattrs: &[ExtendedAttribute]) -> io::Result<()> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+
+    let mut result = Ok(());
+    for xattr in xattrs {
+        let c_name = c_string(xattr.name.as_bytes())?;
+        let ret = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                xattr.value.as_ptr() as *const libc::c_void,
+                xattr.value.len(),
+                0,
+            )
+        };
+
+        if ret < 0 && result.is_ok() {
+            result = Err(io::Error::last_os_error());
+        }
+    }
+
+    result
+}
+
+/// Restorable inode flags of a file or directory, 0 where the filesystem has none.
+pub fn read_flags(path: &Path) -> u32 {
+    let file = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(_) => return 0,
+    };
+
+    let mut flags: libc::c_int = 0;
+    match unsafe { get_inode_flags(file.as_raw_fd(), &mut flags) } {
+        Ok(_) => flags as u32 & RESTORABLE_FLAGS,
+        Err(_) => 0,
+    }
+}
+
+/// Replaces the restorable inode flags of an open file, leaving the filesystem's own alone.
+pub fn write_flags(file: &File, flags: u32) -> io::Result<()> {
+    let mut current: libc::c_int = 0;
+    if let Err(e) = unsafe { get_inode_flags(file.as_raw_fd(), &mut current) } {
+        // A filesystem without flags already has the ones asked for
+        return if flags & RESTORABLE_FLAGS == 0 {
+            Ok(())
+        } else {
+            Err(e.into())
+        };
+    }
+
+    let wanted = (current as u32 & !RESTORABLE_FLAGS) | (flags & RESTORABLE_FLAGS);
+    if wanted != current as u32 {
+        unsafe { set_inode_flags(file.as_raw_fd(), &(wanted as libc::c_int)) }?;
+    }
+
+    Ok(())
+}
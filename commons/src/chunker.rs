@@ -0,0 +1,250 @@
+use std::path::Path;
+
+use protos::{Body, Chunk};
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    envelope::{self, ReceiveError},
+    file_manager, mux,
+    packeter::WritePacketError,
+};
+
+/// Smaller files are sent and stored whole, they would make a single chunk anyway.
+pub const CHUNKING_THRESHOLD: u64 = 1024 * 1024;
+
+pub const MIN_CHUNK_SIZE: usize = 256 * 1024;
+const AVG_CHUNK_SIZE: usize = 1024 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const CHUNK_LIST_BATCH_SIZE: usize = 1024;
+
+/// Cut points are harder to hit before the average size and easier past it, which keeps
+/// chunk sizes close to it. The masks test the top bits, that depend on the last 64 bytes.
+const MASK_SMALL: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() + 2);
+const MASK_LARGE: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() - 2);
+
+const GEAR: [u64; 256] = gear_table();
+
+#[derive(Error, Debug)]
+pub enum ChunkListTransferError {
+    #[error("Failed to read chunk list: {0}")]
+    ReadError(#[from] ReceiveError),
+    #[error("Failed to write chunk list: {0}")]
+    WriteError(#[from] WritePacketError),
+    #[error("Expected a chunk list, got {0}")]
+    UnexpectedMessage(&'static str),
+    #[error("Chunk list has more chunks than announced")]
+    TooManyChunks,
+    #[error("Chunk list announces {0} chunks, more than the file can have")]
+    ChunkCountTooLarge(u64),
+}
+
+const fn mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+/// Value each byte adds to the fingerprint, fixed so every build cuts a file the same way.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x7a65_6e2d_7379_6e63;
+    let mut i = 0;
+    while i < table.len() {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+}
+
+/// Length of the chunk `data` starts with, FastCDC style. `data` must hold the rest of the
+/// file or at least `MAX_CHUNK_SIZE` bytes.
+pub fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+
+    let mut fingerprint = 0u64;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        fingerprint = (fingerprint << 1).wrapping_add(GEAR[*byte as usize]);
+
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if fingerprint & mask == 0 {
+            return i + 1;
+        }
+    }
+
+    end
+}
+
+/// Chunks of a file along with the hash of its whole content.
+pub struct ChunkedFile {
+    pub chunks: Vec<Chunk>,
+    pub hash: Vec<u8>,
+}
+
+/// Cuts a file into chunks, hashing them and the whole file in a single read.
+pub async fn chunk_file(path: &Path) -> std::io::Result<ChunkedFile> {
+    let mut file = file_manager::open_noatime(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut chunks = Vec::new();
+
+    let mut buf = Vec::with_capacity(MAX_CHUNK_SIZE);
+    let mut eof = false;
+    loop {
+        if !eof {
+            let wanted = (MAX_CHUNK_SIZE - buf.len()) as u64;
+            let n = (&mut file).take(wanted).read_to_end(&mut buf).await? as u64;
+            eof = n < wanted;
+        }
+
+        if buf.is_empty() {
+            break;
+        }
+
+        let len = cut_point(&buf);
+        let chunk = &buf[..len];
+        hasher.update(chunk);
+        chunks.push(protos::create_chunk(
+            blake3::hash(chunk).as_bytes().to_vec(),
+            len as u32,
+        ));
+        buf.drain(..len);
+    }
+
+    Ok(ChunkedFile {
+        chunks,
+        hash: hasher.finalize().as_bytes().to_vec(),
+    })
+}
+
+/// Sends the chunk list that follows a chunks request, in batches that fit in one packet.
+pub async fn send_chunk_list(
+    stream: &mut mux::Stream,
+    chunks: &[Chunk],
+) -> Result<(), ChunkListTransferError> {
+    for batch in chunks.chunks(CHUNK_LIST_BATCH_SIZE) {
+        envelope::send(
+            stream,
+            Body::ChunkList(protos::create_chunk_list(batch.to_vec())),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Receives the chunk list of a file of `file_size` bytes, refusing more chunks than it can be
+/// cut into.
+pub async fn receive_chunk_list(
+    stream: &mut mux::Stream,
+    chunk_count: u64,
+    file_size: u64,
+) -> Result<Vec<Chunk>, ChunkListTransferError> {
+    if chunk_count > file_size / MIN_CHUNK_SIZE as u64 + 1 {
+        return Err(ChunkListTransferError::ChunkCountTooLarge(chunk_count));
+    }
+
+    let mut chunks = Vec::new();
+    while (chunks.len() as u64) < chunk_count {
+        match envelope::receive(stream).await? {
+            Body::ChunkList(batch) => chunks.extend(batch.chunks),
+            body => {
+                return Err(ChunkListTransferError::UnexpectedMessage(envelope::name(
+                    &body,
+                )))
+            }
+        }
+        if chunks.len() as u64 > chunk_count {
+            return Err(ChunkListTransferError::TooManyChunks);
+        }
+    }
+
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        StdRng::seed_from_u64(seed).fill(&mut bytes[..]);
+        bytes
+    }
+
+    /// Chunk lengths of `data`, cut the way `chunk_file` does.
+    fn cut(data: &[u8]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let end = data.len().min(offset + MAX_CHUNK_SIZE);
+            let len = cut_point(&data[offset..end]);
+            lengths.push(len);
+            offset += len;
+        }
+        lengths
+    }
+
+    fn assert_sizes(lengths: &[usize]) {
+        let (last, rest) = lengths.split_last().unwrap();
+        for len in rest {
+            assert!((MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(len), "{}", len);
+        }
+        assert!((1..=MAX_CHUNK_SIZE).contains(last), "{}", last);
+    }
+
+    #[test]
+    fn cuts_the_same_content_the_same_way() {
+        let data = random_bytes(1, 8 * AVG_CHUNK_SIZE);
+        let lengths = cut(&data);
+        assert_eq!(cut(&data), lengths);
+        assert!(lengths.len() > 1);
+
+        // Content past an insertion is still cut at the same places
+        let shifted = [random_bytes(2, 1000).as_slice(), &data].concat();
+        assert!(cut(&shifted).ends_with(&lengths[2..]));
+    }
+
+    #[test]
+    fn keeps_chunk_sizes_in_range() {
+        assert_sizes(&cut(&random_bytes(3, 6 * AVG_CHUNK_SIZE + 17)));
+        // Content without any cut point is cut at the largest size
+        let zeros = cut(&vec![0u8; 2 * MAX_CHUNK_SIZE + 5]);
+        assert_sizes(&zeros);
+        assert_eq!(zeros, [MAX_CHUNK_SIZE, MAX_CHUNK_SIZE, 5]);
+        assert_eq!(cut(&random_bytes(4, MIN_CHUNK_SIZE)), [MIN_CHUNK_SIZE]);
+    }
+
+    #[tokio::test]
+    async fn chunks_files_like_their_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let data = random_bytes(5, 6 * AVG_CHUNK_SIZE + 3);
+        std::fs::write(&path, &data).unwrap();
+
+        let chunked = chunk_file(&path).await.unwrap();
+        let lengths: Vec<_> = chunked.chunks.iter().map(|c| c.size as usize).collect();
+        assert_eq!(lengths, cut(&data));
+        assert_eq!(chunked.hash, blake3::hash(&data).as_bytes());
+
+        let mut offset = 0;
+        for chunk in &chunked.chunks {
+            let end = offset + chunk.size as usize;
+            assert_eq!(chunk.hash, blake3::hash(&data[offset..end]).as_bytes());
+            offset = end;
+        }
+
+        let again = chunk_file(&path).await.unwrap();
+        assert_eq!(again.chunks, chunked.chunks);
+    }
+}
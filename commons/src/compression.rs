@@ -0,0 +1,154 @@
+use std::path::Path;
+
+use protos::{delta_operation::Operation, Capability, Compression, DeltaOperation};
+use thiserror::Error;
+
+use crate::session::Capabilities;
+
+const ZSTD_LEVEL: i32 = 3;
+/// Upper bound of a decoded frame, no sender ever puts more than this in one packet.
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// Compression is given up for the rest of a transfer after this many chunks didn't shrink.
+const MAX_INCOMPRESSIBLE_CHUNKS: u32 = 4;
+
+const FRAME_RAW: u8 = 0;
+const FRAME_ZSTD: u8 = 1;
+const FRAME_LZ4: u8 = 2;
+
+/// Extensions of formats that are already compressed, there is nothing to gain on them.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz",
+    "heic", "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt",
+    "ogg", "opus", "pdf", "png", "pptx", "rar", "rpm", "tgz", "webm", "webp", "xlsx", "xz", "zip",
+    "zst",
+];
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("Empty frame")]
+    EmptyFrame,
+    #[error("Unexpected frame codec {0}")]
+    UnexpectedCodec(u8),
+    #[error("Frame decodes to more than {MAX_FRAME_LEN} bytes")]
+    TooLarge,
+    #[error("Failed to decompress zstd frame: {0}")]
+    ZstdError(#[from] std::io::Error),
+    #[error("Failed to decompress lz4 frame: {0}")]
+    Lz4Error(#[from] lz4_flex::block::DecompressError),
+}
+
+/// Whether both peers announced the capability needed by `compression`.
+pub fn is_negotiated(compression: Compression, capabilities: &Capabilities) -> bool {
+    match compression {
+        Compression::None => true,
+        Compression::Zstd => capabilities.has(Capability::CompressionZstd),
+        Compression::Lz4 => capabilities.has(Capability::CompressionLz4),
+    }
+}
+
+/// Guesses from its extension whether a file is worth compressing.
+pub fn is_compressible(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => !COMPRESSED_EXTENSIONS
+            .iter()
+            .any(|compressed| extension.eq_ignore_ascii_case(compressed)),
+        None => true,
+    }
+}
+
+/// Picks the codec of a transfer: the preferred one if it was negotiated and the file looks compressible.
+pub fn choose(preferred: Compression, capabilities: &Capabilities, path: &Path) -> Compression {
+    if is_negotiated(preferred, capabilities) && is_compressible(path) {
+        preferred
+    } else {
+        Compression::None
+    }
+}
+
+/// Encodes the payload frames of one transfer, each prefixed with the codec it was written with.
+pub struct Compressor {
+    compression: Compression,
+    incompressible_chunks: u32,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            incompressible_chunks: 0,
+        }
+    }
+
+    pub fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.incompressible_chunks < MAX_INCOMPRESSIBLE_CHUNKS {
+            let compressed = match self.compression {
+                Compression::None => None,
+                Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)
+                    .ok()
+                    .map(|compressed| (FRAME_ZSTD, compressed)),
+                Compression::Lz4 => Some((FRAME_LZ4, lz4_flex::compress_prepend_size(data))),
+            };
+
+            match compressed {
+                Some((codec, compressed)) if compressed.len() < data.len() => {
+                    return frame(codec, &compressed);
+                }
+                Some(_) => self.incompressible_chunks += 1,
+                None => {}
+            }
+        }
+
+        frame(FRAME_RAW, data)
+    }
+
+    /// Compresses the literal of a delta operation, block copies are left as they are.
+    pub fn encode_operation(&mut self, operation: DeltaOperation) -> DeltaOperation {
+        match operation.operation {
+            Some(Operation::Literal(data)) => protos::create_delta_literal(self.encode(&data)),
+            _ => operation,
+        }
+    }
+}
+
+fn frame(codec: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(codec);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Decodes a frame written by a `Compressor` of the given codec.
+pub fn decode(compression: Compression, frame: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    let (codec, data) = frame.split_first().ok_or(CompressionError::EmptyFrame)?;
+
+    match (*codec, compression) {
+        (FRAME_RAW, _) => Ok(data.to_vec()),
+        (FRAME_ZSTD, Compression::Zstd) => Ok(zstd::bulk::decompress(data, MAX_FRAME_LEN)?),
+        (FRAME_LZ4, Compression::Lz4) => {
+            // The announced size is checked first, the decoder would allocate it blindly
+            let len = data
+                .get(..4)
+                .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                .unwrap_or_default();
+            if len > MAX_FRAME_LEN {
+                return Err(CompressionError::TooLarge);
+            }
+
+            Ok(lz4_flex::decompress_size_prepended(data)?)
+        }
+        (codec, _) => Err(CompressionError::UnexpectedCodec(codec)),
+    }
+}
+
+/// Decodes the literal of a delta operation written by `Compressor::encode_operation`.
+pub fn decode_operation(
+    compression: Compression,
+    operation: DeltaOperation,
+) -> Result<DeltaOperation, CompressionError> {
+    match operation.operation {
+        Some(Operation::Literal(frame)) => {
+            Ok(protos::create_delta_literal(decode(compression, &frame)?))
+        }
+        _ => Ok(operation),
+    }
+}
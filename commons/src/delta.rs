@@ -0,0 +1,451 @@
+use std::{collections::HashMap, io::SeekFrom, path::Path};
+
+use protos::{delta_operation::Operation, BlockSignature, Body, DeltaOperation};
+use thiserror::Error;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{
+    envelope::{self, ReceiveError},
+    mux,
+    packeter::WritePacketError,
+};
+
+/// Files smaller than this are cheaper to send whole than to diff.
+pub const DELTA_THRESHOLD: u64 = 64 * 1024;
+
+const MIN_BLOCK_SIZE: u32 = 2048;
+const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+const MAX_LITERAL_SIZE: usize = 32768;
+const SIGNATURE_BATCH_SIZE: usize = 1024;
+/// Most blocks a received signature may have, as many as `block_size_for` gives a 1 TiB file.
+const MAX_SIGNATURE_BLOCKS: u64 = MAX_BLOCK_SIZE as u64;
+
+#[derive(Error, Debug)]
+pub enum ApplyDeltaError {
+    #[error("Failed to apply delta: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Empty delta operation")]
+    EmptyOperation,
+    #[error("No base file to copy blocks from")]
+    MissingBase,
+    #[error("Block {0} is out of range")]
+    BlockOutOfRange(u64),
+}
+
+#[derive(Error, Debug)]
+pub enum SignatureTransferError {
+    #[error("Failed to read signature: {0}")]
+    ReadError(#[from] ReceiveError),
+    #[error("Failed to write signature: {0}")]
+    WriteError(#[from] WritePacketError),
+    #[error("Expected signature blocks, got {0}")]
+    UnexpectedMessage(&'static str),
+    #[error("Signature has more blocks than announced")]
+    TooManyBlocks,
+    #[error("Signature announces {0} blocks, more than allowed")]
+    BlockCountTooLarge(u64),
+    #[error("Signature block size {0} is out of range")]
+    InvalidBlockSize(u32),
+}
+
+/// Rsync style weak checksum that can slide over a buffer one byte at a time.
+#[derive(Default, Clone, Copy)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+
+        Self { a, b, len }
+    }
+
+    pub fn roll(&mut self, out: u8, input: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(input as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+pub struct Signature {
+    pub block_size: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    pub fn empty(block_size: u32) -> Self {
+        Self {
+            block_size,
+            blocks: Vec::new(),
+        }
+    }
+}
+
+pub fn block_size_for(len: u64) -> u32 {
+    let block_size = (len as f64).sqrt() as u64;
+    block_size.clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+async fn read_block(file: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+
+    Ok(filled)
+}
+
+pub async fn compute_signature(path: &Path, block_size: u32) -> std::io::Result<Signature> {
+    signature_of(&mut File::open(path).await?, block_size).await
+}
+
+/// Signature of everything left to read from `file`.
+pub async fn signature_of(
+    file: &mut (impl AsyncRead + Unpin),
+    block_size: u32,
+) -> std::io::Result<Signature> {
+    let mut signature = Signature::empty(block_size);
+
+    let mut buf = vec![0u8; block_size as usize];
+    loop {
+        let n = read_block(file, &mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        signature.blocks.push(BlockSignature {
+            weak: RollingChecksum::new(&buf[..n]).digest(),
+            strong: blake3::hash(&buf[..n]).as_bytes().to_vec(),
+        });
+    }
+
+    Ok(signature)
+}
+
+/// Sends the block list that follows a signature header, in batches that fit in one packet.
+pub async fn send_signature_blocks(
+    stream: &mut mux::Stream,
+    signature: Signature,
+) -> Result<(), SignatureTransferError> {
+    let mut blocks = signature.blocks.into_iter().peekable();
+    while blocks.peek().is_some() {
+        let batch = blocks.by_ref().take(SIGNATURE_BATCH_SIZE).collect();
+        envelope::send(
+            stream,
+            Body::SignatureBlocks(protos::create_signature_blocks(batch)),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn receive_signature_blocks(
+    stream: &mut mux::Stream,
+    header: &protos::Signature,
+) -> Result<Signature, SignatureTransferError> {
+    // The block size sets what the delta generator buffers
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&header.block_size) {
+        return Err(SignatureTransferError::InvalidBlockSize(header.block_size));
+    }
+    // And the block count what is kept of the signature
+    if header.block_count > MAX_SIGNATURE_BLOCKS {
+        return Err(SignatureTransferError::BlockCountTooLarge(
+            header.block_count,
+        ));
+    }
+
+    let mut signature = Signature::empty(header.block_size);
+    while (signature.blocks.len() as u64) < header.block_count {
+        match envelope::receive(stream).await? {
+            Body::SignatureBlocks(batch) => signature.blocks.extend(batch.blocks),
+            body => {
+                return Err(SignatureTransferError::UnexpectedMessage(envelope::name(
+                    &body,
+                )))
+            }
+        }
+        if signature.blocks.len() as u64 > header.block_count {
+            return Err(SignatureTransferError::TooManyBlocks);
+        }
+    }
+
+    Ok(signature)
+}
+
+/// Turns a stream of new file content into literal runs and references to blocks
+/// of the receiver's copy.
+pub struct DeltaGenerator {
+    block_size: usize,
+    weak_table: HashMap<u32, Vec<u64>>,
+    blocks: Vec<BlockSignature>,
+    buffer: Vec<u8>,
+    position: usize,
+    literal_start: usize,
+    rolling: Option<RollingChecksum>,
+}
+
+impl DeltaGenerator {
+    pub fn new(signature: Signature) -> Self {
+        let mut weak_table: HashMap<u32, Vec<u64>> = HashMap::new();
+        for (index, block) in signature.blocks.iter().enumerate() {
+            weak_table.entry(block.weak).or_default().push(index as u64);
+        }
+
+        Self {
+            block_size: signature.block_size as usize,
+            weak_table,
+            blocks: signature.blocks,
+            buffer: Vec::new(),
+            position: 0,
+            literal_start: 0,
+            rolling: None,
+        }
+    }
+
+    fn find_block(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        let candidates = self.weak_table.get(&weak)?;
+        let strong = blake3::hash(window);
+
+        candidates
+            .iter()
+            .find(|index| self.blocks[**index as usize].strong == strong.as_bytes())
+            .copied()
+    }
+
+    fn flush_literal(&mut self, end: usize, operations: &mut Vec<DeltaOperation>) {
+        while self.literal_start < end {
+            let chunk_end = end.min(self.literal_start + MAX_LITERAL_SIZE);
+            let literal = self.buffer[self.literal_start..chunk_end].to_vec();
+            operations.push(protos::create_delta_literal(literal));
+            self.literal_start = chunk_end;
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Vec<DeltaOperation> {
+        let mut operations = Vec::new();
+        self.buffer.extend_from_slice(data);
+
+        if self.weak_table.is_empty() {
+            let pending = self.buffer.len() - self.literal_start;
+            let end = self.literal_start + pending - pending % MAX_LITERAL_SIZE;
+            self.flush_literal(end, &mut operations);
+            self.position = self.literal_start;
+        } else {
+            while self.position + self.block_size <= self.buffer.len() {
+                let window_end = self.position + self.block_size;
+                let rolling = *self.rolling.get_or_insert_with(|| {
+                    RollingChecksum::new(&self.buffer[self.position..window_end])
+                });
+
+                let window = &self.buffer[self.position..window_end];
+                if let Some(index) = self.find_block(rolling.digest(), window) {
+                    self.flush_literal(self.position, &mut operations);
+                    operations.push(protos::create_delta_copy(index));
+                    self.position = window_end;
+                    self.literal_start = window_end;
+                    self.rolling = None;
+                    continue;
+                }
+
+                if window_end == self.buffer.len() {
+                    break;
+                }
+
+                let mut rolling = rolling;
+                rolling.roll(self.buffer[self.position], self.buffer[window_end]);
+                self.rolling = Some(rolling);
+                self.position += 1;
+
+                if self.position - self.literal_start >= MAX_LITERAL_SIZE {
+                    self.flush_literal(self.position, &mut operations);
+                }
+            }
+        }
+
+        self.buffer.drain(..self.literal_start);
+        self.position -= self.literal_start;
+        self.literal_start = 0;
+
+        operations
+    }
+
+    pub fn finish(mut self) -> Vec<DeltaOperation> {
+        let mut operations = Vec::new();
+
+        if let Some(last) = self.blocks.last() {
+            let tail = &self.buffer[self.literal_start..];
+            if !tail.is_empty()
+                && tail.len() < self.block_size
+                && last.strong == blake3::hash(tail).as_bytes()
+            {
+                operations.push(protos::create_delta_copy(self.blocks.len() as u64 - 1));
+                return operations;
+            }
+        }
+
+        let end = self.buffer.len();
+        self.flush_literal(end, &mut operations);
+
+        operations
+    }
+}
+
+/// Number of bytes an operation expands to, or `None` if it can't be applied to a
+/// base of `base_len` bytes.
+pub fn operation_len(operation: &DeltaOperation, block_size: u32, base_len: u64) -> Option<u64> {
+    match &operation.operation {
+        Some(Operation::Literal(data)) => Some(data.len() as u64),
+        Some(Operation::Copy(index)) => {
+            let offset = index.checked_mul(block_size as u64)?;
+            (offset < base_len).then(|| (base_len - offset).min(block_size as u64))
+        }
+        None => None,
+    }
+}
+
+/// Applies one delta operation, appending its content to `output`. Returns the
+/// number of bytes written.
+pub async fn apply_operation(
+    base: Option<&mut (impl AsyncRead + AsyncSeek + Unpin)>,
+    block_size: u32,
+    operation: DeltaOperation,
+    output: &mut (impl AsyncWrite + Unpin),
+) -> Result<u64, ApplyDeltaError> {
+    match operation.operation {
+        Some(Operation::Literal(data)) => {
+            output.write_all(&data).await?;
+            Ok(data.len() as u64)
+        }
+        Some(Operation::Copy(index)) => {
+            let base = base.ok_or(ApplyDeltaError::MissingBase)?;
+            let offset = index
+                .checked_mul(block_size as u64)
+                .ok_or(ApplyDeltaError::BlockOutOfRange(index))?;
+            if offset >= base.seek(SeekFrom::End(0)).await? {
+                return Err(ApplyDeltaError::BlockOutOfRange(index));
+            }
+
+            let mut buf = vec![0u8; block_size as usize];
+            base.seek(SeekFrom::Start(offset)).await?;
+            let n = read_block(base, &mut buf).await?;
+            output.write_all(&buf[..n]).await?;
+
+            Ok(n as u64)
+        }
+        None => Err(ApplyDeltaError::EmptyOperation),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        rng.fill(&mut bytes[..]);
+        bytes
+    }
+
+    /// Diffs `new` against a signature of `base` and rebuilds it from the delta.
+    async fn round_trip(base: &[u8], new: &[u8], block_size: u32) -> Vec<u8> {
+        let signature = signature_of(&mut &base[..], block_size).await.unwrap();
+        let mut generator = DeltaGenerator::new(signature);
+        let mut operations = Vec::new();
+        for piece in new.chunks(5000) {
+            operations.extend(generator.update(piece));
+        }
+        operations.extend(generator.finish());
+
+        let mut base = Cursor::new(base.to_vec());
+        let mut output = Vec::new();
+        for operation in operations {
+            apply_operation(Some(&mut base), block_size, operation, &mut output)
+                .await
+                .unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn rolling_matches_recomputed_checksum() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let data = random_bytes(&mut rng, 4096);
+
+        for window in [1, 7, 64, 2048] {
+            let mut rolling = RollingChecksum::new(&data[..window]);
+            for start in 1..=data.len() - window {
+                rolling.roll(data[start - 1], data[start + window - 1]);
+                assert_eq!(
+                    rolling.digest(),
+                    RollingChecksum::new(&data[start..start + window]).digest(),
+                    "window {} at {}",
+                    window,
+                    start
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_edited_files() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let block_size = MIN_BLOCK_SIZE;
+        let base = random_bytes(&mut rng, 20 * block_size as usize + 123);
+
+        let mut inserted = base.clone();
+        inserted.splice(5000..5000, random_bytes(&mut rng, 777));
+        let mut removed = base.clone();
+        removed.drain(3000..9000);
+        let mut changed = base.clone();
+        changed[10_000] ^= 0xff;
+        let appended = [base.as_slice(), &random_bytes(&mut rng, 4000)].concat();
+        let unrelated = random_bytes(&mut rng, base.len());
+
+        for new in [
+            base.clone(),
+            inserted,
+            removed,
+            changed,
+            appended,
+            unrelated,
+        ] {
+            assert!(round_trip(&base, &new, block_size).await == new);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_without_base() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let new = random_bytes(&mut rng, 3 * MAX_LITERAL_SIZE + 10);
+
+        assert!(round_trip(&[], &new, MIN_BLOCK_SIZE).await == new);
+        assert!(round_trip(&new, &[], MIN_BLOCK_SIZE).await.is_empty());
+    }
+}
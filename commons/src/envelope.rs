@@ -0,0 +1,115 @@
+use protos::Body;
+use thiserror::Error;
+
+use crate::{
+    mux,
+    packeter::{ReadPacketError, WritePacketError},
+};
+
+#[derive(Error, Debug)]
+pub enum ReceiveError {
+    #[error("Failed to read envelope: {0}")]
+    ReadError(#[from] ReadPacketError),
+    #[error("Failed to decode envelope: {0}")]
+    DecodeError(#[from] prost::DecodeError),
+    #[error("Envelope without a body")]
+    Empty,
+}
+
+pub async fn send(stream: &mut mux::Stream, body: Body) -> Result<(), WritePacketError> {
+    stream.write_packet(&protos::serialize_envelope(body)).await
+}
+
+pub async fn receive(stream: &mut mux::Stream) -> Result<Body, ReceiveError> {
+    let packet = stream.read_packet().await?;
+
+    protos::deserialize_envelope(&packet)?
+        .body
+        .ok_or(ReceiveError::Empty)
+}
+
+/// Request a message belongs to, `None` for the ones that aren't a request or a response.
+pub fn request_id(body: &Body) -> Option<u64> {
+    match body {
+        Body::RequestAdd(request) => Some(request.request_id),
+        Body::RequestMove(request) => Some(request.request_id),
+        Body::RequestRemove(request) => Some(request.request_id),
+        Body::RequestGet(request) => Some(request.request_id),
+        Body::RequestSync(request) => Some(request.request_id),
+        Body::RequestResume(request) => Some(request.request_id),
+        Body::RequestStat(request) => Some(request.request_id),
+        Body::RequestList(request) => Some(request.request_id),
+        Body::RequestOffer(request) => Some(request.request_id),
+        Body::RequestChunks(request) => Some(request.request_id),
+        Body::RequestVersions(request) => Some(request.request_id),
+        Body::RequestSnapshot(request) => Some(request.request_id),
+        Body::RequestSnapshots(request) => Some(request.request_id),
+        Body::RequestTrash(request) => Some(request.request_id),
+        Body::RequestRestoreTrash(request) => Some(request.request_id),
+        Body::RequestEmptyTrash(request) => Some(request.request_id),
+        Body::ResponseGet(response) => Some(response.request_id),
+        Body::ResponseSync(response) => Some(response.request_id),
+        Body::ResponseSignature(response) => Some(response.request_id),
+        Body::ResponseAck(response) => Some(response.request_id),
+        Body::ResponseResume(response) => Some(response.request_id),
+        Body::ResponseError(response) => Some(response.request_id),
+        Body::ResponseStat(response) => Some(response.request_id),
+        Body::ResponseList(response) => Some(response.request_id),
+        Body::ResponseOffer(response) => Some(response.request_id),
+        Body::ResponseChunks(response) => Some(response.request_id),
+        Body::ResponseMove(response) => Some(response.request_id),
+        Body::ResponseVersions(response) => Some(response.request_id),
+        Body::ResponseSnapshot(response) => Some(response.request_id),
+        Body::ResponseSnapshots(response) => Some(response.request_id),
+        Body::ResponseTrash(response) => Some(response.request_id),
+        Body::ResponseRestoreTrash(response) => Some(response.request_id),
+        Body::SignatureBlocks(_)
+        | Body::DeltaOperation(_)
+        | Body::Data(_)
+        | Body::EndOfFile(_)
+        | Body::ChunkList(_) => None,
+    }
+}
+
+/// Short name of a message, for protocol errors.
+pub fn name(body: &Body) -> &'static str {
+    match body {
+        Body::RequestAdd(_) => "add request",
+        Body::RequestMove(_) => "move request",
+        Body::RequestRemove(_) => "remove request",
+        Body::RequestGet(_) => "get request",
+        Body::RequestSync(_) => "sync request",
+        Body::RequestResume(_) => "resume request",
+        Body::RequestStat(_) => "stat request",
+        Body::RequestList(_) => "list request",
+        Body::RequestOffer(_) => "offer request",
+        Body::RequestChunks(_) => "chunks request",
+        Body::RequestVersions(_) => "versions request",
+        Body::RequestSnapshot(_) => "snapshot request",
+        Body::RequestSnapshots(_) => "snapshots request",
+        Body::RequestTrash(_) => "trash request",
+        Body::RequestRestoreTrash(_) => "trash restore request",
+        Body::RequestEmptyTrash(_) => "empty trash request",
+        Body::ResponseGet(_) => "get response",
+        Body::ResponseSync(_) => "sync response",
+        Body::ResponseSignature(_) => "signature response",
+        Body::ResponseAck(_) => "acknowledgement",
+        Body::ResponseResume(_) => "resume response",
+        Body::ResponseError(_) => "error response",
+        Body::ResponseStat(_) => "stat response",
+        Body::ResponseList(_) => "list response",
+        Body::ResponseOffer(_) => "offer response",
+        Body::ResponseChunks(_) => "chunks response",
+        Body::ResponseMove(_) => "move response",
+        Body::ResponseVersions(_) => "versions response",
+        Body::ResponseSnapshot(_) => "snapshot response",
+        Body::ResponseSnapshots(_) => "snapshots response",
+        Body::ResponseTrash(_) => "trash response",
+        Body::ResponseRestoreTrash(_) => "trash restore response",
+        Body::SignatureBlocks(_) => "signature blocks",
+        Body::DeltaOperation(_) => "delta operation",
+        Body::Data(_) => "data chunk",
+        Body::EndOfFile(_) => "end of file",
+        Body::ChunkList(_) => "chunk list",
+    }
+}
@@ -1,18 +1,25 @@
 use std::{
-    fs::{Metadata, Permissions},
-    os::unix::fs::{MetadataExt, PermissionsExt},
-    path::PathBuf,
+    fs::{FileTimes, Metadata, Permissions},
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
     time::{Duration, SystemTime},
 };
 
-use anyhow::bail;
+use anyhow::{bail, Context};
+use nix::sys::{
+    stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags},
+    time::TimeSpec,
+};
+use nix::{libc, unistd::mkfifo};
 use rand::Rng;
 use thiserror::Error;
 use tokio::{
     fs::{self, File, OpenOptions},
-    io::{AsyncReadExt, BufReader},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader},
 };
 
+use crate::attributes;
+
 #[derive(Error, Debug)]
 pub enum CreateTemporaryFileError {
     #[error("Failed to create temporary file: {0}")]
@@ -42,14 +49,63 @@ pub fn file_type_convert(file_type: std::fs::FileType) -> anyhow::Result<protos:
         Ok(protos::FileType::Directory)
     } else if file_type.is_file() {
         Ok(protos::FileType::File)
+    } else if file_type.is_symlink() {
+        Ok(protos::FileType::Symlink)
+    } else if file_type.is_fifo() {
+        Ok(protos::FileType::Fifo)
+    } else if file_type.is_char_device() {
+        Ok(protos::FileType::CharDevice)
+    } else if file_type.is_block_device() {
+        Ok(protos::FileType::BlockDevice)
     } else {
         bail!("Unsupported file type");
     }
 }
 
+/// Whether entries of this type are recreated from their metadata alone, without any content.
+pub fn is_node(file_type: protos::FileType) -> bool {
+    !matches!(
+        file_type,
+        protos::FileType::File | protos::FileType::Directory
+    )
+}
+
+/// Opens a file for reading without updating its access time, when we are allowed to.
+pub async fn open_noatime(path: &Path) -> std::io::Result<File> {
+    open_reading(path, 0).await
+}
+
+/// Opens a file like `open_noatime`, failing rather than reading through it if `path` is a
+/// symlink.
+pub async fn open_noatime_nofollow(path: &Path) -> std::io::Result<File> {
+    open_reading(path, libc::O_NOFOLLOW).await
+}
+
+async fn open_reading(path: &Path, flags: libc::c_int) -> std::io::Result<File> {
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOATIME | flags)
+        .open(path)
+        .await
+    {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(flags)
+                .open(path)
+                .await
+        }
+        result => result,
+    }
+}
+
 async fn hash_file(file_path: &PathBuf) -> anyhow::Result<Vec<u8>> {
+    Ok(hash_reader(open_noatime(file_path).await?).await?)
+}
+
+/// Hashes everything read from `file`.
+pub async fn hash_reader(file: impl AsyncRead + Unpin) -> std::io::Result<Vec<u8>> {
     let mut hasher = blake3::Hasher::new();
-    let file = File::open(file_path).await?;
     let mut file_reader = BufReader::new(file);
 
     let mut buf = vec![0u8; 524288];
@@ -64,31 +120,99 @@ async fn hash_file(file_path: &PathBuf) -> anyhow::Result<Vec<u8>> {
     Ok(hasher.finalize().as_bytes().to_vec())
 }
 
+/// Hashes the first `len` bytes of a file, used to check that a partial transfer still
+/// matches the file it is resumed from.
+pub async fn hash_prefix(file_path: &Path, len: u64) -> std::io::Result<Vec<u8>> {
+    let hasher = prefix_hasher(file_path, len).await?;
+
+    Ok(hasher.finalize().as_bytes().to_vec())
+}
+
+/// Hasher fed with the first `len` bytes of a file, for a transfer that continues past them.
+pub async fn prefix_hasher(file_path: &Path, len: u64) -> std::io::Result<blake3::Hasher> {
+    reader_prefix_hasher(open_noatime(file_path).await?, len).await
+}
+
+/// Hasher fed with the first `len` bytes read from `file`.
+pub async fn reader_prefix_hasher(
+    file: impl AsyncRead + Unpin,
+    len: u64,
+) -> std::io::Result<blake3::Hasher> {
+    let mut hasher = blake3::Hasher::new();
+    let mut file_reader = BufReader::new(file).take(len);
+
+    let mut buf = vec![0u8; 524288];
+    loop {
+        let bytes_read = file_reader.read(&mut buf).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+    }
+
+    if file_reader.limit() > 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(hasher)
+}
+
+/// Describes the entry at `local_path` as `file_path`, `metadata` must not follow symlinks for
+/// a symlink to be described as one. Without a local path, only `metadata` is used.
 pub async fn create_from_metadata(
     file_path: &PathBuf,
-    hash_path: Option<&PathBuf>,
+    local_path: Option<&PathBuf>,
     metadata: &Metadata,
+    hash_content: bool,
 ) -> anyhow::Result<protos::File> {
     let file_type = file_type_convert(metadata.file_type())?;
     let path = String::from(file_path.to_str().unwrap());
     let len = metadata.len();
 
     let mut hash = None;
-    if !metadata.file_type().is_dir() {
-        if let Some(hash_path) = hash_path {
-            hash = Some(hash_file(hash_path).await?);
+    let mut link_target = None;
+    let mut xattrs = Vec::new();
+    let mut flags = 0;
+    if let Some(local_path) = local_path {
+        if metadata.is_file() && hash_content {
+            hash = Some(hash_file(local_path).await?);
+        } else if metadata.is_symlink() {
+            let target = fs::read_link(local_path).await?;
+            let target = target.to_str().with_context(|| {
+                format!(
+                    "Target of symlink {} isn't valid UTF-8",
+                    local_path.display()
+                )
+            })?;
+            link_target = Some(String::from(target));
         }
+
+        let local_path = local_path.clone();
+        let has_flags = metadata.is_file() || metadata.is_dir();
+        (xattrs, flags) = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            let flags = if has_flags {
+                attributes::read_flags(&local_path)
+            } else {
+                0
+            };
+
+            Ok((attributes::read_xattrs(&local_path)?, flags))
+        })
+        .await??;
     }
 
     let file_owner = metadata.uid();
     let file_group = metadata.gid();
     let file_permissions = metadata.permissions().mode();
-    let last_modified = metadata
+    let modified = metadata
         .modified()?
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
+        .duration_since(SystemTime::UNIX_EPOCH)?;
+    let accessed = metadata
+        .accessed()?
+        .duration_since(SystemTime::UNIX_EPOCH)?;
+    let last_modified = modified.as_secs();
 
-    let file_add = protos::create_file(
+    let mut file_add = protos::create_file(
         file_type,
         path,
         len,
@@ -98,14 +222,24 @@ pub async fn create_from_metadata(
         file_permissions,
         last_modified,
     );
+    file_add.link_target = link_target;
+    file_add.last_modified_nanos = modified.subsec_nanos();
+    file_add.last_accessed = accessed.as_secs();
+    file_add.last_accessed_nanos = accessed.subsec_nanos();
+    file_add.xattrs = xattrs;
+    file_add.flags = flags;
+    if matches!(
+        file_type,
+        protos::FileType::CharDevice | protos::FileType::BlockDevice
+    ) {
+        file_add.device = metadata.rdev();
+    }
 
     Ok(file_add)
 }
 
-pub async fn open_temporary_file(
-    path: &PathBuf,
-) -> Result<(tokio::fs::File, PathBuf), CreateTemporaryFileError> {
-    let path = path.clone();
+/// Where a new version of `path` is written before it replaces it.
+pub fn temporary_path(path: &Path) -> Result<PathBuf, CreateTemporaryFileError> {
     let parent_path = if let Some(path) = path.parent() {
         if !path.exists() {
             return Err(CreateTemporaryFileError::ParentPathDoesNotExist);
@@ -122,7 +256,13 @@ pub async fn open_temporary_file(
         return Err(CreateTemporaryFileError::InvalidFileName);
     };
 
-    let temp_path = parent_path.join(temp_name);
+    Ok(parent_path.join(temp_name))
+}
+
+pub async fn open_temporary_file(
+    path: &Path,
+) -> Result<(tokio::fs::File, PathBuf), CreateTemporaryFileError> {
+    let temp_path = temporary_path(path)?;
     let temp_file = OpenOptions::new()
         .create_new(true)
         .append(true)
@@ -132,26 +272,188 @@ pub async fn open_temporary_file(
     Ok((temp_file, temp_path))
 }
 
+/// Where an interrupted download of `path` is kept until it can be resumed.
+pub fn partial_file_path(path: &Path) -> Result<PathBuf, CreateTemporaryFileError> {
+    let parent_path = path.parent().ok_or(CreateTemporaryFileError::InvalidPath)?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(CreateTemporaryFileError::InvalidFileName)?;
+
+    Ok(parent_path.join(format!(".{}.partial", name)))
+}
+
+/// Opens the partial file of `path` for appending, keeping only its first `offset` bytes.
+pub async fn open_partial_file(
+    path: &Path,
+    offset: u64,
+) -> Result<(tokio::fs::File, PathBuf), CreateTemporaryFileError> {
+    let partial_path = partial_file_path(path)?;
+    if !partial_path.parent().unwrap().exists() {
+        return Err(CreateTemporaryFileError::ParentPathDoesNotExist);
+    }
+
+    let mut partial_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&partial_path)
+        .await?;
+    partial_file.set_len(offset).await?;
+    partial_file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+    Ok((partial_file, partial_path))
+}
+
+fn timestamp(secs: u64, nanos: u32) -> Option<Duration> {
+    Duration::from_secs(secs).checked_add(Duration::from_nanos(nanos.into()))
+}
+
+/// Modification and access times of `file`, none for the access time of a peer that doesn't
+/// send it or for values out of range.
+fn file_times(file: &protos::File) -> (Option<Duration>, Option<Duration>) {
+    let modified = timestamp(file.last_modified, file.last_modified_nanos);
+    let accessed = if file.last_accessed > 0 || file.last_accessed_nanos > 0 {
+        timestamp(file.last_accessed, file.last_accessed_nanos)
+    } else {
+        None
+    };
+
+    (modified, accessed)
+}
+
+/// Gives the temporary file the metadata of `file` before moving it to `final_path`.
 pub async fn close_temporary_file(
     temp_file: std::fs::File,
     temp_path: &PathBuf,
     final_path: &PathBuf,
-    last_modified: u64,
-    file_permissions: u32,
-    file_owner: u32,
-    file_group: u32,
+    file: &protos::File,
 ) -> Result<(), std::io::Error> {
-    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(last_modified);
-    let permissions = Permissions::from_mode(file_permissions);
+    let mut times = FileTimes::new();
+    let (modified, accessed) = file_times(file);
+    if let Some(modified) = modified.and_then(|time| SystemTime::UNIX_EPOCH.checked_add(time)) {
+        times = times.set_modified(modified);
+    }
+    if let Some(accessed) = accessed.and_then(|time| SystemTime::UNIX_EPOCH.checked_add(time)) {
+        times = times.set_accessed(accessed);
+    }
+    let permissions = Permissions::from_mode(file.file_permissions);
+    let (file_owner, file_group) = (file.file_owner, file.file_group);
+    let xattrs = file.xattrs.clone();
 
     let clone = temp_path.clone();
-    tokio::task::spawn_blocking(move || {
-        let _ = temp_file.set_modified(modified);
-        let _ = temp_file.set_permissions(permissions);
-        std::os::unix::fs::chown(clone, Some(file_owner), Some(file_group))
-    });
+    let temp_file = tokio::task::spawn_blocking(move || {
+        // A new owner drops setuid bits and file capabilities, so it goes first
+        std::os::unix::fs::fchown(&temp_file, Some(file_owner), Some(file_group))?;
+        temp_file.set_permissions(permissions)?;
+        attributes::write_xattrs(&clone, &xattrs)?;
+        temp_file.set_times(times)?;
+        temp_file.sync_all()?;
+
+        Ok::<_, std::io::Error>(temp_file)
+    })
+    .await??;
 
     fs::rename(temp_path, final_path).await?;
 
+    let flags = file.flags;
+    let parent = final_path.parent().map(Path::to_path_buf);
+    tokio::task::spawn_blocking(move || {
+        // An immutable file can't be renamed anymore, flags come once it is in place
+        let _ = attributes::write_flags(&temp_file, flags);
+
+        // Persist the rename itself so an acknowledged file survives a crash
+        if let Some(parent) = parent {
+            std::fs::File::open(parent)?.sync_all()?;
+        }
+
+        Ok::<_, std::io::Error>(())
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// Creates the symlink, FIFO or device node described by `file` at `final_path`, replacing
+/// whatever is there.
+pub async fn create_node(file: &protos::File, final_path: &Path) -> anyhow::Result<()> {
+    let file_type = file.file_type();
+    let link_target = file.link_target.clone();
+    let device = file.device;
+    let permissions = file.file_permissions;
+    let (file_owner, file_group) = (file.file_owner, file.file_group);
+    let xattrs = file.xattrs.clone();
+    let (modified, accessed) = file_times(file);
+    let modified = modified.map_or(TimeSpec::UTIME_OMIT, TimeSpec::from_duration);
+    let accessed = accessed.map_or(TimeSpec::UTIME_OMIT, TimeSpec::from_duration);
+
+    let temp_path = temporary_path(final_path)?;
+    let clone = temp_path.clone();
+    let result = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mode = Mode::from_bits_truncate(permissions & 0o7777);
+        match file_type {
+            protos::FileType::Symlink => {
+                let target = link_target.context("Symlink without a target")?;
+                std::os::unix::fs::symlink(target, &clone)?;
+            }
+            protos::FileType::Fifo => mkfifo(&clone, mode)?,
+            protos::FileType::CharDevice => mknod(&clone, SFlag::S_IFCHR, mode, device)?,
+            protos::FileType::BlockDevice => mknod(&clone, SFlag::S_IFBLK, mode, device)?,
+            _ => bail!("{:?} is not a node", file_type),
+        }
+
+        // The mode of a symlink itself can't be changed, it is always 777
+        std::os::unix::fs::lchown(&clone, Some(file_owner), Some(file_group))?;
+        if file_type != protos::FileType::Symlink {
+            std::fs::set_permissions(&clone, Permissions::from_mode(permissions))?;
+        }
+        attributes::write_xattrs(&clone, &xattrs)?;
+        utimensat(
+            None,
+            &clone,
+            &accessed,
+            &modified,
+            UtimensatFlags::NoFollowSymlink,
+        )?;
+
+        Ok(())
+    })
+    .await?;
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+
+    if let Err(e) = fs::rename(&temp_path, final_path).await {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Makes `final_path` another name of the file at `source`, replacing whatever is there.
+pub async fn create_hardlink(source: &Path, final_path: &Path) -> anyhow::Result<()> {
+    if let (Ok(source_metadata), Ok(final_metadata)) = (
+        fs::symlink_metadata(source).await,
+        fs::symlink_metadata(final_path).await,
+    ) {
+        // Renaming over another name of the same file would leave the temporary link behind
+        if source_metadata.dev() == final_metadata.dev()
+            && source_metadata.ino() == final_metadata.ino()
+        {
+            return Ok(());
+        }
+    }
+
+    let temp_path = temporary_path(final_path)?;
+    fs::hard_link(source, &temp_path).await?;
+
+    if let Err(e) = fs::rename(&temp_path, final_path).await {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e.into());
+    }
+
     Ok(())
 }
@@ -0,0 +1,86 @@
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use thiserror::Error;
+use tokio::io::AsyncWrite;
+
+use crate::file_manager;
+
+#[derive(Error, Debug)]
+pub enum IntegrityError {
+    #[error("Received {actual} bytes, expected {expected}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error("Received content doesn't match its hash")]
+    HashMismatch,
+    #[error("No hash to verify the received content against")]
+    MissingHash,
+}
+
+/// Hashes everything written through it, so a transfer can be checked without reading it back.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+    written: u64,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+            written: 0,
+        }
+    }
+
+    /// Picks up a transfer whose first `offset` bytes are already in the file at `path`.
+    pub async fn resume(inner: W, path: &Path, offset: u64) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            hasher: file_manager::prefix_hasher(path, offset).await?,
+            written: offset,
+        })
+    }
+
+    /// Checks the content written so far is the `size` bytes hashing to `hash`.
+    pub fn verify(&self, size: u64, hash: Option<&[u8]>) -> Result<(), IntegrityError> {
+        if self.written != size {
+            return Err(IntegrityError::SizeMismatch {
+                expected: size,
+                actual: self.written,
+            });
+        }
+
+        let hash = hash.ok_or(IntegrityError::MissingHash)?;
+        if self.hasher.finalize().as_bytes().as_slice() != hash {
+            return Err(IntegrityError::HashMismatch);
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
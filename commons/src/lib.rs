@@ -1,9 +1,17 @@
 use lazy_static::lazy_static;
 use snow::params::NoiseParams;
 
+pub mod attributes;
+pub mod chunker;
+pub mod compression;
+pub mod delta;
+pub mod envelope;
 pub mod file_manager;
+pub mod integrity;
 pub mod keys_manager;
+pub mod mux;
 pub mod packeter;
+pub mod session;
 
 lazy_static! {
     pub static ref NOISE_PARAMS: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
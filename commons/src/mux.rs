@@ -0,0 +1,790 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot, Notify, Semaphore},
+    task::AbortHandle,
+};
+
+use crate::packeter::{self, ReadPacketError, WritePacketError};
+
+/// Bytes a stream may have in flight before its reader hands out more credit.
+pub const STREAM_WINDOW: u32 = 256 * 1024;
+/// Streams a peer may keep open at the same time.
+pub const MAX_STREAMS: usize = 64;
+/// Age past which a session is ended once idle, unless configured otherwise.
+pub const DEFAULT_MAX_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long an expired session must go without streams or frames before it is ended, so we
+/// don't cut off a peer between two requests.
+const IDLE_GRACE: Duration = Duration::from_secs(5);
+/// How often the timers of a session are checked.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+const HEADER_LEN: usize = 5;
+/// Largest payload carried by a single frame, longer packets are split over several.
+pub const MAX_PAYLOAD: usize = packeter::MAX_PACKET_LEN - HEADER_LEN;
+
+const DATA: u8 = 0;
+const WINDOW_UPDATE: u8 = 1;
+const CLOSE: u8 = 2;
+/// Fragment of a packet that continues in the next data frame of the stream.
+const DATA_MORE: u8 = 3;
+const PING: u8 = 4;
+const PONG: u8 = 5;
+/// The sender ends the session, nothing follows it.
+const GO_AWAY: u8 = 6;
+/// Stream the frames about the session itself are sent on, neither side ever opens it.
+const SESSION_STREAM: u32 = 0;
+
+#[derive(Error, Debug)]
+pub enum MuxError {
+    #[error("Failed to read frame: {0}")]
+    ReadError(#[from] ReadPacketError),
+    #[error("Multiplexing protocol violation: {0}")]
+    Protocol(&'static str),
+    #[error("Session closed")]
+    Closed,
+    #[error("Peer doesn't answer pings")]
+    PingUnsupported,
+}
+
+/// When a session pings its peer and gives up on it, off until it is set.
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    /// Silence from the peer after which it is pinged.
+    pub interval: Duration,
+    /// Silence from the peer after which the connection is considered dead.
+    pub read_timeout: Duration,
+    /// Time without any stream after which the session is ended, `None` keeps it open.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(90),
+            idle_timeout: None,
+        }
+    }
+}
+
+/// Why a session ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Ending {
+    /// We closed it.
+    Closed,
+    /// The peer said it was done with it.
+    PeerClosed,
+    /// No stream was open for the idle timeout.
+    Idle,
+    /// It outlived its maximum lifetime.
+    Expired,
+    /// The peer was silent for the read timeout.
+    TimedOut,
+    /// The connection broke or the peer didn't follow the protocol.
+    Lost,
+}
+
+impl fmt::Display for Ending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Ending::Closed => "closed",
+            Ending::PeerClosed => "closed by the peer",
+            Ending::Idle => "idle for too long",
+            Ending::Expired => "past its maximum lifetime",
+            Ending::TimedOut => "peer stopped responding",
+            Ending::Lost => "connection lost",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Which end of the session we are, streams opened by each side use distinct ids.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Initiator,
+    Responder,
+}
+
+impl Side {
+    fn first_stream_id(&self) -> u32 {
+        match self {
+            Side::Initiator => 1,
+            Side::Responder => 2,
+        }
+    }
+
+    fn owns(&self, stream_id: u32) -> bool {
+        stream_id % 2 == self.first_stream_id() % 2
+    }
+}
+
+fn frame(stream_id: u32, kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Payload of a data frame, and whether it is the last fragment of its packet.
+type Fragment = (Vec<u8>, bool);
+
+struct StreamEntry {
+    incoming: Option<mpsc::UnboundedSender<Fragment>>,
+    credit: Arc<Semaphore>,
+    buffered: Arc<AtomicU32>,
+    announced: bool,
+    local_closed: bool,
+    remote_closed: bool,
+}
+
+impl StreamEntry {
+    fn new(incoming: mpsc::UnboundedSender<Fragment>, announced: bool) -> Self {
+        Self {
+            incoming: Some(incoming),
+            credit: Arc::new(Semaphore::new(STREAM_WINDOW as usize)),
+            buffered: Arc::new(AtomicU32::new(0)),
+            announced,
+            local_closed: false,
+            remote_closed: false,
+        }
+    }
+}
+
+/// Frames waiting for the writer, control frames first and then one frame per stream in turn.
+#[derive(Default)]
+struct Outbox {
+    control: VecDeque<Vec<u8>>,
+    queues: HashMap<u32, VecDeque<Vec<u8>>>,
+    ready: VecDeque<u32>,
+    /// Go-away frame, sent once every other frame is out.
+    farewell: Option<Vec<u8>>,
+}
+
+impl Outbox {
+    fn push(&mut self, stream_id: u32, frame: Vec<u8>) {
+        let queue = self.queues.entry(stream_id).or_default();
+        if queue.is_empty() {
+            self.ready.push_back(stream_id);
+        }
+        queue.push_back(frame);
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+
+        let Some(stream_id) = self.ready.pop_front() else {
+            return self.farewell.take();
+        };
+        let queue = self.queues.get_mut(&stream_id)?;
+        let frame = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&stream_id);
+        } else {
+            self.ready.push_back(stream_id);
+        }
+
+        frame
+    }
+}
+
+struct State {
+    streams: HashMap<u32, StreamEntry>,
+    next_stream_id: u32,
+    outbox: Outbox,
+    closed: bool,
+    ending: Option<Ending>,
+    /// Last frame of any kind from the peer.
+    last_heard: Instant,
+    /// Last stream frame from the peer, pings don't keep a session busy.
+    last_active: Instant,
+    last_ping: Instant,
+    next_ping_id: u64,
+    /// Pings waiting for their pong, by id.
+    pings: HashMap<u64, oneshot::Sender<()>>,
+    keepalive: Option<Keepalive>,
+    /// Whether the peer answers pings and understands `GO_AWAY`.
+    pinging: bool,
+    expires: Option<Instant>,
+}
+
+impl State {
+    fn is_idle_for(&self, duration: Duration) -> bool {
+        self.streams.is_empty() && self.last_active.elapsed() >= duration
+    }
+
+    /// Whether the session has run out of time, pinging the peer if it has been quiet.
+    fn check_timers(&mut self) -> Option<Ending> {
+        if let Some(keepalive) = self.keepalive {
+            if self.last_heard.elapsed() >= keepalive.read_timeout {
+                return Some(Ending::TimedOut);
+            }
+
+            if keepalive
+                .idle_timeout
+                .is_some_and(|timeout| self.is_idle_for(timeout))
+            {
+                return Some(Ending::Idle);
+            }
+
+            if self.pinging
+                && self.last_heard.elapsed() >= keepalive.interval
+                && self.last_ping.elapsed() >= keepalive.interval
+            {
+                // Nobody waits for its pong, any frame back tells the peer is alive
+                let id = self.next_ping_id;
+                self.next_ping_id += 1;
+                self.outbox
+                    .control
+                    .push_back(frame(SESSION_STREAM, PING, &id.to_be_bytes()));
+                self.last_ping = Instant::now();
+            }
+        }
+
+        // An expired session waits for a quiet moment, so we don't cut off a peer between
+        // two requests
+        let expired = self
+            .expires
+            .is_some_and(|expires| Instant::now() >= expires);
+        if expired && self.is_idle_for(IDLE_GRACE) {
+            return Some(Ending::Expired);
+        }
+
+        None
+    }
+}
+
+struct Shared {
+    side: Side,
+    max_message_len: usize,
+    state: Mutex<State>,
+    writable: Notify,
+    accepted: tokio::sync::Mutex<mpsc::UnboundedReceiver<Stream>>,
+    reader: Mutex<Option<AbortHandle>>,
+    writer: Mutex<Option<AbortHandle>>,
+}
+
+impl Shared {
+    fn new_stream(self: &Arc<Self>, state: &mut State, stream_id: u32, announced: bool) -> Stream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let entry = StreamEntry::new(tx, announced);
+        let stream = Stream {
+            id: stream_id,
+            incoming: rx,
+            credit: entry.credit.clone(),
+            buffered: entry.buffered.clone(),
+            consumed: 0,
+            message: Vec::new(),
+            shared: self.clone(),
+        };
+        state.streams.insert(stream_id, entry);
+
+        stream
+    }
+
+    fn enqueue(&self, stream_id: u32, kind: u8, payload: &[u8]) -> Result<(), WritePacketError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(WritePacketError::Closed);
+        }
+
+        if let Some(entry) = state.streams.get_mut(&stream_id) {
+            entry.announced = true;
+        }
+        state
+            .outbox
+            .push(stream_id, frame(stream_id, kind, payload));
+        drop(state);
+
+        self.writable.notify_one();
+        Ok(())
+    }
+
+    fn send_window_update(&self, stream_id: u32, credit: u32) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        let update = frame(stream_id, WINDOW_UPDATE, &credit.to_be_bytes());
+        state.outbox.control.push_back(update);
+        drop(state);
+
+        self.writable.notify_one();
+    }
+
+    fn close_stream(&self, stream_id: u32) {
+        let mut state = self.state.lock().unwrap();
+        let entry = match state.streams.get_mut(&stream_id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        // The peer never heard of a stream we didn't write to
+        if !entry.announced {
+            state.streams.remove(&stream_id);
+            return;
+        }
+
+        entry.incoming = None;
+        entry.local_closed = true;
+        if entry.remote_closed {
+            state.streams.remove(&stream_id);
+        }
+
+        if !state.closed {
+            // Queued behind the stream's data so the peer reads everything before the close
+            state.outbox.push(stream_id, frame(stream_id, CLOSE, &[]));
+            drop(state);
+            self.writable.notify_one();
+        }
+    }
+
+    fn dispatch(
+        self: &Arc<Self>,
+        packet: Vec<u8>,
+        accept: &mpsc::UnboundedSender<Stream>,
+    ) -> Result<(), MuxError> {
+        if packet.len() < HEADER_LEN {
+            return Err(MuxError::Protocol("Frame is shorter than its header"));
+        }
+        let stream_id = u32::from_be_bytes(packet[..4].try_into().unwrap());
+        let kind = packet[4];
+        let payload = &packet[HEADER_LEN..];
+
+        let mut state = self.state.lock().unwrap();
+        state.last_heard = Instant::now();
+        if stream_id == SESSION_STREAM {
+            return self.dispatch_session(state, kind, payload);
+        }
+
+        state.last_active = Instant::now();
+        if !state.streams.contains_key(&stream_id) {
+            // Entries live until both sides closed, so anything else is a leftover
+            if !matches!(kind, DATA | DATA_MORE) || self.side.owns(stream_id) {
+                return Ok(());
+            }
+
+            let open_streams = state
+                .streams
+                .keys()
+                .filter(|id| !self.side.owns(**id))
+                .count();
+            if open_streams >= MAX_STREAMS {
+                return Err(MuxError::Protocol("Too many open streams"));
+            }
+
+            let stream = self.new_stream(&mut state, stream_id, true);
+            if let Err(rejected) = accept.send(stream) {
+                // Dropping the stream closes it, which needs the lock
+                drop(state);
+                drop(rejected);
+                return Ok(());
+            }
+        }
+
+        let entry = state.streams.get_mut(&stream_id).unwrap();
+        match kind {
+            DATA | DATA_MORE => {
+                let buffered = entry
+                    .buffered
+                    .fetch_add(payload.len() as u32, Ordering::AcqRel)
+                    + payload.len() as u32;
+                if buffered > STREAM_WINDOW {
+                    return Err(MuxError::Protocol("Stream window exceeded"));
+                }
+
+                if let Some(incoming) = &entry.incoming {
+                    let _ = incoming.send((payload.to_vec(), kind == DATA));
+                }
+            }
+            WINDOW_UPDATE => {
+                let credit: [u8; 4] = payload
+                    .try_into()
+                    .map_err(|_| MuxError::Protocol("Malformed window update"))?;
+                let credit = u32::from_be_bytes(credit) as usize;
+                if entry.credit.available_permits() + credit > STREAM_WINDOW as usize {
+                    return Err(MuxError::Protocol("Stream window overflow"));
+                }
+
+                entry.credit.add_permits(credit);
+            }
+            CLOSE => {
+                entry.incoming = None;
+                entry.credit.close();
+                entry.remote_closed = true;
+                if entry.local_closed {
+                    state.streams.remove(&stream_id);
+                }
+            }
+            _ => return Err(MuxError::Protocol("Unknown frame kind")),
+        }
+
+        Ok(())
+    }
+
+    fn dispatch_session(
+        &self,
+        mut state: MutexGuard<State>,
+        kind: u8,
+        payload: &[u8],
+    ) -> Result<(), MuxError> {
+        match kind {
+            PING => {
+                if !state.closed {
+                    let pong = frame(SESSION_STREAM, PONG, payload);
+                    state.outbox.control.push_back(pong);
+                    drop(state);
+                    self.writable.notify_one();
+                }
+            }
+            PONG => {
+                let id: [u8; 8] = payload
+                    .try_into()
+                    .map_err(|_| MuxError::Protocol("Malformed pong"))?;
+                if let Some(waiter) = state.pings.remove(&u64::from_be_bytes(id)) {
+                    let _ = waiter.send(());
+                }
+            }
+            GO_AWAY => {
+                drop(state);
+                self.shutdown(Ending::PeerClosed);
+            }
+            _ => return Err(MuxError::Protocol("Unknown session frame kind")),
+        }
+
+        Ok(())
+    }
+
+    /// Ends every stream, pending reads drain what was already received. The peer is told
+    /// when it understands it and is still there to hear it.
+    fn shutdown(&self, ending: Ending) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        state.closed = true;
+        state.ending = Some(ending);
+        for entry in state.streams.values_mut() {
+            entry.incoming = None;
+            entry.credit.close();
+        }
+        state.pings.clear();
+
+        let peer_listening = matches!(ending, Ending::Closed | Ending::Idle | Ending::Expired);
+        if peer_listening && state.pinging {
+            state.outbox.farewell = Some(frame(SESSION_STREAM, GO_AWAY, &[]));
+        }
+        drop(state);
+
+        // Nothing read from now on matters, and the accept loop waits for the reader to end
+        if let Some(reader) = self.reader.lock().unwrap().take() {
+            reader.abort();
+        }
+        // The writer flushes what is queued, unless nobody is left to read it
+        if !peer_listening {
+            if let Some(writer) = self.writer.lock().unwrap().take() {
+                writer.abort();
+            }
+        }
+
+        self.writable.notify_one();
+    }
+}
+
+/// Checks the timers of a session until it ends.
+async fn watch(shared: Weak<Shared>) {
+    let mut ticks = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+
+        let mut state = shared.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        let queued = state.outbox.control.len();
+        let ending = state.check_timers();
+        let pinged = state.outbox.control.len() > queued;
+        drop(state);
+
+        if let Some(ending) = ending {
+            shared.shutdown(ending);
+            return;
+        }
+        if pinged {
+            shared.writable.notify_one();
+        }
+    }
+}
+
+async fn read_loop<T: AsyncRead>(
+    shared: &Arc<Shared>,
+    mut reader: packeter::ReadHalf<T>,
+    accept: mpsc::UnboundedSender<Stream>,
+) -> Result<(), MuxError> {
+    loop {
+        let packet = reader.read_packet().await?;
+        shared.dispatch(packet, &accept)?;
+    }
+}
+
+async fn write_loop<T: AsyncWrite>(
+    shared: &Shared,
+    mut writer: packeter::WriteHalf<T>,
+) -> Result<(), WritePacketError> {
+    loop {
+        let (frame, closed) = {
+            let mut state = shared.state.lock().unwrap();
+            (state.outbox.next_frame(), state.closed)
+        };
+
+        match frame {
+            Some(frame) => writer.write_packet(&frame).await?,
+            None if closed => return writer.shutdown().await,
+            None => shared.writable.notified().await,
+        }
+    }
+}
+
+/// Carries independent streams of packets over a single session, so a large transfer
+/// doesn't hold up every other request.
+#[derive(Clone)]
+pub struct Multiplexer {
+    shared: Arc<Shared>,
+}
+
+impl Multiplexer {
+    pub fn new<T>(handler: packeter::Handler<T>, side: Side) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let max_message_len = handler.max_message_len();
+        let (reader, writer) = handler.into_split();
+        let (accept_tx, accept_rx) = mpsc::unbounded_channel();
+
+        let shared = Arc::new(Shared {
+            side,
+            max_message_len,
+            state: Mutex::new(State {
+                streams: HashMap::new(),
+                next_stream_id: side.first_stream_id(),
+                outbox: Outbox::default(),
+                closed: false,
+                ending: None,
+                last_heard: Instant::now(),
+                last_active: Instant::now(),
+                last_ping: Instant::now(),
+                next_ping_id: 0,
+                pings: HashMap::new(),
+                keepalive: None,
+                pinging: false,
+                expires: None,
+            }),
+            writable: Notify::new(),
+            accepted: tokio::sync::Mutex::new(accept_rx),
+            reader: Mutex::new(None),
+            writer: Mutex::new(None),
+        });
+
+        let read_shared = shared.clone();
+        let reader = tokio::spawn(async move {
+            let _ = read_loop(&read_shared, reader, accept_tx).await;
+            read_shared.shutdown(Ending::Lost);
+        });
+        *shared.reader.lock().unwrap() = Some(reader.abort_handle());
+
+        let write_shared = shared.clone();
+        let writer = tokio::spawn(async move {
+            let _ = write_loop(&write_shared, writer).await;
+            write_shared.shutdown(Ending::Lost);
+        });
+        *shared.writer.lock().unwrap() = Some(writer.abort_handle());
+
+        tokio::spawn(watch(Arc::downgrade(&shared)));
+
+        Self { shared }
+    }
+
+    /// Ends the session once it is older than `max_lifetime` and idle, a busy one lives on.
+    pub fn with_max_lifetime(self, max_lifetime: Duration) -> Self {
+        self.shared.state.lock().unwrap().expires = Some(Instant::now() + max_lifetime);
+        self
+    }
+
+    /// Ends the session once the peer is silent for the read timeout or it sits unused,
+    /// without ever pinging the peer.
+    pub fn with_timeouts(self, keepalive: Keepalive) -> Self {
+        self.shared.state.lock().unwrap().keepalive = Some(keepalive);
+        self
+    }
+
+    /// Pings a quiet peer and ends the session once it stops answering or sits unused. Only
+    /// for peers that negotiated `Capability::Keepalive`.
+    pub fn with_keepalive(self, keepalive: Keepalive) -> Self {
+        let mut state = self.shared.state.lock().unwrap();
+        state.keepalive = Some(keepalive);
+        state.pinging = true;
+        drop(state);
+        self
+    }
+
+    pub fn open(&self) -> Result<Stream, WritePacketError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(WritePacketError::Closed);
+        }
+
+        let stream_id = state.next_stream_id;
+        state.next_stream_id = stream_id.checked_add(2).ok_or(WritePacketError::Closed)?;
+        let stream = self.shared.new_stream(&mut state, stream_id, false);
+
+        Ok(stream)
+    }
+
+    /// Waits for the peer to open a stream, `None` once the session is over.
+    pub async fn accept(&self) -> Option<Stream> {
+        self.shared.accepted.lock().await.recv().await
+    }
+
+    /// Flushes what is queued and closes the session.
+    pub fn close(&self) {
+        self.shared.shutdown(Ending::Closed);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    /// Why the session ended, `None` while it is open.
+    pub fn ending(&self) -> Option<Ending> {
+        self.shared.state.lock().unwrap().ending
+    }
+
+    /// Round trip time to the peer, once it answered a ping.
+    pub async fn ping(&self) -> Result<Duration, MuxError> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(MuxError::Closed);
+            }
+            if !state.pinging {
+                return Err(MuxError::PingUnsupported);
+            }
+
+            let id = state.next_ping_id;
+            state.next_ping_id += 1;
+            state.pings.insert(id, tx);
+            state
+                .outbox
+                .control
+                .push_back(frame(SESSION_STREAM, PING, &id.to_be_bytes()));
+        }
+        self.shared.writable.notify_one();
+
+        let start = Instant::now();
+        rx.await.map_err(|_| MuxError::Closed)?;
+
+        Ok(start.elapsed())
+    }
+}
+
+pub struct Stream {
+    id: u32,
+    incoming: mpsc::UnboundedReceiver<Fragment>,
+    credit: Arc<Semaphore>,
+    buffered: Arc<AtomicU32>,
+    consumed: u32,
+    /// Fragments of the packet being reassembled.
+    message: Vec<u8>,
+    shared: Arc<Shared>,
+}
+
+impl Stream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn release(&mut self, len: u32) {
+        self.buffered.fetch_sub(len, Ordering::AcqRel);
+
+        // Hand the credit back in batches rather than once per frame
+        self.consumed += len;
+        if self.consumed >= STREAM_WINDOW / 2 {
+            self.shared.send_window_update(self.id, self.consumed);
+            self.consumed = 0;
+        }
+    }
+
+    pub async fn read_packet(&mut self) -> Result<Vec<u8>, ReadPacketError> {
+        loop {
+            let (fragment, last) = self.incoming.recv().await.ok_or(ReadPacketError::EOF)?;
+            // Fragments leave the window as soon as they are buffered here, the cap below
+            // bounds what a packet can hold instead
+            self.release(fragment.len() as u32);
+
+            if self.message.len() + fragment.len() > self.shared.max_message_len {
+                return Err(ReadPacketError::TooLarge(self.shared.max_message_len));
+            }
+
+            if last && self.message.is_empty() {
+                return Ok(fragment);
+            }
+
+            self.message.extend_from_slice(&fragment);
+            if last {
+                return Ok(std::mem::take(&mut self.message));
+            }
+        }
+    }
+
+    pub async fn write_packet(&mut self, buf: &[u8]) -> Result<(), WritePacketError> {
+        if buf.len() > self.shared.max_message_len {
+            return Err(WritePacketError::TooLarge(buf.len()));
+        }
+
+        let mut offset = 0;
+        loop {
+            let end = (offset + MAX_PAYLOAD).min(buf.len());
+            let more = end < buf.len();
+
+            self.credit
+                .acquire_many((end - offset) as u32)
+                .await
+                .map_err(|_| WritePacketError::Closed)?
+                .forget();
+
+            let kind = if more { DATA_MORE } else { DATA };
+            self.shared.enqueue(self.id, kind, &buf[offset..end])?;
+
+            if !more {
+                return Ok(());
+            }
+            offset = end;
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.shared.close_stream(self.id);
+    }
+}
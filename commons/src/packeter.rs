@@ -1,9 +1,28 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use snow::TransportState;
 use thiserror::Error;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest Noise message, tag included.
+pub const MAX_NOISE_MESSAGE_LEN: usize = 65535;
+/// Length of the flags that start the plaintext of every fragment.
+const FLAGS_LEN: usize = 1;
+/// Largest payload that fits in a single Noise message along with its flags, longer packets
+/// are split.
+pub const MAX_PACKET_LEN: usize = MAX_NOISE_MESSAGE_LEN - 16 - FLAGS_LEN;
+/// Largest packet accepted unless the handler is configured otherwise.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+/// Time a peer gets to complete the handshake unless configured otherwise.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Set in the flags of every fragment of a packet but the last one. Flags are encrypted along
+/// with the fragment, so they can't be changed on the way unnoticed.
+const MORE_FRAGMENTS: u8 = 1 << 0;
+/// Set in the flags of a fragment without data after which the sender's key changes.
+const REKEY: u8 = 1 << 1;
 
 #[derive(Error, Debug)]
 pub enum ReadPacketError {
@@ -13,6 +32,14 @@ pub enum ReadPacketError {
     EOF,
     #[error("Noise protocol error: {0}")]
     NoiseError(#[from] snow::Error),
+    #[error("Fragment of {0} bytes exceeds the Noise message size")]
+    InvalidLength(usize),
+    #[error("Packet is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("Rekey notice carries data")]
+    InvalidRekey,
+    #[error("Fragment with invalid flags")]
+    InvalidFlags,
 }
 
 #[derive(Error, Debug)]
@@ -21,53 +48,330 @@ pub enum WritePacketError {
     WriteError(#[from] tokio::io::Error),
     #[error("Noise protocol error: {0}")]
     NoiseError(#[from] snow::Error),
+    #[error("Packet of {0} bytes is too large")]
+    TooLarge(usize),
+    #[error("Connection closed")]
+    Closed,
 }
 
-pub struct Handler {
-    buf_reader: BufReader<TcpStream>,
-    noise: TransportState,
+/// When a sender replaces its key, whichever limit is reached first. Each direction has its
+/// own key and is rekeyed by its sender alone, which tells the receiver in-band.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+    /// Noise messages sent under one key.
+    pub max_messages: u64,
+    /// Bytes encrypted under one key.
+    pub max_bytes: u64,
+    /// Time a key is used for, checked whenever a packet is written.
+    pub max_age: Duration,
 }
 
-impl Handler {
-    pub fn new(buf_reader: BufReader<TcpStream>, noise: TransportState) -> Self {
-        Self { buf_reader, noise }
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_messages: 1 << 20,
+            max_bytes: 1 << 30,
+            max_age: Duration::from_secs(60 * 60),
+        }
     }
+}
 
-    pub async fn read_packet(&mut self) -> Result<Vec<u8>, ReadPacketError> {
-        let mut read_buffer = vec![0u8; 65535];
-        let mut decrypt_buffer = vec![0u8; 65535];
-
-        let n = self.buf_reader.read_u32().await.map_err(|e| {
-            if e.kind() == tokio::io::ErrorKind::UnexpectedEof {
-                ReadPacketError::EOF
-            } else {
-                ReadPacketError::ReadError(e)
+/// Use of the outgoing key since it was last replaced.
+struct Rekeying {
+    policy: RekeyPolicy,
+    messages: u64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl Rekeying {
+    fn new(policy: RekeyPolicy) -> Self {
+        Self {
+            policy,
+            messages: 0,
+            bytes: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, len: usize) {
+        self.messages += 1;
+        self.bytes += len as u64;
+    }
+
+    fn due(&self) -> bool {
+        self.messages >= self.policy.max_messages
+            || self.bytes >= self.policy.max_bytes
+            || self.since.elapsed() >= self.policy.max_age
+    }
+}
+
+fn map_read_error(e: io::Error) -> ReadPacketError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        ReadPacketError::EOF
+    } else {
+        ReadPacketError::ReadError(e)
+    }
+}
+
+/// Reads one length-prefixed handshake message, refusing a length no Noise message can have
+/// before anything is allocated for it.
+pub async fn read_handshake_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<u8>, ReadPacketError> {
+    let n = reader.read_u32().await.map_err(map_read_error)? as usize;
+    if n > MAX_NOISE_MESSAGE_LEN {
+        return Err(ReadPacketError::InvalidLength(n));
+    }
+
+    let mut message = vec![0u8; n];
+    reader
+        .read_exact(&mut message)
+        .await
+        .map_err(map_read_error)?;
+
+    Ok(message)
+}
+
+/// Reads one encrypted fragment, its length prefix being nothing but its length.
+async fn read_fragment<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, ReadPacketError> {
+    let n = reader.read_u32().await.map_err(map_read_error)? as usize;
+    if n > MAX_NOISE_MESSAGE_LEN {
+        return Err(ReadPacketError::InvalidLength(n));
+    }
+
+    let mut fragment = vec![0u8; n];
+    reader
+        .read_exact(&mut fragment)
+        .await
+        .map_err(map_read_error)?;
+
+    Ok(fragment)
+}
+
+/// Splits the decrypted plaintext of a fragment into its flags and its data.
+fn split_flags(mut plaintext: Vec<u8>) -> Result<(u8, Vec<u8>), ReadPacketError> {
+    match plaintext.first() {
+        Some(&flags) if flags & !(MORE_FRAGMENTS | REKEY) == 0 => {
+            plaintext.drain(..FLAGS_LEN);
+            Ok((flags, plaintext))
+        }
+        _ => Err(ReadPacketError::InvalidFlags),
+    }
+}
+
+/// Reads and decrypts the fragments of one packet, refusing to buffer more than `max_len`.
+/// Rekey notices in between switch to the peer's next key.
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    noise: &Mutex<TransportState>,
+) -> Result<Vec<u8>, ReadPacketError> {
+    let mut message = Vec::new();
+    loop {
+        let fragment = read_fragment(reader).await?;
+        let plaintext = decrypt(&mut noise.lock().unwrap(), &fragment)?;
+        let (flags, fragment) = split_flags(plaintext)?;
+        if flags & REKEY != 0 {
+            if flags != REKEY || !fragment.is_empty() {
+                return Err(ReadPacketError::InvalidRekey);
             }
-        })?;
 
-        self.buf_reader
-            .read_exact(&mut read_buffer[..n as usize])
-            .await
-            .map_err(|e| {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    ReadPacketError::EOF
-                } else {
-                    ReadPacketError::ReadError(e)
-                }
-            })?;
-        let read = self
-            .noise
-            .read_message(&mut read_buffer[..n as usize], &mut decrypt_buffer)?;
+            noise.lock().unwrap().rekey_incoming();
+            continue;
+        }
+
+        let more = flags & MORE_FRAGMENTS != 0;
+        if message.len() + fragment.len() > max_len {
+            return Err(ReadPacketError::TooLarge(max_len));
+        }
 
-        Ok(decrypt_buffer[..read].to_vec())
+        if !more {
+            if message.is_empty() {
+                return Ok(fragment);
+            }
+
+            message.extend_from_slice(&fragment);
+            return Ok(message);
+        }
+
+        message.extend_from_slice(&fragment);
     }
+}
+
+fn decrypt(noise: &mut TransportState, message: &[u8]) -> Result<Vec<u8>, ReadPacketError> {
+    let mut decrypt_buffer = vec![0u8; MAX_NOISE_MESSAGE_LEN];
+    let read = noise.read_message(message, &mut decrypt_buffer)?;
+    decrypt_buffer.truncate(read);
+
+    Ok(decrypt_buffer)
+}
+
+fn encrypt(noise: &mut TransportState, buf: &[u8]) -> Result<Vec<u8>, WritePacketError> {
+    let mut encrypted_buffer = vec![0u8; MAX_NOISE_MESSAGE_LEN];
+    let len = noise.write_message(buf, &mut encrypted_buffer)?;
+    encrypted_buffer.truncate(len);
+
+    Ok(encrypted_buffer)
+}
 
+async fn write_fragment<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    fragment: &[u8],
+) -> Result<(), WritePacketError> {
+    // A single write, so Nagle's algorithm doesn't hold the fragment back behind its prefix
+    let mut message = Vec::with_capacity(4 + fragment.len());
+    message.extend_from_slice(&(fragment.len() as u32).to_be_bytes());
+    message.extend_from_slice(fragment);
+    writer.write_all(&message).await?;
+
+    Ok(())
+}
+
+/// Encrypts a packet into as many Noise messages as it takes, an empty one still takes one.
+/// The key is replaced after the packet once the rekey policy says so.
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buf: &[u8],
+    max_len: usize,
+    noise: &Mutex<TransportState>,
+    rekeying: &mut Rekeying,
+) -> Result<(), WritePacketError> {
+    if buf.len() > max_len {
+        return Err(WritePacketError::TooLarge(buf.len()));
+    }
+
+    let mut offset = 0;
+    loop {
+        let end = (offset + MAX_PACKET_LEN).min(buf.len());
+        let more = end < buf.len();
+        let mut plaintext = Vec::with_capacity(FLAGS_LEN + end - offset);
+        plaintext.push(if more { MORE_FRAGMENTS } else { 0 });
+        plaintext.extend_from_slice(&buf[offset..end]);
+        let fragment = encrypt(&mut noise.lock().unwrap(), &plaintext)?;
+        write_fragment(writer, &fragment).await?;
+        rekeying.record(end - offset);
+
+        if !more {
+            break;
+        }
+        offset = end;
+    }
+
+    if rekeying.due() {
+        // The notice goes out under the old key, the peer switches once it decrypted it
+        let notice = encrypt(&mut noise.lock().unwrap(), &[REKEY])?;
+        write_fragment(writer, &notice).await?;
+        noise.lock().unwrap().rekey_outgoing();
+        *rekeying = Rekeying::new(rekeying.policy);
+    }
+
+    Ok(())
+}
+
+/// Encrypted session over any byte stream, a TCP connection, a pipe or an in-memory duplex.
+pub struct Handler<T> {
+    stream: T,
+    noise: Mutex<TransportState>,
+    rekeying: Rekeying,
+    max_message_len: usize,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Handler<T> {
+    pub fn new(stream: T, noise: TransportState) -> Self {
+        Self {
+            stream,
+            noise: Mutex::new(noise),
+            rekeying: Rekeying::new(RekeyPolicy::default()),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+
+    /// Caps the size of the packets read and written, bounding what a peer can make us buffer.
+    pub fn with_max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+
+    /// Sets when our outgoing key is replaced, the peer's follows its own policy.
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Self {
+        self.rekeying.policy = policy;
+        self
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    pub async fn read_packet(&mut self) -> Result<Vec<u8>, ReadPacketError> {
+        read_message(&mut self.stream, self.max_message_len, &self.noise).await
+    }
+
+    pub async fn write_packet(&mut self, buf: &[u8]) -> Result<(), WritePacketError> {
+        write_message(
+            &mut self.stream,
+            buf,
+            self.max_message_len,
+            &self.noise,
+            &mut self.rekeying,
+        )
+        .await
+    }
+
+    /// Splits the session so one task can read while another writes.
+    pub fn into_split(self) -> (ReadHalf<T>, WriteHalf<T>) {
+        let (reader, writer) = io::split(self.stream);
+        let noise = Arc::new(self.noise);
+
+        (
+            ReadHalf {
+                reader,
+                noise: noise.clone(),
+                max_message_len: self.max_message_len,
+            },
+            WriteHalf {
+                writer,
+                noise,
+                rekeying: self.rekeying,
+                max_message_len: self.max_message_len,
+            },
+        )
+    }
+}
+
+pub struct ReadHalf<T> {
+    reader: io::ReadHalf<T>,
+    noise: Arc<Mutex<TransportState>>,
+    max_message_len: usize,
+}
+
+impl<T: AsyncRead> ReadHalf<T> {
+    pub async fn read_packet(&mut self) -> Result<Vec<u8>, ReadPacketError> {
+        read_message(&mut self.reader, self.max_message_len, &self.noise).await
+    }
+}
+
+pub struct WriteHalf<T> {
+    writer: io::WriteHalf<T>,
+    noise: Arc<Mutex<TransportState>>,
+    rekeying: Rekeying,
+    max_message_len: usize,
+}
+
+impl<T: AsyncWrite> WriteHalf<T> {
     pub async fn write_packet(&mut self, buf: &[u8]) -> Result<(), WritePacketError> {
-        let mut encrypted_buffer = vec![0u8; 65535];
-        let len = self.noise.write_message(&buf, &mut encrypted_buffer)?;
+        write_message(
+            &mut self.writer,
+            buf,
+            self.max_message_len,
+            &self.noise,
+            &mut self.rekeying,
+        )
+        .await
+    }
 
-        self.buf_reader.write_u32(len as u32).await?;
-        self.buf_reader.write_all(&encrypted_buffer[..len]).await?;
+    pub async fn shutdown(&mut self) -> Result<(), WritePacketError> {
+        self.writer.shutdown().await?;
 
         Ok(())
     }
@@ -0,0 +1,148 @@
+use std::{collections::HashSet, time::Duration};
+
+use protos::Capability;
+use serde::Deserialize;
+
+use crate::{
+    mux::{self, Keepalive},
+    packeter::{RekeyPolicy, DEFAULT_HANDSHAKE_TIMEOUT},
+};
+
+/// Wire protocol version spoken by this build. Bump it on any incompatible change to `protos`.
+pub const PROTOCOL_VERSION: u32 = 5;
+/// Oldest peer protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 5;
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub fn is_compatible(protocol_version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    capabilities: HashSet<Capability>,
+}
+
+impl Capabilities {
+    /// Every optional feature implemented by this build.
+    pub fn supported() -> Self {
+        Self {
+            capabilities: HashSet::from([
+                Capability::Delta,
+                Capability::Resume,
+                Capability::CompressionZstd,
+                Capability::CompressionLz4,
+                Capability::Links,
+                Capability::Stat,
+                Capability::Listing,
+                Capability::Dedup,
+                Capability::Chunks,
+                Capability::Keepalive,
+                Capability::AtomicMove,
+                Capability::Versions,
+                Capability::Snapshots,
+                Capability::Trash,
+            ]),
+        }
+    }
+
+    /// Builds a set from the raw enum values of a message, dropping the ones this build doesn't know.
+    pub fn from_wire(capabilities: &[i32]) -> Self {
+        Self {
+            capabilities: capabilities
+                .iter()
+                .filter_map(|capability| Capability::try_from(*capability).ok())
+                .collect(),
+        }
+    }
+
+    pub fn to_wire(&self) -> Vec<i32> {
+        let mut capabilities: Vec<i32> = self
+            .capabilities
+            .iter()
+            .map(|capability| *capability as i32)
+            .collect();
+        capabilities.sort();
+        capabilities
+    }
+
+    pub fn intersection(&self, other: &Capabilities) -> Self {
+        Self {
+            capabilities: self
+                .capabilities
+                .intersection(&other.capabilities)
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// How long session keys and sessions are used before they are replaced, the same settings on
+/// both sides.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct SessionLimits {
+    /// Noise messages sent under one key.
+    pub rekey_after_messages: u64,
+    /// Bytes sent under one key.
+    pub rekey_after_bytes: u64,
+    /// Seconds a key is used for.
+    pub rekey_after_seconds: u64,
+    /// Seconds the handshake may take before the connection is dropped.
+    pub handshake_timeout_seconds: u64,
+    /// Seconds of silence from the peer after which it is pinged.
+    pub keepalive_seconds: u64,
+    /// Seconds of silence from the peer after which the connection is dropped.
+    pub read_timeout_seconds: u64,
+    /// Seconds without any request after which the session is ended, unset keeps it open.
+    pub idle_timeout_seconds: Option<u64>,
+    /// Seconds after which a session is ended, as soon as it is idle.
+    pub max_lifetime_seconds: u64,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        let policy = RekeyPolicy::default();
+        let keepalive = Keepalive::default();
+        Self {
+            rekey_after_messages: policy.max_messages,
+            rekey_after_bytes: policy.max_bytes,
+            rekey_after_seconds: policy.max_age.as_secs(),
+            handshake_timeout_seconds: DEFAULT_HANDSHAKE_TIMEOUT.as_secs(),
+            keepalive_seconds: keepalive.interval.as_secs(),
+            read_timeout_seconds: keepalive.read_timeout.as_secs(),
+            idle_timeout_seconds: Some(15 * 60),
+            max_lifetime_seconds: mux::DEFAULT_MAX_LIFETIME.as_secs(),
+        }
+    }
+}
+
+impl SessionLimits {
+    pub fn rekey_policy(&self) -> RekeyPolicy {
+        RekeyPolicy {
+            max_messages: self.rekey_after_messages,
+            max_bytes: self.rekey_after_bytes,
+            max_age: Duration::from_secs(self.rekey_after_seconds),
+        }
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_seconds)
+    }
+
+    pub fn keepalive(&self) -> Keepalive {
+        Keepalive {
+            interval: Duration::from_secs(self.keepalive_seconds),
+            read_timeout: Duration::from_secs(self.read_timeout_seconds),
+            idle_timeout: self.idle_timeout_seconds.map(Duration::from_secs),
+        }
+    }
+
+    pub fn max_lifetime(&self) -> Duration {
+        Duration::from_secs(self.max_lifetime_seconds)
+    }
+}
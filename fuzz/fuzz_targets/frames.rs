@@ -0,0 +1,85 @@
+#![no_main]
+
+//! Feeds arbitrary bytes to the packet reader of an authenticated session, either as they are or
+//! sealed under the peer's key with a valid length prefix, so that both the framing and the
+//! flags of decrypted fragments get arbitrary input.
+
+use std::sync::LazyLock;
+
+use commons::{
+    packeter::{self, Handler},
+    NOISE_PARAMS,
+};
+use libfuzzer_sys::fuzz_target;
+use snow::{Keypair, TransportState};
+use tokio::io::AsyncWriteExt;
+
+/// Keeps a single packet from allocating more than the fuzzer allows.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+static KEYS: LazyLock<(Keypair, Keypair)> = LazyLock::new(|| {
+    let builder = snow::Builder::new(NOISE_PARAMS.clone());
+    (
+        builder.generate_keypair().unwrap(),
+        builder.generate_keypair().unwrap(),
+    )
+});
+
+fn transports() -> (TransportState, TransportState) {
+    let (client, server) = &*KEYS;
+    let mut initiator = snow::Builder::new(NOISE_PARAMS.clone())
+        .local_private_key(&client.private)
+        .remote_public_key(&server.public)
+        .build_initiator()
+        .unwrap();
+    let mut responder = snow::Builder::new(NOISE_PARAMS.clone())
+        .local_private_key(&server.private)
+        .build_responder()
+        .unwrap();
+
+    let mut message = vec![0u8; packeter::MAX_NOISE_MESSAGE_LEN];
+    let mut payload = vec![0u8; packeter::MAX_NOISE_MESSAGE_LEN];
+    let n = initiator.write_message(&[], &mut message).unwrap();
+    responder.read_message(&message[..n], &mut payload).unwrap();
+    let n = responder.write_message(&[], &mut message).unwrap();
+    initiator.read_message(&message[..n], &mut payload).unwrap();
+
+    (
+        initiator.into_transport_mode().unwrap(),
+        responder.into_transport_mode().unwrap(),
+    )
+}
+
+fuzz_target!(|frames: Vec<(bool, Vec<u8>)>| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let (mut initiator, responder) = transports();
+        let (mut client, server) = tokio::io::duplex(packeter::MAX_NOISE_MESSAGE_LEN);
+
+        tokio::spawn(async move {
+            let mut message = vec![0u8; packeter::MAX_NOISE_MESSAGE_LEN];
+            for (sealed, bytes) in frames {
+                let written = if sealed {
+                    let Ok(n) = initiator.write_message(&bytes, &mut message) else {
+                        continue;
+                    };
+                    let mut frame = (n as u32).to_be_bytes().to_vec();
+                    frame.extend_from_slice(&message[..n]);
+                    client.write_all(&frame).await
+                } else {
+                    client.write_all(&bytes).await
+                };
+                if written.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut handler = Handler::new(server, responder).with_max_message_len(MAX_MESSAGE_LEN);
+        while handler.read_packet().await.is_ok() {}
+    });
+});
@@ -0,0 +1,37 @@
+#![no_main]
+
+//! Feeds the bytes a connecting peer sends before authenticating to the server side of the
+//! handshake.
+
+use std::sync::LazyLock;
+
+use commons::{packeter, NOISE_PARAMS};
+use libfuzzer_sys::fuzz_target;
+use snow::Keypair;
+
+static SERVER_KEYS: LazyLock<Keypair> = LazyLock::new(|| {
+    snow::Builder::new(NOISE_PARAMS.clone())
+        .generate_keypair()
+        .unwrap()
+});
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let mut responder = snow::Builder::new(NOISE_PARAMS.clone())
+            .local_private_key(&SERVER_KEYS.private)
+            .build_responder()
+            .unwrap();
+
+        let mut reader = data;
+        let Ok(message) = packeter::read_handshake_message(&mut reader).await else {
+            return;
+        };
+
+        let mut payload = vec![0u8; packeter::MAX_NOISE_MESSAGE_LEN];
+        let _ = responder.read_message(&message, &mut payload);
+    });
+});
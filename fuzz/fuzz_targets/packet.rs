@@ -0,0 +1,78 @@
+#![no_main]
+
+//! Sends arbitrary packets over an authenticated session and reads them back as mux frames
+//! and envelopes, the way a peer that completed the handshake could.
+
+use std::sync::LazyLock;
+
+use commons::{
+    envelope,
+    mux::{Multiplexer, Side},
+    packeter::{self, Handler},
+    NOISE_PARAMS,
+};
+use libfuzzer_sys::fuzz_target;
+use snow::{Keypair, TransportState};
+
+/// Keeps a single packet from allocating more than the fuzzer allows.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+static KEYS: LazyLock<(Keypair, Keypair)> = LazyLock::new(|| {
+    let builder = snow::Builder::new(NOISE_PARAMS.clone());
+    (
+        builder.generate_keypair().unwrap(),
+        builder.generate_keypair().unwrap(),
+    )
+});
+
+fn transports() -> (TransportState, TransportState) {
+    let (client, server) = &*KEYS;
+    let mut initiator = snow::Builder::new(NOISE_PARAMS.clone())
+        .local_private_key(&client.private)
+        .remote_public_key(&server.public)
+        .build_initiator()
+        .unwrap();
+    let mut responder = snow::Builder::new(NOISE_PARAMS.clone())
+        .local_private_key(&server.private)
+        .build_responder()
+        .unwrap();
+
+    let mut message = vec![0u8; packeter::MAX_NOISE_MESSAGE_LEN];
+    let mut payload = vec![0u8; packeter::MAX_NOISE_MESSAGE_LEN];
+    let n = initiator.write_message(&[], &mut message).unwrap();
+    responder.read_message(&message[..n], &mut payload).unwrap();
+    let n = responder.write_message(&[], &mut message).unwrap();
+    initiator.read_message(&message[..n], &mut payload).unwrap();
+
+    (
+        initiator.into_transport_mode().unwrap(),
+        responder.into_transport_mode().unwrap(),
+    )
+}
+
+fuzz_target!(|packets: Vec<Vec<u8>>| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let (initiator, responder) = transports();
+        let (client, server) = tokio::io::duplex(packeter::MAX_NOISE_MESSAGE_LEN);
+
+        tokio::spawn(async move {
+            let mut handler = Handler::new(client, initiator).with_max_message_len(MAX_MESSAGE_LEN);
+            for packet in packets {
+                if handler.write_packet(&packet).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let handler = Handler::new(server, responder).with_max_message_len(MAX_MESSAGE_LEN);
+        let mux = Multiplexer::new(handler, Side::Responder);
+        while let Some(mut stream) = mux.accept().await {
+            while envelope::receive(&mut stream).await.is_ok() {}
+        }
+    });
+});
@@ -6,6 +6,8 @@ fn main() {
             "src/protos/file.proto",
             "src/protos/requests.proto",
             "src/protos/responses.proto",
+            "src/protos/session.proto",
+            "src/protos/envelopes.proto",
         ],
         &["src/protos"],
     )
@@ -7,13 +7,33 @@ mod file {
 mod responses {
     include!(concat!(env!("OUT_DIR"), "/responses.rs"));
 }
+mod session {
+    include!(concat!(env!("OUT_DIR"), "/session.rs"));
+}
+mod envelopes {
+    include!(concat!(env!("OUT_DIR"), "/envelopes.rs"));
+}
 
-pub use file::{File, FileGet, FileSync, FileType};
+pub use envelopes::{envelope::Body, EndOfFile, Envelope};
+pub use file::{
+    delta_operation, BlockSignature, Chunk, ChunkList, Compression, ConflictPolicy, DeltaOperation,
+    ExtendedAttribute, File, FileGet, FileMove, FileStat, FileSync, FileType, Manifest,
+    PartialTransfer, Signature, SignatureBlocks,
+};
 use prost::{DecodeError, Message};
 pub use requests::{
-    Request, RequestAdd, RequestGet, RequestMove, RequestRemove, RequestSync, RequestType,
+    RequestAdd, RequestChunks, RequestEmptyTrash, RequestGet, RequestList, RequestMove,
+    RequestOffer, RequestRemove, RequestRestoreTrash, RequestResume, RequestSnapshot,
+    RequestSnapshots, RequestStat, RequestSync, RequestTrash, RequestVersions,
+};
+pub use responses::{
+    ErrorCode, MoveResult, MoveStatus, ResponseAck, ResponseChunks, ResponseError, ResponseGet,
+    ResponseList, ResponseMove, ResponseOffer, ResponseRestoreTrash, ResponseResume,
+    ResponseSignature, ResponseSnapshot, ResponseSnapshots, ResponseStat, ResponseSync,
+    ResponseTrash, ResponseVersions, Snapshot, StatEntry, TrashEntry, Version,
 };
-pub use responses::{ResponseGet, ResponseSync, ResponseType};
+pub use session::{Capability, Hello, HelloAck};
+use std::time::Duration;
 
 // File Functions
 pub fn create_file(
@@ -39,6 +59,10 @@ pub fn create_file(
     file
 }
 
+pub fn create_extended_attribute(name: String, value: Vec<u8>) -> file::ExtendedAttribute {
+    file::ExtendedAttribute { name, value }
+}
+
 pub fn create_file_move(old_path: String, new_path: String) -> file::FileMove {
     let mut file = file::FileMove::default();
     file.old_path = old_path;
@@ -64,133 +88,509 @@ pub fn create_file_sync(path: String) -> file::FileSync {
     file
 }
 
-// Requests Functions
-pub fn deserialize_request(buf: &[u8]) -> Result<requests::Request, DecodeError> {
-    requests::Request::decode(buf)
+pub fn create_file_stat(path: String) -> file::FileStat {
+    file::FileStat { path }
 }
 
-pub fn create_request_add(file: file::File, data_len: u64) -> requests::RequestAdd {
-    let mut request = requests::RequestAdd::default();
-    request.request_type = RequestType::Add as i32;
-    request.file = Some(file);
-    request.payoad_size = data_len;
-    request
+pub fn create_signature(block_size: u32, block_count: u64) -> file::Signature {
+    file::Signature {
+        block_size,
+        block_count,
+    }
 }
 
-pub fn serialize_request_add(request: requests::RequestAdd) -> Vec<u8> {
+pub fn create_partial_transfer(offset: u64, hash: Vec<u8>) -> file::PartialTransfer {
+    file::PartialTransfer { offset, hash }
+}
+
+pub fn serialize_partial_transfer(partial: file::PartialTransfer) -> Vec<u8> {
     let mut buf = Vec::new();
-    request.encode(&mut buf).unwrap();
+    partial.encode(&mut buf).unwrap();
     buf
 }
 
-pub fn deserialize_request_add(buf: &[u8]) -> Result<requests::RequestAdd, DecodeError> {
-    requests::RequestAdd::decode(buf)
+pub fn deserialize_partial_transfer(buf: &[u8]) -> Result<file::PartialTransfer, DecodeError> {
+    file::PartialTransfer::decode(buf)
 }
 
-pub fn create_request_move(files: Vec<file::FileMove>) -> requests::RequestMove {
-    let mut request = requests::RequestMove::default();
-    request.request_type = RequestType::Move as i32;
-    request.files = files;
-    request
+pub fn create_chunk(hash: Vec<u8>, size: u32) -> file::Chunk {
+    file::Chunk { hash, size }
+}
+
+pub fn create_chunk_list(chunks: Vec<file::Chunk>) -> file::ChunkList {
+    file::ChunkList { chunks }
+}
+
+pub fn create_manifest(chunks: Vec<file::Chunk>, size: u64, hash: Vec<u8>) -> file::Manifest {
+    file::Manifest { chunks, size, hash }
+}
+
+pub fn serialize_manifest(manifest: &file::Manifest) -> Vec<u8> {
+    manifest.encode_to_vec()
 }
 
-pub fn serialize_request_move(request: requests::RequestMove) -> Vec<u8> {
+pub fn deserialize_manifest(buf: &[u8]) -> Result<file::Manifest, DecodeError> {
+    file::Manifest::decode(buf)
+}
+
+pub fn create_signature_blocks(blocks: Vec<file::BlockSignature>) -> file::SignatureBlocks {
+    file::SignatureBlocks { blocks }
+}
+
+pub fn create_delta_literal(data: Vec<u8>) -> file::DeltaOperation {
+    file::DeltaOperation {
+        operation: Some(file::delta_operation::Operation::Literal(data)),
+    }
+}
+
+pub fn create_delta_copy(block_index: u64) -> file::DeltaOperation {
+    file::DeltaOperation {
+        operation: Some(file::delta_operation::Operation::Copy(block_index)),
+    }
+}
+
+// Session Functions
+pub fn create_hello(
+    protocol_version: u32,
+    software_version: String,
+    capabilities: Vec<i32>,
+) -> session::Hello {
+    session::Hello {
+        protocol_version,
+        software_version,
+        capabilities,
+    }
+}
+
+pub fn serialize_hello(hello: session::Hello) -> Vec<u8> {
     let mut buf = Vec::new();
-    request.encode(&mut buf).unwrap();
+    hello.encode(&mut buf).unwrap();
     buf
 }
 
-pub fn deserialize_request_move(buf: &[u8]) -> Result<requests::RequestMove, DecodeError> {
-    requests::RequestMove::decode(buf)
+pub fn deserialize_hello(buf: &[u8]) -> Result<session::Hello, DecodeError> {
+    session::Hello::decode(buf)
 }
 
-pub fn create_request_remove(files: Vec<file::FileRemove>) -> requests::RequestRemove {
-    let mut request = requests::RequestRemove::default();
-    request.request_type = RequestType::Remove as i32;
-    request.files = files;
-    request
+pub fn create_hello_ack(
+    protocol_version: u32,
+    software_version: String,
+    capabilities: Vec<i32>,
+) -> session::HelloAck {
+    session::HelloAck {
+        accepted: true,
+        protocol_version,
+        software_version,
+        capabilities,
+        reason: String::new(),
+    }
+}
+
+pub fn create_hello_reject(
+    protocol_version: u32,
+    software_version: String,
+    reason: String,
+) -> session::HelloAck {
+    session::HelloAck {
+        accepted: false,
+        protocol_version,
+        software_version,
+        capabilities: Vec::new(),
+        reason,
+    }
 }
 
-pub fn serialize_request_remove(request: requests::RequestRemove) -> Vec<u8> {
+pub fn serialize_hello_ack(hello_ack: session::HelloAck) -> Vec<u8> {
     let mut buf = Vec::new();
-    request.encode(&mut buf).unwrap();
+    hello_ack.encode(&mut buf).unwrap();
     buf
 }
 
-pub fn deserialize_request_remove(buf: &[u8]) -> Result<requests::RequestRemove, DecodeError> {
-    requests::RequestRemove::decode(buf)
+pub fn deserialize_hello_ack(buf: &[u8]) -> Result<session::HelloAck, DecodeError> {
+    session::HelloAck::decode(buf)
 }
 
-pub fn create_request_get(files: Vec<FileGet>) -> requests::RequestGet {
-    let mut request = requests::RequestGet::default();
-    request.request_type = RequestType::Get as i32;
+// Requests Functions
+pub fn create_request_add(
+    file: file::File,
+    data_len: u64,
+    delta: bool,
+    transfer_id: String,
+    offset: u64,
+    compression: file::Compression,
+    request_id: u64,
+) -> requests::RequestAdd {
+    let mut request = requests::RequestAdd::default();
+    request.request_id = request_id;
+    request.file = Some(file);
+    request.payoad_size = data_len;
+    request.delta = delta;
+    request.transfer_id = transfer_id;
+    request.offset = offset;
+    request.compression = compression as i32;
+    request
+}
+
+pub fn create_request_move(
+    files: Vec<file::FileMove>,
+    conflict: file::ConflictPolicy,
+    request_id: u64,
+) -> requests::RequestMove {
+    let mut request = requests::RequestMove::default();
+    request.request_id = request_id;
     request.files = files;
+    request.set_conflict(conflict);
     request
 }
 
-pub fn serialize_request_get(request: requests::RequestGet) -> Vec<u8> {
-    let mut buf = Vec::new();
-    request.encode(&mut buf).unwrap();
-    buf
+pub fn create_request_remove(
+    files: Vec<file::FileRemove>,
+    request_id: u64,
+) -> requests::RequestRemove {
+    let mut request = requests::RequestRemove::default();
+    request.request_id = request_id;
+    request.files = files;
+    request
 }
 
-pub fn deserialize_request_get(buf: &[u8]) -> Result<requests::RequestGet, DecodeError> {
-    requests::RequestGet::decode(buf)
+pub fn create_request_get(files: Vec<FileGet>, request_id: u64) -> requests::RequestGet {
+    let mut request = requests::RequestGet::default();
+    request.request_id = request_id;
+    request.files = files;
+    request
 }
 
-pub fn create_request_sync(file: file::FileSync) -> requests::RequestSync {
+pub fn create_request_sync(
+    file: file::FileSync,
+    signature: Option<file::Signature>,
+    partial: Option<file::PartialTransfer>,
+    compression: file::Compression,
+    version: Option<String>,
+    request_id: u64,
+) -> requests::RequestSync {
     let mut request = requests::RequestSync::default();
-    request.request_type = RequestType::Sync as i32;
+    request.request_id = request_id;
     request.file = Some(file);
+    request.signature = signature;
+    request.partial = partial;
+    request.compression = compression as i32;
+    request.version = version;
     request
 }
 
-pub fn serialize_request_sync(request: requests::RequestSync) -> Vec<u8> {
-    let mut buf = Vec::new();
-    request.encode(&mut buf).unwrap();
-    buf
+pub fn create_request_versions(path: String, request_id: u64) -> requests::RequestVersions {
+    requests::RequestVersions { path, request_id }
 }
 
-pub fn deserialize_request_sync(buf: &[u8]) -> Result<requests::RequestSync, DecodeError> {
-    requests::RequestSync::decode(buf)
+pub fn create_request_snapshot(request_id: u64) -> requests::RequestSnapshot {
+    requests::RequestSnapshot { request_id }
 }
 
-// Responses Functions
-pub fn deserialize_response(buf: &[u8]) -> Result<responses::Response, DecodeError> {
-    responses::Response::decode(buf)
+pub fn create_request_snapshots(request_id: u64) -> requests::RequestSnapshots {
+    requests::RequestSnapshots { request_id }
 }
 
-pub fn create_response_get(files: Vec<file::File>) -> responses::ResponseGet {
+pub fn create_request_trash(request_id: u64) -> requests::RequestTrash {
+    requests::RequestTrash { request_id }
+}
+
+pub fn create_request_restore_trash(
+    id: String,
+    path: Option<String>,
+    conflict: file::ConflictPolicy,
+    request_id: u64,
+) -> requests::RequestRestoreTrash {
+    requests::RequestRestoreTrash {
+        id,
+        path,
+        conflict: conflict as i32,
+        request_id,
+    }
+}
+
+pub fn create_request_empty_trash(
+    ids: Vec<String>,
+    request_id: u64,
+) -> requests::RequestEmptyTrash {
+    requests::RequestEmptyTrash { ids, request_id }
+}
+
+pub fn create_request_stat(
+    files: Vec<file::FileStat>,
+    hash: bool,
+    children: bool,
+    request_id: u64,
+) -> requests::RequestStat {
+    requests::RequestStat {
+        files,
+        hash,
+        children,
+        snapshot: None,
+        request_id,
+    }
+}
+
+/// Listing of the whole tree under `path` in one go, the other options are set on the result.
+pub fn create_request_list(path: String, request_id: u64) -> requests::RequestList {
+    requests::RequestList {
+        path,
+        request_id,
+        ..Default::default()
+    }
+}
+
+pub fn create_request_offer(file: file::File, request_id: u64) -> requests::RequestOffer {
+    requests::RequestOffer {
+        file: Some(file),
+        request_id,
+    }
+}
+
+pub fn create_request_chunks(
+    file: file::File,
+    chunk_count: u64,
+    compression: file::Compression,
+    request_id: u64,
+) -> requests::RequestChunks {
+    requests::RequestChunks {
+        file: Some(file),
+        chunk_count,
+        compression: compression as i32,
+        request_id,
+    }
+}
+
+pub fn create_request_resume(transfer_id: String, request_id: u64) -> requests::RequestResume {
+    requests::RequestResume {
+        transfer_id,
+        request_id,
+    }
+}
+
+// Responses Functions
+pub fn create_response_get(files: Vec<file::File>, request_id: u64) -> responses::ResponseGet {
     let mut response = responses::ResponseGet::default();
-    response.response_type = ResponseType::Get as i32;
+    response.request_id = request_id;
     response.files = files;
     response
 }
 
-pub fn serialize_response_get(response: responses::ResponseGet) -> Vec<u8> {
-    let mut buf = Vec::new();
-    response.encode(&mut buf).unwrap();
-    buf
+pub fn create_response_offer(present: bool, request_id: u64) -> responses::ResponseOffer {
+    responses::ResponseOffer {
+        present,
+        request_id,
+    }
+}
+
+pub fn create_response_chunks(missing: Vec<u64>, request_id: u64) -> responses::ResponseChunks {
+    responses::ResponseChunks {
+        missing,
+        request_id,
+    }
+}
+
+pub fn create_move_result(
+    old_path: String,
+    new_path: String,
+    status: responses::MoveStatus,
+    error: Option<(responses::ErrorCode, String)>,
+) -> responses::MoveResult {
+    let (error, message) = match error {
+        Some((code, message)) => (Some(code as i32), message),
+        None => (None, String::new()),
+    };
+
+    responses::MoveResult {
+        old_path,
+        new_path,
+        status: status as i32,
+        error,
+        message,
+    }
+}
+
+pub fn create_response_move(
+    results: Vec<responses::MoveResult>,
+    request_id: u64,
+) -> responses::ResponseMove {
+    responses::ResponseMove {
+        results,
+        request_id,
+    }
+}
+
+pub fn create_version(id: String, uploaded: Duration, file: file::File) -> responses::Version {
+    responses::Version {
+        id,
+        uploaded: uploaded.as_secs(),
+        uploaded_nanos: uploaded.subsec_nanos(),
+        file: Some(file),
+    }
 }
 
-pub fn deserialize_response_get(buf: &[u8]) -> Result<responses::ResponseGet, DecodeError> {
-    responses::ResponseGet::decode(buf)
+pub fn create_response_versions(
+    versions: Vec<responses::Version>,
+    request_id: u64,
+) -> responses::ResponseVersions {
+    responses::ResponseVersions {
+        versions,
+        request_id,
+    }
 }
 
-pub fn create_response_sync(file: file::File, data_len: u64) -> responses::ResponseSync {
+pub fn create_snapshot(id: String, created: Duration) -> responses::Snapshot {
+    responses::Snapshot {
+        id,
+        created: created.as_secs(),
+        created_nanos: created.subsec_nanos(),
+    }
+}
+
+pub fn create_response_snapshot(
+    snapshot: responses::Snapshot,
+    request_id: u64,
+) -> responses::ResponseSnapshot {
+    responses::ResponseSnapshot {
+        snapshot: Some(snapshot),
+        request_id,
+    }
+}
+
+pub fn create_response_snapshots(
+    snapshots: Vec<responses::Snapshot>,
+    request_id: u64,
+) -> responses::ResponseSnapshots {
+    responses::ResponseSnapshots {
+        snapshots,
+        request_id,
+    }
+}
+
+pub fn create_trash_entry(
+    id: String,
+    path: String,
+    deleted: Duration,
+    file: file::File,
+) -> responses::TrashEntry {
+    responses::TrashEntry {
+        id,
+        path,
+        deleted: deleted.as_secs(),
+        deleted_nanos: deleted.subsec_nanos(),
+        file: Some(file),
+    }
+}
+
+pub fn create_response_trash(
+    entries: Vec<responses::TrashEntry>,
+    request_id: u64,
+) -> responses::ResponseTrash {
+    responses::ResponseTrash {
+        entries,
+        request_id,
+    }
+}
+
+pub fn create_response_restore_trash(
+    path: String,
+    request_id: u64,
+) -> responses::ResponseRestoreTrash {
+    responses::ResponseRestoreTrash { path, request_id }
+}
+
+pub fn create_stat_entry(path: String, file: Option<file::File>) -> responses::StatEntry {
+    responses::StatEntry { path, file }
+}
+
+pub fn create_response_stat(
+    entries: Vec<responses::StatEntry>,
+    request_id: u64,
+) -> responses::ResponseStat {
+    responses::ResponseStat {
+        entries,
+        request_id,
+    }
+}
+
+pub fn create_response_list(
+    files: Vec<file::File>,
+    cursor: String,
+    last: bool,
+    more: bool,
+    request_id: u64,
+) -> responses::ResponseList {
+    responses::ResponseList {
+        files,
+        cursor,
+        last,
+        more,
+        request_id,
+    }
+}
+
+pub fn create_response_sync(
+    file: file::File,
+    data_len: u64,
+    delta: bool,
+    offset: u64,
+    compression: file::Compression,
+    request_id: u64,
+) -> responses::ResponseSync {
     let mut response = responses::ResponseSync::default();
-    response.response_type = ResponseType::Sync as i32;
+    response.request_id = request_id;
     response.file = Some(file);
     response.payload_size = data_len;
+    response.delta = delta;
+    response.offset = offset;
+    response.compression = compression as i32;
     response
 }
 
-pub fn serialize_response_sync(response: responses::ResponseSync) -> Vec<u8> {
+pub fn create_response_signature(
+    signature: file::Signature,
+    request_id: u64,
+) -> responses::ResponseSignature {
+    responses::ResponseSignature {
+        signature: Some(signature),
+        request_id,
+    }
+}
+
+pub fn create_response_error(
+    code: ErrorCode,
+    message: String,
+    request_id: u64,
+) -> responses::ResponseError {
+    responses::ResponseError {
+        code: code as i32,
+        message,
+        request_id,
+    }
+}
+
+pub fn create_response_ack(request_id: u64) -> responses::ResponseAck {
+    responses::ResponseAck { request_id }
+}
+
+pub fn create_response_resume(
+    partial: Option<file::PartialTransfer>,
+    request_id: u64,
+) -> responses::ResponseResume {
+    responses::ResponseResume {
+        partial,
+        request_id,
+    }
+}
+
+// Envelope Functions
+pub fn serialize_envelope(body: envelopes::envelope::Body) -> Vec<u8> {
     let mut buf = Vec::new();
-    response.encode(&mut buf).unwrap();
+    envelopes::Envelope { body: Some(body) }
+        .encode(&mut buf)
+        .unwrap();
     buf
 }
 
-pub fn deserialize_response_sync(buf: &[u8]) -> Result<responses::ResponseSync, DecodeError> {
-    responses::ResponseSync::decode(buf)
+pub fn deserialize_envelope(buf: &[u8]) -> Result<envelopes::Envelope, DecodeError> {
+    envelopes::Envelope::decode(buf)
 }
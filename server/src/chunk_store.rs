@@ -0,0 +1,318 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use commons::{file_manager, integrity::IntegrityError};
+use protos::{Chunk, Manifest};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncSeek, AsyncWriteExt, ReadBuf},
+};
+
+use crate::stored_content;
+
+/// Length of the blake3 hashes chunks and files are named by.
+pub const HASH_LEN: usize = 32;
+/// How often the chunks of a user are checked for ones no file refers to anymore.
+const COLLECTION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Chunks no file refers to are kept this long, an upload may be about to refer to them.
+const UNREFERENCED_CHUNK_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const LAST_COLLECTION_FILE: &str = ".last-collection";
+
+pub fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Where the content hashing to `hash` is kept under `dir`, `None` if it isn't a hash.
+pub fn hash_path(dir: &Path, hash: &[u8]) -> Option<PathBuf> {
+    if hash.len() != HASH_LEN {
+        return None;
+    }
+
+    let hex = hex(hash);
+    // Keeps directories to a few thousand entries
+    Some(dir.join(&hex[..2]).join(hex))
+}
+
+fn age(metadata: &std::fs::Metadata) -> Duration {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .unwrap_or_default()
+}
+
+/// Chunks of the files a user stored, each kept once under its hash.
+#[derive(Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub async fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self { dir })
+    }
+
+    fn chunk_path(&self, chunk: &Chunk) -> io::Result<PathBuf> {
+        hash_path(&self.dir, &chunk.hash).ok_or_else(|| io::ErrorKind::InvalidInput.into())
+    }
+
+    /// Whether the chunk is stored, keeping it from being collected for a while if it is.
+    pub async fn contains(&self, chunk: &Chunk) -> io::Result<bool> {
+        let path = self.chunk_path(chunk)?;
+        let metadata = match fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        if metadata.len() != chunk.size as u64 {
+            return Ok(false);
+        }
+
+        tokio::task::spawn_blocking(move || {
+            std::fs::File::open(path)?.set_modified(SystemTime::now())
+        })
+        .await??;
+
+        Ok(true)
+    }
+
+    /// Stores the content of a chunk, once it is checked to be that chunk.
+    pub async fn write(&self, chunk: &Chunk, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() != chunk.size as usize {
+            return Err(IntegrityError::SizeMismatch {
+                expected: chunk.size as u64,
+                actual: data.len() as u64,
+            }
+            .into());
+        }
+
+        if blake3::hash(data).as_bytes().as_slice() != chunk.hash {
+            return Err(IntegrityError::HashMismatch.into());
+        }
+
+        let path = self.chunk_path(chunk)?;
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        let (mut temp_file, temp_path) = file_manager::open_temporary_file(&path).await?;
+        let result = async {
+            temp_file.write_all(data).await?;
+            // Files only refer to chunks that made it to disk
+            temp_file.sync_all().await?;
+            fs::rename(&temp_path, &path).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        Ok(result?)
+    }
+
+    /// Reader of the content a manifest describes.
+    pub fn reader(&self, manifest: Manifest) -> ChunkReader {
+        ChunkReader::new(self.dir.clone(), manifest)
+    }
+
+    /// Whether every chunk of `manifest` is stored.
+    pub async fn contains_all(&self, manifest: &Manifest) -> io::Result<bool> {
+        for chunk in &manifest.chunks {
+            if !self.contains(chunk).await? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Drops the chunks none of the files under `roots` refers to, at most once every
+    /// `COLLECTION_INTERVAL`.
+    pub async fn collect_garbage(&self, roots: &[PathBuf]) -> anyhow::Result<()> {
+        let marker = self.dir.join(LAST_COLLECTION_FILE);
+        match fs::metadata(&marker).await {
+            Ok(metadata) if age(&metadata) < COLLECTION_INTERVAL => return Ok(()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        fs::write(&marker, []).await?;
+
+        let mut referenced = HashSet::new();
+        for root in roots {
+            referenced.extend(referenced_chunks(root).await?);
+        }
+
+        let mut removed = 0;
+        let mut prefixes = fs::read_dir(&self.dir).await?;
+        while let Some(prefix) = prefixes.next_entry().await? {
+            if !prefix.file_type().await?.is_dir() {
+                continue;
+            }
+
+            let mut entries = fs::read_dir(prefix.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                // Temporary files of interrupted writes are collected along with the chunks
+                let name = entry.file_name();
+                if referenced.contains(name.to_str().unwrap_or_default()) {
+                    continue;
+                }
+
+                if age(&entry.metadata().await?) > UNREFERENCED_CHUNK_LIFETIME {
+                    fs::remove_file(entry.path()).await?;
+                    removed += 1;
+                }
+            }
+        }
+
+        println!("Collected {} unused chunks", removed);
+
+        Ok(())
+    }
+}
+
+/// Names of the chunks the files under `root` are made of.
+async fn referenced_chunks(root: &Path) -> anyhow::Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+
+            if !file_type.is_file() {
+                continue;
+            }
+
+            if let Some(manifest) = stored_content::read_manifest(&entry.path()).await? {
+                referenced.extend(manifest.chunks.iter().map(|chunk| hex(&chunk.hash)));
+            }
+        }
+    }
+
+    Ok(referenced)
+}
+
+async fn load_chunk(path: PathBuf, chunk: Chunk) -> io::Result<Vec<u8>> {
+    let data = fs::read(&path).await?;
+    if data.len() != chunk.size as usize || blake3::hash(&data).as_bytes().as_slice() != chunk.hash
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Stored chunk {} is corrupted", path.display()),
+        ));
+    }
+
+    Ok(data)
+}
+
+type LoadChunk = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// Reads the content a manifest describes, with one of its chunks in memory at a time.
+pub struct ChunkReader {
+    dir: PathBuf,
+    manifest: Manifest,
+    /// Where each chunk starts in the content.
+    offsets: Vec<u64>,
+    position: u64,
+    loaded: Option<(usize, Vec<u8>)>,
+    loading: Option<(usize, LoadChunk)>,
+}
+
+impl ChunkReader {
+    fn new(dir: PathBuf, manifest: Manifest) -> Self {
+        let offsets = manifest
+            .chunks
+            .iter()
+            .scan(0, |offset, chunk| {
+                let start = *offset;
+                *offset += chunk.size as u64;
+                Some(start)
+            })
+            .collect();
+
+        Self {
+            dir,
+            manifest,
+            offsets,
+            position: 0,
+            loaded: None,
+            loading: None,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.manifest.size
+    }
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position >= this.manifest.size || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let index = this
+            .offsets
+            .partition_point(|offset| *offset <= this.position)
+            - 1;
+
+        if !matches!(&this.loaded, Some((loaded, _)) if *loaded == index) {
+            if !matches!(&this.loading, Some((loading, _)) if *loading == index) {
+                let chunk = this.manifest.chunks[index].clone();
+                let path = hash_path(&this.dir, &chunk.hash)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+                this.loading = Some((index, Box::pin(load_chunk(path, chunk))));
+            }
+
+            let (_, load) = this.loading.as_mut().unwrap();
+            let data = ready!(load.as_mut().poll(cx));
+            this.loading = None;
+            this.loaded = Some((index, data?));
+        }
+
+        let (_, data) = this.loaded.as_ref().unwrap();
+        let start = (this.position - this.offsets[index]) as usize;
+        let n = buf.remaining().min(data.len() - start);
+        buf.put_slice(&data[start..start + n]);
+        this.position += n as u64;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for ChunkReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.manifest.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+
+        self.position = position.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
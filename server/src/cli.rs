@@ -10,6 +10,11 @@ pub struct Args {
     /// Config file path
     #[arg(short, long, default_value = "config.toml")]
     pub config_path: String,
+
+    /// Serve a single session on stdin and stdout instead of listening, e.g. over
+    /// `ssh host zen-sync-server --stdio`
+    #[arg(long)]
+    pub stdio: bool,
 }
 
 pub fn get_args() -> Args {
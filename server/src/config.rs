@@ -1,5 +1,6 @@
+use commons::{packeter, session::SessionLimits};
 use serde::Deserialize;
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, time::Duration};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,6 +22,75 @@ pub struct ServerConfig {
     pub port: u16,
     pub folder: String,
     pub private_key: String,
+    /// Largest message a client may send, in bytes.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// Connections that may be authenticating at once, from all addresses.
+    #[serde(default = "default_max_unauthenticated")]
+    pub max_unauthenticated: usize,
+    /// Connections an address may have that are still authenticating.
+    #[serde(default = "default_max_unauthenticated_per_address")]
+    pub max_unauthenticated_per_address: usize,
+    /// Authenticated sessions that may be open at once, from all users.
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    /// Authenticated sessions a user may have open at once.
+    #[serde(default = "default_max_sessions_per_user")]
+    pub max_sessions_per_user: usize,
+    /// Earlier versions kept of each file when it is replaced, or removed without a trash. 0
+    /// keeps none.
+    #[serde(default = "default_max_versions_per_file")]
+    pub max_versions_per_file: usize,
+    /// Snapshots kept of the tree of each user, the oldest are dropped past it. 0 keeps none.
+    #[serde(default = "default_max_snapshots")]
+    pub max_snapshots: usize,
+    /// Whether a snapshot is taken when a session that changed the tree ends.
+    #[serde(default)]
+    pub snapshot_sessions: bool,
+    /// Days removed entries are kept in the trash before they are purged, 0 removes them right
+    /// away and keeps their files as versions instead.
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u64,
+    #[serde(default)]
+    pub session: SessionLimits,
+}
+
+fn default_max_message_size() -> usize {
+    packeter::DEFAULT_MAX_MESSAGE_LEN
+}
+
+fn default_max_unauthenticated() -> usize {
+    64
+}
+
+fn default_max_unauthenticated_per_address() -> usize {
+    4
+}
+
+fn default_max_sessions() -> usize {
+    256
+}
+
+fn default_max_sessions_per_user() -> usize {
+    16
+}
+
+fn default_max_versions_per_file() -> usize {
+    32
+}
+
+fn default_max_snapshots() -> usize {
+    64
+}
+
+fn default_trash_retention_days() -> u64 {
+    30
+}
+
+impl ServerConfig {
+    pub fn trash_retention(&self) -> Duration {
+        Duration::from_secs(self.trash_retention_days * 24 * 60 * 60)
+    }
 }
 
 #[derive(Deserialize, Clone)]
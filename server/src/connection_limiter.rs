@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps the connections that haven't authenticated yet, in all and per address, so one host
+/// can't take every handshake slot with handshakes it never finishes. Authenticated sessions
+/// don't count against either.
+#[derive(Clone)]
+pub struct PreAuthLimiter {
+    pending: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    handshakes: Arc<Semaphore>,
+    max_per_address: usize,
+}
+
+impl PreAuthLimiter {
+    pub fn new(max_per_address: usize, max_handshakes: usize) -> Self {
+        Self {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            handshakes: Arc::new(Semaphore::new(max_handshakes)),
+            max_per_address,
+        }
+    }
+
+    /// Counts a new connection from `address`, `None` if the address already has too many
+    /// pending or every handshake slot is taken. Never waits, so a full limiter can't stall
+    /// accepting connections.
+    pub fn acquire(&self, address: IpAddr) -> Option<PreAuthPermit> {
+        let mut pending = self.pending.lock().unwrap();
+        let count = pending.entry(address).or_default();
+        if *count >= self.max_per_address {
+            return None;
+        }
+        let Ok(slot) = self.handshakes.clone().try_acquire_owned() else {
+            if *count == 0 {
+                pending.remove(&address);
+            }
+            return None;
+        };
+        *count += 1;
+
+        Some(PreAuthPermit {
+            limiter: self.clone(),
+            address,
+            _slot: slot,
+        })
+    }
+}
+
+/// Held until the connection authenticated or ended.
+pub struct PreAuthPermit {
+    limiter: PreAuthLimiter,
+    address: IpAddr,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl Drop for PreAuthPermit {
+    fn drop(&mut self) {
+        let mut pending = self.limiter.pending.lock().unwrap();
+        if let Some(count) = pending.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&self.address);
+            }
+        }
+    }
+}
+
+/// Caps the authenticated sessions, in all and per user, so a peer can't tie up the server's
+/// buffers and tasks with sessions it opens without end.
+#[derive(Clone)]
+pub struct SessionLimiter {
+    open: Arc<Mutex<HashMap<String, usize>>>,
+    sessions: Arc<Semaphore>,
+    max_per_user: usize,
+}
+
+impl SessionLimiter {
+    pub fn new(max_per_user: usize, max_sessions: usize) -> Self {
+        Self {
+            open: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Semaphore::new(max_sessions)),
+            max_per_user,
+        }
+    }
+
+    /// Counts a new session of `username`, `None` if the user already has too many open or the
+    /// server has no room for another.
+    pub fn acquire(&self, username: &str) -> Option<SessionPermit> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(username.to_string()).or_default();
+        if *count >= self.max_per_user {
+            return None;
+        }
+        let Ok(slot) = self.sessions.clone().try_acquire_owned() else {
+            if *count == 0 {
+                open.remove(username);
+            }
+            return None;
+        };
+        *count += 1;
+
+        Some(SessionPermit {
+            limiter: self.clone(),
+            username: username.to_string(),
+            _slot: slot,
+        })
+    }
+}
+
+/// Held for as long as the session lasts.
+pub struct SessionPermit {
+    limiter: SessionLimiter,
+    username: String,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.username) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.username);
+            }
+        }
+    }
+}
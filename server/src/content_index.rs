@@ -0,0 +1,77 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use commons::file_manager;
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::chunk_store;
+
+/// Where a copy of every content a user stored can be found, by its hash.
+///
+/// Entries only record the last path a content was stored at, they aren't updated when that
+/// file is moved, replaced or removed, so whatever they point to must be checked before use.
+pub struct ContentIndex {
+    dir: PathBuf,
+}
+
+impl ContentIndex {
+    pub async fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self { dir })
+    }
+
+    fn entry_path(&self, hash: &[u8]) -> Option<PathBuf> {
+        chunk_store::hash_path(&self.dir, hash)
+    }
+
+    /// Path of the file the content hashing to `hash` was last stored at.
+    pub async fn lookup(&self, hash: &[u8]) -> io::Result<Option<PathBuf>> {
+        let Some(entry_path) = self.entry_path(hash) else {
+            return Ok(None);
+        };
+
+        match fs::read_to_string(&entry_path).await {
+            Ok(path) => Ok(Some(PathBuf::from(path))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records that the content hashing to `hash` is stored at `path`.
+    pub async fn record(&self, hash: &[u8], path: &Path) -> anyhow::Result<()> {
+        let Some(entry_path) = self.entry_path(hash) else {
+            return Ok(());
+        };
+
+        fs::create_dir_all(entry_path.parent().unwrap()).await?;
+        let (mut temp_file, temp_path) = file_manager::open_temporary_file(&entry_path).await?;
+        let result = async {
+            temp_file
+                .write_all(path.as_os_str().as_encoded_bytes())
+                .await?;
+            fs::rename(&temp_path, &entry_path).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        Ok(result?)
+    }
+
+    /// Drops the entry of `hash` if it still points to `path`, once it was found not to hold it.
+    pub async fn forget(&self, hash: &[u8], path: &Path) -> io::Result<()> {
+        if self.lookup(hash).await?.as_deref() != Some(path) {
+            return Ok(());
+        }
+
+        match fs::remove_file(self.entry_path(hash).unwrap()).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
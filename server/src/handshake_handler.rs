@@ -1,17 +1,18 @@
 use base64::{prelude::BASE64_STANDARD, Engine};
+use commons::{
+    packeter::{self, ReadPacketError, WritePacketError},
+    session::{self, Capabilities},
+};
 use snow::{HandshakeState, TransportState};
 use thiserror::Error;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::client_checker::PeerChecker;
 
 #[derive(Error, Debug)]
 pub enum ReadInitiatorError {
     #[error("Failed to read packet: {0}")]
-    ReadError(#[from] tokio::io::Error),
+    ReadError(#[from] ReadPacketError),
     #[error("Noise protocol error: {0}")]
     NoiseError(#[from] snow::Error),
 }
@@ -38,38 +39,47 @@ pub enum HandshakeError {
     ClientNotInPeerList,
 }
 
+#[derive(Error, Debug)]
+pub enum HelloError {
+    #[error("Failed to read hello: {0}")]
+    ReadError(#[from] ReadPacketError),
+    #[error("Failed to send hello acknowledgement: {0}")]
+    WriteError(#[from] WritePacketError),
+    #[error("Invalid hello: {0}")]
+    DecodeError(#[from] prost::DecodeError),
+    #[error("Client speaks unsupported protocol version {0} (client {1})")]
+    UnsupportedVersion(u32, String),
+}
+
 async fn read_initiator(
-    buf_reader: &mut BufReader<TcpStream>,
+    stream: &mut (impl AsyncRead + Unpin),
     noise: &mut HandshakeState,
 ) -> Result<(), ReadInitiatorError> {
-    let length = buf_reader.read_u32().await?;
-    let mut buffer = vec![0u8; length as usize];
-
-    buf_reader.read_exact(&mut buffer).await?;
-    noise.read_message(&mut buffer, &mut [])?;
+    let message = packeter::read_handshake_message(stream).await?;
+    noise.read_message(&message, &mut [])?;
 
     Ok(())
 }
 
 async fn do_receiver(
-    buf_reader: &mut BufReader<TcpStream>,
+    stream: &mut (impl AsyncWrite + Unpin),
     noise: &mut HandshakeState,
 ) -> Result<(), DoReceiverError> {
     let mut write_buffer = vec![0u8; 65535];
     let len = noise.write_message(&[], &mut write_buffer)?;
 
-    buf_reader.write_u32(len as u32).await?;
-    buf_reader.write_all(&write_buffer[..len]).await?;
+    stream.write_u32(len as u32).await?;
+    stream.write_all(&write_buffer[..len]).await?;
 
     Ok(())
 }
 
 pub async fn handle_handshake(
-    buf_reader: &mut BufReader<TcpStream>,
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
     peer_checker: &PeerChecker,
     mut noise: HandshakeState,
 ) -> Result<TransportState, HandshakeError> {
-    read_initiator(buf_reader, &mut noise).await?;
+    read_initiator(stream, &mut noise).await?;
     let client_public_key = match noise.get_remote_static() {
         Some(key) => key,
         None => return Err(HandshakeError::NoRemotePublicKey),
@@ -80,7 +90,54 @@ pub async fn handle_handshake(
         return Err(HandshakeError::ClientNotInPeerList);
     }
 
-    do_receiver(buf_reader, &mut noise).await?;
+    do_receiver(stream, &mut noise).await?;
 
     Ok(noise.into_transport_mode()?)
 }
+
+pub async fn handle_hello(
+    handler: &mut packeter::Handler<impl AsyncRead + AsyncWrite + Unpin>,
+) -> Result<Capabilities, HelloError> {
+    let hello = handler.read_packet().await?;
+    let hello = protos::deserialize_hello(&hello)?;
+
+    if !session::is_compatible(hello.protocol_version) {
+        let reason = format!(
+            "Protocol version {} is not supported, server accepts versions {} to {}",
+            hello.protocol_version,
+            session::MIN_PROTOCOL_VERSION,
+            session::PROTOCOL_VERSION
+        );
+        let hello_reject = protos::create_hello_reject(
+            session::PROTOCOL_VERSION,
+            String::from(session::SOFTWARE_VERSION),
+            reason,
+        );
+        handler
+            .write_packet(&protos::serialize_hello_ack(hello_reject))
+            .await?;
+
+        return Err(HelloError::UnsupportedVersion(
+            hello.protocol_version,
+            hello.software_version,
+        ));
+    }
+
+    let capabilities =
+        Capabilities::supported().intersection(&Capabilities::from_wire(&hello.capabilities));
+    let hello_ack = protos::create_hello_ack(
+        hello.protocol_version,
+        String::from(session::SOFTWARE_VERSION),
+        capabilities.to_wire(),
+    );
+    handler
+        .write_packet(&protos::serialize_hello_ack(hello_ack))
+        .await?;
+
+    println!(
+        "Client running zen-sync {} (protocol v{})",
+        hello.software_version, hello.protocol_version
+    );
+
+    Ok(capabilities)
+}
@@ -0,0 +1,213 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use commons::{envelope, mux};
+use prost::Message;
+use protos::{Body, Capability, File, RequestList};
+use tokio::fs;
+
+use crate::{describe_entry, request_error::RequestError, virtualizer::VirtualPath, ClientContext};
+
+/// Entries per page when the client leaves it to us, and the most it can ask for.
+const DEFAULT_PAGE_SIZE: usize = 1000;
+const MAX_PAGE_SIZE: usize = 10000;
+/// A page is sent early once its entries take this much, whatever their count.
+const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+/// Entries of a page, nested under their parent when it is in the same page.
+struct Page {
+    flat: bool,
+    files: Vec<File>,
+    /// Entry being filled and its ancestors in the page, innermost last.
+    open: Vec<File>,
+    len: usize,
+    bytes: usize,
+}
+
+impl Page {
+    fn new(flat: bool) -> Self {
+        Self {
+            flat,
+            files: Vec::new(),
+            open: Vec::new(),
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    fn push(&mut self, file: File) {
+        self.len += 1;
+        self.bytes += file.encoded_len();
+
+        if self.flat {
+            self.files.push(file);
+            return;
+        }
+
+        // Entries come in pre-order, the parent is open if it is in the page
+        while let Some(last) = self.open.last() {
+            if Path::new(&file.path).parent() == Some(Path::new(&last.path)) {
+                break;
+            }
+
+            self.close_last();
+        }
+        self.open.push(file);
+    }
+
+    fn close_last(&mut self) {
+        let last = self.open.pop().unwrap();
+        match self.open.last_mut() {
+            Some(parent) => parent.childrens.push(last),
+            None => self.files.push(last),
+        }
+    }
+
+    fn take(&mut self) -> Vec<File> {
+        while !self.open.is_empty() {
+            self.close_last();
+        }
+
+        self.len = 0;
+        self.bytes = 0;
+        std::mem::take(&mut self.files)
+    }
+}
+
+/// Where an entry is relative to the cursor the walk resumes after.
+enum Position {
+    /// Listed before, along with everything under it.
+    Before,
+    /// The cursor itself or one of its ancestors, what's under it may not have been listed.
+    Ancestor,
+    After,
+}
+
+/// Entries are walked in pre-order with siblings sorted by name, which is the order paths
+/// compare in component by component.
+fn position(path: &Path, cursor: Option<&Path>) -> Position {
+    let cursor = match cursor {
+        Some(cursor) => cursor,
+        None => return Position::After,
+    };
+
+    match path.cmp(cursor) {
+        Ordering::Greater => Position::After,
+        _ if cursor.starts_with(path) => Position::Ancestor,
+        _ => Position::Before,
+    }
+}
+
+async fn send_page(
+    stream: &mut mux::Stream,
+    page: &mut Page,
+    cursor: &Option<PathBuf>,
+    last: bool,
+    more: bool,
+    request_id: u64,
+) -> anyhow::Result<()> {
+    let cursor = cursor
+        .as_ref()
+        .map(|cursor| String::from(cursor.to_str().unwrap()))
+        .unwrap_or_default();
+    let response = protos::create_response_list(page.take(), cursor, last, more, request_id);
+    envelope::send(stream, Body::ResponseList(response)).await?;
+
+    Ok(())
+}
+
+/// Children of a stored directory, in the order they are listed in.
+async fn sorted_children(dir: &VirtualPath) -> anyhow::Result<Vec<VirtualPath>> {
+    let mut children = dir.entries().await?;
+    children.sort_unstable_by(|a, b| a.true_path().cmp(b.true_path()));
+
+    Ok(children)
+}
+
+/// Walks the tree under the requested path, sending entries page by page as they are found.
+pub async fn handle_list(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &RequestList,
+) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Listing) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Listings were not negotiated"
+        )));
+    }
+
+    let root = context.virtualizer.v_path(&PathBuf::from(&request.path))?;
+    // Fails early when there is nothing to list
+    fs::symlink_metadata(&root).await?;
+
+    let page_size = match request.page_size as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        page_size => page_size.min(MAX_PAGE_SIZE),
+    };
+    let resume_after = request.cursor.as_ref().map(PathBuf::from);
+    let mut cursor = resume_after.clone();
+    let mut page = Page::new(request.flat);
+    let mut hardlinks = HashMap::new();
+    let mut sent = 0;
+
+    // Entries still to visit with their depth, the next one last
+    let mut pending = vec![(root, 0)];
+    while let Some((virtual_path, depth)) = pending.pop() {
+        let metadata = match fs::symlink_metadata(&virtual_path).await {
+            Ok(metadata) => metadata,
+            // Removed since its directory was read
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let true_path = virtual_path.true_path().to_path_buf();
+
+        match position(&true_path, resume_after.as_deref()) {
+            Position::Before => continue,
+            Position::Ancestor => {}
+            Position::After => {
+                let file = describe_entry(
+                    context,
+                    &true_path,
+                    &virtual_path,
+                    Some(&mut hardlinks),
+                    request.hash,
+                )
+                .await?;
+
+                if let Some(file) = file {
+                    page.push(file);
+                    sent += 1;
+                    cursor = Some(true_path);
+
+                    if sent == request.limit {
+                        return send_page(
+                            stream,
+                            &mut page,
+                            &cursor,
+                            true,
+                            true,
+                            request.request_id,
+                        )
+                        .await;
+                    }
+
+                    if page.len >= page_size || page.bytes >= MAX_PAGE_BYTES {
+                        send_page(stream, &mut page, &cursor, false, false, request.request_id)
+                            .await?;
+                    }
+                }
+            }
+        }
+
+        if metadata.is_dir() && (request.max_depth == 0 || depth < request.max_depth) {
+            let children = sorted_children(&virtual_path).await?;
+            pending.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+        }
+    }
+
+    send_page(stream, &mut page, &cursor, true, false, request.request_id).await
+}
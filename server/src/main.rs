@@ -1,184 +1,868 @@
+mod chunk_store;
 mod cli;
 mod client_checker;
 mod config;
+mod connection_limiter;
+mod content_index;
 mod handshake_handler;
-
+mod listing;
+mod moves;
+mod request_error;
+mod snapshots;
+mod stored_content;
+mod stored_flags;
+mod transfers;
+mod trash;
+mod versions;
+mod virtualizer;
+
+use chunk_store::ChunkStore;
 use cli::Args;
-use config::ServerConfig;
+use config::{Config, ServerConfig};
+use connection_limiter::{PreAuthLimiter, PreAuthPermit, SessionLimiter};
+use content_index::ContentIndex;
 use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::fs::MetadataExt,
+    },
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
-use commons::{file_manager, packeter};
+use commons::{
+    chunker,
+    compression::{self, Compressor},
+    delta,
+    envelope::{self, ReceiveError},
+    file_manager,
+    integrity::HashingWriter,
+    mux::{self, Multiplexer, Side},
+    packeter,
+    session::{Capabilities, SessionLimits},
+};
 use nix::unistd::Uid;
 
 use anyhow::{bail, Context};
 use base64::{prelude::BASE64_STANDARD, Engine};
 use client_checker::PeerChecker;
-use protos::{
-    deserialize_request, deserialize_request_add, deserialize_request_get,
-    deserialize_request_move, deserialize_request_remove, deserialize_request_sync, RequestType,
-};
+use protos::{Body, Capability, Compression, FileType};
+use request_error::RequestError;
+use snapshots::{SnapshotStore, TreeLocks};
+use stored_content::StoredContent;
 use tokio::{
     fs,
-    io::{self, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
-    sync::Semaphore,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
 };
+use transfers::TransferStore;
+use trash::Trash;
+use versions::VersionStore;
+use virtualizer::{VirtualPath, Virtualizer};
+
+const TRANSFERS_FOLDER: &str = ".transfers";
+const INDEX_FOLDER: &str = ".index";
+const CHUNKS_FOLDER: &str = ".chunks";
+const VERSIONS_FOLDER: &str = ".versions";
+const SNAPSHOTS_FOLDER: &str = ".snapshots";
+const TRASH_FOLDER: &str = ".trash";
+
+/// Everything a request of an authenticated client can need.
+struct ClientContext {
+    virtualizer: Virtualizer,
+    capabilities: Capabilities,
+    transfers: TransferStore,
+    content_index: ContentIndex,
+    chunks: ChunkStore,
+    versions: VersionStore,
+    snapshots: SnapshotStore,
+    trash: Trash,
+    /// Whether a request of the session may have changed the tree.
+    modified: AtomicBool,
+}
 
-const MAX_CONCURRENT_CONNECTIONS: usize = 10;
+fn decode_frame(compression: Compression, frame: &[u8]) -> Result<Vec<u8>, RequestError> {
+    compression::decode(compression, frame)
+        .map_err(|e| RequestError::Protocol(format!("Invalid payload frame: {}", e)))
+}
 
-struct Virtualizer {
-    user_path: PathBuf,
+fn unexpected_message(expected: &str, body: &Body) -> RequestError {
+    RequestError::Protocol(format!(
+        "Expected {}, got {}",
+        expected,
+        envelope::name(body)
+    ))
 }
 
-impl Virtualizer {
-    fn new(user_path: PathBuf) -> Self {
-        Self { user_path }
-    }
+/// Reads the next message of an upload, one that doesn't decode leaves its end unknown.
+async fn receive_content(stream: &mut mux::Stream) -> anyhow::Result<Body> {
+    envelope::receive(stream).await.map_err(|e| match e {
+        ReceiveError::ReadError(_) => e.into(),
+        _ => RequestError::Protocol(e.to_string()).into(),
+    })
+}
 
-    fn v_path(&self, path: &PathBuf) -> io::Result<PathBuf> {
-        if path.starts_with("/") {
-            Ok(self.user_path.join(path.strip_prefix("/").unwrap()))
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Path must start with /"),
-            ))
+async fn receive_payload(
+    stream: &mut mux::Stream,
+    output: &mut (impl AsyncWrite + Unpin),
+    size: u64,
+    compression: Compression,
+) -> anyhow::Result<()> {
+    let mut write_result = Ok(());
+    let mut remaining_bytes = size;
+    loop {
+        let frame = match receive_content(stream).await? {
+            Body::Data(frame) => frame,
+            Body::EndOfFile(_) => break,
+            body => bail!(unexpected_message("a data chunk", &body)),
+        };
+        let payload_chunk = decode_frame(compression, &frame)?;
+
+        println!("Received chunk of {} bytes", payload_chunk.len());
+
+        // Keep reading after a failed write so the next request starts on a packet boundary
+        if write_result.is_ok() {
+            write_result = output.write_all(&payload_chunk).await;
         }
+
+        remaining_bytes = remaining_bytes
+            .checked_sub(payload_chunk.len() as u64)
+            .ok_or_else(|| {
+                RequestError::Protocol(String::from("Payload is larger than announced size"))
+            })?;
     }
 
-    fn uv_path(&self, path: &PathBuf) -> io::Result<PathBuf> {
-        if path.starts_with(&self.user_path) {
-            let relative_path = path.strip_prefix(&self.user_path).unwrap();
-            Ok(Path::new("/").join(relative_path))
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Path must start with {}", &self.user_path.display()),
-            ))
+    if remaining_bytes > 0 {
+        bail!(RequestError::Protocol(String::from(
+            "Payload is smaller than announced size"
+        )));
+    }
+
+    Ok(write_result?)
+}
+
+async fn discard_payload(
+    stream: &mut mux::Stream,
+    request: &protos::RequestAdd,
+) -> anyhow::Result<()> {
+    // Delta uploads wait for our signature, so nothing is in flight yet
+    if request.delta {
+        return Ok(());
+    }
+
+    loop {
+        match receive_content(stream).await? {
+            Body::Data(_) => {}
+            Body::EndOfFile(_) => return Ok(()),
+            body => bail!(unexpected_message("a data chunk", &body)),
         }
     }
 }
 
-async fn handle_add_request(
-    handler: &mut packeter::Handler,
-    request: protos::RequestAdd,
-    virtualizer: &Virtualizer,
+async fn receive_delta(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    virtual_path: &PathBuf,
+    output: &mut (impl AsyncWrite + Unpin),
+    size: u64,
+    compression: Compression,
+    request_id: u64,
 ) -> anyhow::Result<()> {
-    let request_file = if let Some(file) = request.file {
-        file
+    let is_file = fs::symlink_metadata(&virtual_path)
+        .await
+        .is_ok_and(|metadata| metadata.is_file());
+    let mut base = if is_file {
+        Some(StoredContent::open(virtual_path, &context.chunks).await?)
     } else {
-        return Err(anyhow::anyhow!("No file in request"));
+        None
     };
 
-    let true_path = PathBuf::from(request_file.path);
-    let virtual_path = virtualizer.v_path(&true_path)?;
+    let base_len = if let Some(base) = &mut base {
+        base.size().await?
+    } else {
+        0
+    };
 
-    if let Some(parent) = virtual_path.parent() {
-        if !fs::try_exists(&parent).await? {
-            fs::create_dir_all(&parent).await?;
+    let signature = if let Some(base) = &mut base {
+        delta::signature_of(base, delta::block_size_for(base_len)).await?
+    } else {
+        delta::Signature::empty(delta::block_size_for(size))
+    };
+    let block_size = signature.block_size;
+
+    let header = protos::create_signature(block_size, signature.blocks.len() as u64);
+    let response = protos::create_response_signature(header, request_id);
+    envelope::send(stream, Body::ResponseSignature(response)).await?;
+    delta::send_signature_blocks(stream, signature).await?;
+
+    let mut apply_result = Ok(());
+    let mut remaining_bytes = size;
+    loop {
+        let operation = match receive_content(stream).await? {
+            Body::DeltaOperation(operation) => {
+                compression::decode_operation(compression, operation).map_err(|e| {
+                    RequestError::Protocol(format!("Invalid delta operation: {}", e))
+                })?
+            }
+            Body::EndOfFile(_) => break,
+            body => bail!(unexpected_message("a delta operation", &body)),
+        };
+        let len = delta::operation_len(&operation, block_size, base_len)
+            .ok_or_else(|| RequestError::Protocol(String::from("Delta operation out of range")))?;
+
+        // Keep consuming operations after a failure so the session stays usable
+        if apply_result.is_ok() {
+            apply_result = delta::apply_operation(base.as_mut(), block_size, operation, output)
+                .await
+                .map(|_| ());
         }
+
+        remaining_bytes = remaining_bytes.checked_sub(len).ok_or_else(|| {
+            RequestError::Protocol(String::from("Delta is larger than announced size"))
+        })?;
     }
 
-    let (mut temp_file, temp_path) = file_manager::open_temporary_file(&virtual_path).await?;
+    if remaining_bytes > 0 {
+        bail!(RequestError::Protocol(String::from(
+            "Delta is smaller than announced size"
+        )));
+    }
 
-    let mut remaining_bytes = request_file.size;
-    loop {
-        if remaining_bytes == 0 {
-            break;
+    Ok(apply_result?)
+}
+
+async fn open_add_target<'a>(
+    request: &'a protos::RequestAdd,
+    context: &ClientContext,
+) -> anyhow::Result<(&'a protos::File, VirtualPath, fs::File, PathBuf)> {
+    let request_file = if let Some(file) = &request.file {
+        file
+    } else {
+        bail!(RequestError::InvalidRequest(String::from(
+            "No file in request"
+        )));
+    };
+
+    if request.delta && !context.capabilities.has(Capability::Delta) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Delta transfer was not negotiated"
+        )));
+    }
+
+    let resumable = !request.transfer_id.is_empty();
+    if resumable && !context.capabilities.has(Capability::Resume) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Resumable transfer was not negotiated"
+        )));
+    }
+
+    if !compression::is_negotiated(request.compression(), &context.capabilities) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Compression was not negotiated"
+        )));
+    }
+
+    if request.offset > request_file.size || (!resumable && request.offset > 0) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Offset is past the end of the file"
+        )));
+    }
+
+    let true_path = PathBuf::from(&request_file.path);
+    let mut virtual_path = context.virtualizer.v_path(&true_path)?;
+    virtual_path.create_parents()?;
+
+    let (temp_file, temp_path) = if resumable {
+        context
+            .transfers
+            .open_partial(&request.transfer_id, request.offset)
+            .await?
+    } else {
+        file_manager::open_temporary_file(&virtual_path).await?
+    };
+
+    Ok((request_file, virtual_path, temp_file, temp_path))
+}
+
+/// Receives the content of an upload into `temp_file`, checking it against the announced hash.
+async fn receive_verified(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &protos::RequestAdd,
+    request_file: &protos::File,
+    virtual_path: &PathBuf,
+    temp_file: &mut fs::File,
+    temp_path: &Path,
+) -> anyhow::Result<()> {
+    let mut output = if request.offset > 0 {
+        HashingWriter::resume(temp_file, temp_path, request.offset).await?
+    } else {
+        HashingWriter::new(temp_file)
+    };
+
+    let remaining_bytes = request_file.size - request.offset;
+    if request.delta {
+        receive_delta(
+            stream,
+            context,
+            virtual_path,
+            &mut output,
+            remaining_bytes,
+            request.compression(),
+            request.request_id,
+        )
+        .await?;
+    } else {
+        receive_payload(stream, &mut output, remaining_bytes, request.compression()).await?;
+    }
+
+    output.flush().await?;
+    output.verify(request_file.size, request_file.hash.as_deref())?;
+
+    Ok(())
+}
+
+/// Stores an entry that comes without content, a symlink, hardlink or special file.
+async fn handle_add_node(file: &protos::File, context: &ClientContext) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Links) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Links were not negotiated"
+        )));
+    }
+
+    let mut virtual_path = context.virtualizer.v_path(&PathBuf::from(&file.path))?;
+    virtual_path.create_parents()?;
+
+    if file.link_target.is_none()
+        && matches!(file.file_type(), FileType::Symlink | FileType::Hardlink)
+    {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Link without a target"
+        )));
+    }
+
+    match file.file_type() {
+        FileType::Hardlink => {
+            let source_path = PathBuf::from(file.link_target.as_ref().unwrap());
+            let source = context.virtualizer.v_path(&source_path)?;
+            let source_metadata = match fs::symlink_metadata(&source).await {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => bail!(RequestError::InvalidRequest(String::from(
+                    "Hardlink to a file that isn't stored"
+                ))),
+            };
+
+            // Linking the same file again replaces nothing
+            let linked = fs::symlink_metadata(&virtual_path)
+                .await
+                .is_ok_and(|metadata| {
+                    metadata.dev() == source_metadata.dev()
+                        && metadata.ino() == source_metadata.ino()
+                });
+            if !linked {
+                keep_version(context, &virtual_path, None).await?;
+            }
+
+            file_manager::create_hardlink(&source, &virtual_path).await
+        }
+        FileType::Symlink => {
+            keep_version(context, &virtual_path, None).await?;
+            file_manager::create_node(&stored_flags::to_stored(file), &virtual_path).await
+        }
+        _ => {
+            keep_version(context, &virtual_path, None).await?;
+            stored_content::write_node(&virtual_path, file).await
         }
+    }
+}
 
-        let payload_chunk = match handler.read_packet().await {
-            Ok(chunk) => chunk,
-            Err(e) => match e {
-                packeter::ReadPacketError::ReadError(_) => {
-                    continue;
-                }
-                _ => break,
-            },
+async fn handle_add_request(
+    stream: &mut mux::Stream,
+    request: protos::RequestAdd,
+    context: &ClientContext,
+) -> anyhow::Result<()> {
+    // Nodes are announced without any content following them
+    if let Some(file) = &request.file {
+        if file_manager::is_node(file.file_type()) {
+            return handle_add_node(file, context).await;
+        }
+    }
+
+    let (request_file, virtual_path, mut temp_file, temp_path) =
+        match open_add_target(&request, context).await {
+            Ok(target) => target,
+            Err(e) => {
+                discard_payload(stream, &request).await?;
+                return Err(e);
+            }
         };
 
-        println!("Received chunk of {} bytes", payload_chunk.len());
+    let result = receive_verified(
+        stream,
+        context,
+        &request,
+        request_file,
+        &virtual_path,
+        &mut temp_file,
+        &temp_path,
+    )
+    .await;
+
+    if let Err(e) = result {
+        // Keep what made it here if the client can come back for the rest
+        if !request.transfer_id.is_empty() && request_error::is_interrupted(&e) {
+            if let Err(suspend_error) = context
+                .transfers
+                .suspend(&request.transfer_id, temp_file)
+                .await
+            {
+                eprintln!("Failed to suspend transfer: {:#}", suspend_error);
+            }
+        } else {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        return Err(e);
+    }
 
-        temp_file.write_all(&payload_chunk).await?;
-        remaining_bytes -= payload_chunk.len() as u64;
+    if let Err(e) = keep_version(context, &virtual_path, request_file.hash.as_deref()).await {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e);
     }
 
     let std_temp_file = temp_file.into_std().await;
-    file_manager::close_temporary_file(
+    let result = file_manager::close_temporary_file(
         std_temp_file,
         &temp_path,
         &virtual_path,
-        request_file.last_modified,
-        request_file.file_permissions,
-        request_file.file_owner,
-        request_file.file_group,
+        &stored_flags::to_stored(request_file),
     )
-    .await?;
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+    result?;
+
+    record_content(context, request_file).await;
 
     Ok(())
 }
 
-async fn handle_move(request: &protos::RequestMove) -> anyhow::Result<()> {
-    for file in &request.files {
-        let old_path = Path::new(&file.old_path);
-        if !fs::try_exists(&old_path).await? {
-            continue;
+/// Keeps the file stored at `virtual_path` as a version before it is removed, or replaced by
+/// content hashing to `replacement`.
+async fn keep_version(
+    context: &ClientContext,
+    virtual_path: &VirtualPath,
+    replacement: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    context
+        .versions
+        .keep(virtual_path.true_path(), virtual_path, replacement)
+        .await
+}
+
+/// Adds a stored file to the content index, a failure only costs a later upload.
+async fn record_content(context: &ClientContext, file: &protos::File) {
+    let Some(hash) = &file.hash else {
+        return;
+    };
+
+    if let Err(e) = context
+        .content_index
+        .record(hash, Path::new(&file.path))
+        .await
+    {
+        eprintln!("Failed to index {}: {:#}", file.path, e);
+    }
+}
+
+/// Copies the stored file at `source` into `temp_file`, if it still is the offered content.
+async fn copy_content(
+    source: &Path,
+    file: &protos::File,
+    temp_file: &mut fs::File,
+) -> anyhow::Result<bool> {
+    // Anything but a plain file is left alone, reading a fifo would block
+    if !fs::symlink_metadata(source).await?.is_file() {
+        return Ok(false);
+    }
+
+    let mut reader = BufReader::new(file_manager::open_noatime_nofollow(source).await?);
+    let mut output = HashingWriter::new(temp_file);
+    tokio::io::copy(&mut reader, &mut output).await?;
+    output.flush().await?;
+
+    Ok(output.verify(file.size, file.hash.as_deref()).is_ok())
+}
+
+/// Stores an offered file from content already stored under another path, if there is some.
+async fn handle_offer(
+    stream: &mut mux::Stream,
+    request: &protos::RequestOffer,
+    context: &ClientContext,
+) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Dedup) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Deduplication was not negotiated"
+        )));
+    }
+
+    let file = match &request.file {
+        Some(file) if file.file_type() == FileType::File => file,
+        _ => bail!(RequestError::InvalidRequest(String::from(
+            "Only files can be offered"
+        ))),
+    };
+    let hash = match &file.hash {
+        Some(hash) => hash,
+        None => bail!(RequestError::InvalidRequest(String::from(
+            "Offered file without a hash"
+        ))),
+    };
+
+    let mut virtual_path = context.virtualizer.v_path(&PathBuf::from(&file.path))?;
+    let present = match context.content_index.lookup(hash).await? {
+        Some(source) => store_from(context, file, &source, &mut virtual_path).await?,
+        None => false,
+    };
+
+    if present {
+        record_content(context, file).await;
+    }
+
+    let response = protos::create_response_offer(present, request.request_id);
+    envelope::send(stream, Body::ResponseOffer(response)).await?;
+
+    Ok(())
+}
+
+/// Stores `file` at `virtual_path` from the copy at `source`, `false` if it no longer holds it.
+async fn store_from(
+    context: &ClientContext,
+    file: &protos::File,
+    source: &PathBuf,
+    virtual_path: &mut VirtualPath,
+) -> anyhow::Result<bool> {
+    let hash = file.hash.as_deref().unwrap_or_default();
+    let source_virtual_path = match context.virtualizer.v_path(source) {
+        Ok(path) => path,
+        Err(_) => {
+            context.content_index.forget(hash, source).await?;
+            return Ok(false);
+        }
+    };
+
+    virtual_path.create_parents()?;
+    keep_version(context, virtual_path, Some(hash)).await?;
+
+    // Content kept as chunks is shared by writing its manifest again
+    let manifest = match fs::symlink_metadata(&source_virtual_path).await {
+        Ok(metadata) if metadata.is_file() => {
+            stored_content::read_manifest(&source_virtual_path).await?
+        }
+        _ => None,
+    };
+    if let Some(manifest) = manifest {
+        if manifest.size != file.size
+            || manifest.hash != hash
+            || !context.chunks.contains_all(&manifest).await?
+        {
+            context.content_index.forget(hash, source).await?;
+            return Ok(false);
+        }
+
+        stored_content::write_manifest(virtual_path, file, &manifest).await?;
+        return Ok(true);
+    }
+
+    let (mut temp_file, temp_path) = file_manager::open_temporary_file(virtual_path).await?;
+    let copied = match copy_content(&source_virtual_path, file, &mut temp_file).await {
+        Ok(copied) => copied,
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            false
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+    };
+
+    if !copied {
+        let _ = fs::remove_file(&temp_path).await;
+        context.content_index.forget(hash, source).await?;
+        return Ok(false);
+    }
+
+    let std_temp_file = temp_file.into_std().await;
+    let result = file_manager::close_temporary_file(
+        std_temp_file,
+        &temp_path,
+        virtual_path,
+        &stored_flags::to_stored(file),
+    )
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+    result?;
+
+    Ok(true)
+}
+
+/// Checks the chunk list of an upload adds up to the announced file, and turns it into its
+/// manifest.
+fn chunks_manifest(
+    file: &protos::File,
+    chunks: Vec<protos::Chunk>,
+) -> anyhow::Result<protos::Manifest> {
+    let hash = match &file.hash {
+        Some(hash) => hash.clone(),
+        None => bail!(RequestError::InvalidRequest(String::from(
+            "Chunked file without a hash"
+        ))),
+    };
+
+    let mut size: u64 = 0;
+    for chunk in &chunks {
+        if chunk.hash.len() != chunk_store::HASH_LEN
+            || chunk.size == 0
+            || chunk.size as usize > chunker::MAX_CHUNK_SIZE
+        {
+            bail!(RequestError::InvalidRequest(String::from(
+                "Invalid chunk in chunk list"
+            )));
         }
 
-        let new_path = Path::new(&file.new_path);
-        fs::rename(&old_path, &new_path).await?;
+        size += chunk.size as u64;
+    }
+
+    if size != file.size {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Chunks don't add up to the file size"
+        )));
     }
 
+    Ok(protos::create_manifest(chunks, size, hash))
+}
+
+/// Receives the chunks of an upload the store lacks, in the order they were asked for.
+async fn receive_chunks(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    chunks: Vec<&protos::Chunk>,
+    compression: Compression,
+) -> anyhow::Result<()> {
+    let mut store_result = Ok(());
+    for chunk in chunks {
+        let mut data = Vec::with_capacity(chunk.size as usize);
+        while data.len() < chunk.size as usize {
+            let frame = match receive_content(stream).await? {
+                Body::Data(frame) => frame,
+                body => bail!(unexpected_message("a data chunk", &body)),
+            };
+            data.extend(decode_frame(compression, &frame)?);
+        }
+
+        if data.len() != chunk.size as usize {
+            bail!(RequestError::Protocol(String::from(
+                "Chunk is larger than announced"
+            )));
+        }
+
+        // Keep reading after a failed write so the next request starts on a packet boundary
+        if store_result.is_ok() {
+            store_result = context.chunks.write(chunk, &data).await;
+        }
+    }
+
+    match receive_content(stream).await? {
+        Body::EndOfFile(_) => store_result,
+        body => bail!(unexpected_message("the end of the chunks", &body)),
+    }
+}
+
+/// Stores an upload made of chunks, asking the client for the ones the store lacks.
+async fn handle_chunks(
+    stream: &mut mux::Stream,
+    request: &protos::RequestChunks,
+    context: &ClientContext,
+) -> anyhow::Result<()> {
+    // The chunk list follows the request, read it before anything can fail
+    let file_size = request.file.as_ref().map_or(0, |file| file.size);
+    let chunks = chunker::receive_chunk_list(stream, request.chunk_count, file_size)
+        .await
+        .map_err(|e| RequestError::Protocol(e.to_string()))?;
+
+    if !context.capabilities.has(Capability::Chunks) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Chunked transfer was not negotiated"
+        )));
+    }
+
+    if !compression::is_negotiated(request.compression(), &context.capabilities) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Compression was not negotiated"
+        )));
+    }
+
+    let file = match &request.file {
+        Some(file) if file.file_type() == FileType::File => file,
+        _ => bail!(RequestError::InvalidRequest(String::from(
+            "Only files can be sent as chunks"
+        ))),
+    };
+    let manifest = chunks_manifest(file, chunks)?;
+
+    let mut virtual_path = context.virtualizer.v_path(&PathBuf::from(&file.path))?;
+    virtual_path.create_parents()?;
+
+    // Chunks repeated in the file are only asked for once
+    let mut asked = HashSet::new();
+    let mut missing = Vec::new();
+    for (index, chunk) in manifest.chunks.iter().enumerate() {
+        if !asked.contains(&chunk.hash) && !context.chunks.contains(chunk).await? {
+            asked.insert(chunk.hash.clone());
+            missing.push(index as u64);
+        }
+    }
+
+    let missing_chunks = missing
+        .iter()
+        .map(|index| &manifest.chunks[*index as usize])
+        .collect();
+    let response = protos::create_response_chunks(missing, request.request_id);
+    envelope::send(stream, Body::ResponseChunks(response)).await?;
+    receive_chunks(stream, context, missing_chunks, request.compression()).await?;
+
+    // Every chunk checked out, this makes sure they are the ones of the announced file
+    let mut output = HashingWriter::new(tokio::io::sink());
+    tokio::io::copy(&mut context.chunks.reader(manifest.clone()), &mut output).await?;
+    output.verify(file.size, file.hash.as_deref())?;
+
+    keep_version(context, &virtual_path, file.hash.as_deref()).await?;
+    stored_content::write_manifest(&virtual_path, file, &manifest).await?;
+    record_content(context, file).await;
+
+    Ok(())
+}
+
+async fn handle_resume(
+    stream: &mut mux::Stream,
+    request: &protos::RequestResume,
+    context: &ClientContext,
+) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Resume) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Resumable transfer was not negotiated"
+        )));
+    }
+
+    let partial = context.transfers.lookup(&request.transfer_id).await?;
+    let response = protos::create_response_resume(partial, request.request_id);
+    envelope::send(stream, Body::ResponseResume(response)).await?;
+
     Ok(())
 }
 
 async fn handle_delete(
     request: &protos::RequestRemove,
-    virtualizer: &Virtualizer,
+    context: &ClientContext,
 ) -> anyhow::Result<()> {
     for file in &request.files {
         let true_path = PathBuf::from(&file.path);
-        let virtual_path = virtualizer.v_path(&true_path)?;
-        if !fs::try_exists(&virtual_path).await? {
-            continue;
+        let virtual_path = context.virtualizer.v_path(&true_path)?;
+        match fs::symlink_metadata(&virtual_path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
         }
 
-        if virtual_path.is_dir() {
-            fs::remove_dir_all(virtual_path).await?;
-        } else {
-            fs::remove_file(virtual_path).await?;
-        }
+        trash::remove(context, &virtual_path).await?;
     }
 
     Ok(())
 }
 
+/// Describes a stored entry, `None` for the ones this client can't be told about. Later names
+/// of a file seen in `hardlinks` are described as hardlinks to the first one.
+async fn describe_entry(
+    context: &ClientContext,
+    true_path: &PathBuf,
+    virtual_path: &PathBuf,
+    hardlinks: Option<&mut HashMap<(u64, u64), String>>,
+    hash_content: bool,
+) -> anyhow::Result<Option<protos::File>> {
+    let metadata = fs::symlink_metadata(&virtual_path).await?;
+    if file_manager::file_type_convert(metadata.file_type()).is_err() {
+        return Ok(None);
+    }
+
+    let links = context.capabilities.has(Capability::Links);
+
+    let hardlinks = hardlinks.filter(|_| links && metadata.is_file() && metadata.nlink() > 1);
+    if let Some(hardlinks) = hardlinks {
+        let key = (metadata.dev(), metadata.ino());
+        if let Some(first) = hardlinks.get(&key) {
+            let mut file_stats =
+                file_manager::create_from_metadata(true_path, None, &metadata, false).await?;
+            file_stats.set_file_type(FileType::Hardlink);
+            file_stats.size = 0;
+            file_stats.link_target = Some(first.clone());
+            return Ok(Some(file_stats));
+        }
+
+        hardlinks.insert(key, String::from(true_path.to_str().unwrap()));
+    }
+
+    let file_stats =
+        stored_content::describe(true_path, virtual_path, &metadata, hash_content).await?;
+    // Nodes are only known once the description of their stored copy is turned back
+    if file_manager::is_node(file_stats.file_type()) && !links {
+        return Ok(None);
+    }
+
+    Ok(Some(file_stats))
+}
+
 async fn visit_dirs(
-    virtualizer: &Virtualizer,
-    dir: &PathBuf,
+    context: &ClientContext,
+    dir: &VirtualPath,
     file_stats: &mut protos::File,
+    hardlinks: &mut HashMap<(u64, u64), String>,
 ) -> anyhow::Result<()> {
-    if dir.is_dir() {
-        let mut dir = fs::read_dir(&dir).await?;
-        while let Ok(entry) = dir.next_entry().await {
-            let entry = match entry {
-                Some(entry) => entry,
-                None => break,
+    if file_stats.file_type() == FileType::Directory {
+        for virtual_path in dir.entries().await? {
+            let true_path = virtual_path.true_path().to_path_buf();
+            let mut entry_stats = match describe_entry(
+                context,
+                &true_path,
+                &virtual_path,
+                Some(&mut *hardlinks),
+                true,
+            )
+            .await?
+            {
+                Some(entry_stats) => entry_stats,
+                None => continue,
             };
 
-            let virtual_path = entry.path();
-            let metadata = fs::metadata(&virtual_path).await?;
-            let true_path = virtualizer.uv_path(&virtual_path)?;
-            let mut entry_stats =
-                file_manager::create_from_metadata(&true_path, Some(&virtual_path), &metadata)
-                    .await?;
-
-            if virtual_path.is_dir() {
-                Box::pin(visit_dirs(virtualizer, &virtual_path, &mut entry_stats)).await?;
-            }
+            Box::pin(visit_dirs(
+                context,
+                &virtual_path,
+                &mut entry_stats,
+                hardlinks,
+            ))
+            .await?;
 
             file_stats.childrens.push(entry_stats);
         }
@@ -187,153 +871,691 @@ async fn visit_dirs(
     Ok(())
 }
 
+/// Paths of the snapshot a request reads from, `None` for the current tree.
+async fn snapshot_tree(
+    context: &ClientContext,
+    snapshot: &Option<String>,
+) -> anyhow::Result<Option<Virtualizer>> {
+    match snapshot {
+        Some(id) => Ok(Some(snapshots::tree(context, id).await?)),
+        None => Ok(None),
+    }
+}
+
 async fn handle_get(
-    handler: &mut packeter::Handler,
-    virtualizer: &Virtualizer,
+    stream: &mut mux::Stream,
+    context: &ClientContext,
     request: &protos::RequestGet,
 ) -> anyhow::Result<()> {
-    let mut response = protos::ResponseGet::default();
+    let snapshot = snapshot_tree(context, &request.snapshot).await?;
+    let tree = snapshot.as_ref().unwrap_or(&context.virtualizer);
+
+    let mut response = protos::create_response_get(Vec::new(), request.request_id);
+    let mut hardlinks = HashMap::new();
     for file in &request.files {
         let true_path = PathBuf::from(&file.path);
-        let virtual_path = virtualizer.v_path(&true_path)?;
+        let virtual_path = tree.v_path(&true_path)?;
 
         if !fs::try_exists(&virtual_path).await? {
             continue;
         }
 
-        let metadata = fs::metadata(&virtual_path).await?;
-        let mut file_stats =
-            file_manager::create_from_metadata(&true_path, Some(&virtual_path), &metadata).await?;
-        visit_dirs(virtualizer, &virtual_path, &mut file_stats).await?;
+        let mut file_stats = match describe_entry(
+            context,
+            &true_path,
+            &virtual_path,
+            Some(&mut hardlinks),
+            true,
+        )
+        .await?
+        {
+            Some(file_stats) => file_stats,
+            None => continue,
+        };
+        visit_dirs(context, &virtual_path, &mut file_stats, &mut hardlinks).await?;
         response.files.push(file_stats);
     }
 
-    let response = protos::serialize_response_get(response);
-    handler.write_packet(response.as_slice()).await?;
+    envelope::send(stream, Body::ResponseGet(response)).await?;
+
+    Ok(())
+}
+
+/// Describes the direct children of a stored directory.
+async fn list_children(
+    context: &ClientContext,
+    dir: &VirtualPath,
+    file_stats: &mut protos::File,
+    hash_content: bool,
+) -> anyhow::Result<()> {
+    for virtual_path in dir.entries().await? {
+        let true_path = virtual_path.true_path().to_path_buf();
+
+        if let Some(entry_stats) =
+            describe_entry(context, &true_path, &virtual_path, None, hash_content).await?
+        {
+            file_stats.childrens.push(entry_stats);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_stat(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &protos::RequestStat,
+) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Stat) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Stat requests were not negotiated"
+        )));
+    }
+
+    let snapshot = snapshot_tree(context, &request.snapshot).await?;
+    let tree = snapshot.as_ref().unwrap_or(&context.virtualizer);
+
+    let mut entries = Vec::new();
+    for file in &request.files {
+        let true_path = PathBuf::from(&file.path);
+        let virtual_path = tree.v_path(&true_path)?;
+
+        let exists = match fs::symlink_metadata(&virtual_path).await {
+            Ok(_) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut file_stats = None;
+        if exists {
+            file_stats =
+                describe_entry(context, &true_path, &virtual_path, None, request.hash).await?;
+        }
+
+        if let Some(file_stats) = &mut file_stats {
+            if request.children && file_stats.file_type() == FileType::Directory {
+                list_children(context, &virtual_path, file_stats, request.hash).await?;
+            }
+        }
+
+        entries.push(protos::create_stat_entry(file.path.clone(), file_stats));
+    }
+
+    let response = protos::create_response_stat(entries, request.request_id);
+    envelope::send(stream, Body::ResponseStat(response)).await?;
+
+    Ok(())
+}
+
+async fn send_delta(
+    stream: &mut mux::Stream,
+    file_reader: &mut (impl AsyncRead + Unpin),
+    signature: delta::Signature,
+    compressor: &mut Compressor,
+) -> anyhow::Result<()> {
+    let mut generator = delta::DeltaGenerator::new(signature);
+
+    let mut read_buf = vec![0u8; 32768];
+    loop {
+        let n = file_reader.read(&mut read_buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        for operation in generator.update(&read_buf[..n]) {
+            let operation = compressor.encode_operation(operation);
+            envelope::send(stream, Body::DeltaOperation(operation)).await?;
+        }
+    }
+
+    for operation in generator.finish() {
+        let operation = compressor.encode_operation(operation);
+        envelope::send(stream, Body::DeltaOperation(operation)).await?;
+    }
+
+    envelope::send(stream, Body::EndOfFile(protos::EndOfFile {})).await?;
+
+    Ok(())
+}
+
+/// Where to pick up a download the client already has the beginning of.
+async fn resume_offset(
+    context: &ClientContext,
+    virtual_path: &Path,
+    size: u64,
+    partial: &Option<protos::PartialTransfer>,
+) -> anyhow::Result<u64> {
+    let partial = match partial {
+        Some(partial) if partial.offset <= size => partial,
+        _ => return Ok(0),
+    };
+
+    let content = StoredContent::open(virtual_path, &context.chunks).await?;
+    let hasher = file_manager::reader_prefix_hasher(content, partial.offset).await?;
+    if hasher.finalize().as_bytes().as_slice() != partial.hash {
+        return Ok(0);
+    }
+
+    Ok(partial.offset)
+}
+
+/// Answers the sync of an entry without content, nothing follows the response.
+async fn send_node(
+    stream: &mut mux::Stream,
+    request: &protos::RequestSync,
+    context: &ClientContext,
+    file_path: &PathBuf,
+    virtual_path: &PathBuf,
+    metadata: &std::fs::Metadata,
+) -> anyhow::Result<()> {
+    file_manager::file_type_convert(metadata.file_type())
+        .map_err(|e| RequestError::InvalidRequest(e.to_string()))?;
+    let mut file_stats =
+        file_manager::create_from_metadata(file_path, Some(virtual_path), metadata, false).await?;
+    stored_flags::from_stored(&mut file_stats);
+    if !file_manager::is_node(file_stats.file_type()) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Only files can be synced"
+        )));
+    }
+
+    if !context.capabilities.has(Capability::Links) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Links were not negotiated"
+        )));
+    }
+
+    let response_sync = protos::create_response_sync(
+        file_stats,
+        0,
+        false,
+        0,
+        Compression::None,
+        request.request_id,
+    );
+    envelope::send(stream, Body::ResponseSync(response_sync)).await?;
 
     Ok(())
 }
 
 async fn handle_sync(
-    handler: &mut packeter::Handler,
+    stream: &mut mux::Stream,
     request: &protos::RequestSync,
-    virtualizer: &Virtualizer,
+    context: &ClientContext,
 ) -> anyhow::Result<()> {
+    // The signature blocks follow the request, read them before anything can fail
+    let signature = if let Some(header) = &request.signature {
+        let signature = delta::receive_signature_blocks(stream, header)
+            .await
+            .map_err(|e| RequestError::Protocol(e.to_string()))?;
+        Some(signature)
+    } else {
+        None
+    };
+
     let file_to_sync = if let Some(file) = &request.file {
         file
     } else {
-        bail!("No file in request");
+        bail!(RequestError::InvalidRequest(String::from(
+            "No file in request"
+        )));
     };
 
+    if signature.is_some() && !context.capabilities.has(Capability::Delta) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Delta transfer was not negotiated"
+        )));
+    }
+
+    if request.partial.is_some() && !context.capabilities.has(Capability::Resume) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Resumable transfer was not negotiated"
+        )));
+    }
+
+    if request.version.is_some() && request.snapshot.is_some() {
+        bail!(RequestError::InvalidRequest(String::from(
+            "A file can't be synced from a version and a snapshot at once"
+        )));
+    }
+
+    let snapshot = snapshot_tree(context, &request.snapshot).await?;
+    let tree = snapshot.as_ref().unwrap_or(&context.virtualizer);
+
     let file_path = PathBuf::from(&file_to_sync.path);
-    let virtual_path = virtualizer.v_path(&file_path)?;
-    let file = tokio::fs::File::open(&virtual_path).await?;
+    let virtual_path = tree.v_path(&file_path)?;
+    let stored_path = match &request.version {
+        Some(version) => {
+            if !context.capabilities.has(Capability::Versions) {
+                bail!(RequestError::InvalidRequest(String::from(
+                    "Versions were not negotiated"
+                )));
+            }
 
-    let metadata = file.metadata().await?;
-    let file_stats =
-        file_manager::create_from_metadata(&file_path, Some(&virtual_path), &metadata).await?;
-    let size = file_stats.size.clone();
-    let request_sync = protos::create_response_sync(file_stats, size);
+            context
+                .versions
+                .find(virtual_path.true_path(), version)
+                .await?
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("No version {} of {}", version, file_to_sync.path),
+                    )
+                })?
+        }
+        None => virtual_path.to_path_buf(),
+    };
 
-    let request = protos::serialize_response_sync(request_sync);
-    handler.write_packet(&request).await?;
+    let metadata = fs::symlink_metadata(&stored_path).await?;
+    if !metadata.is_file() || stored_content::is_node(&stored_path).await? {
+        return send_node(
+            stream,
+            request,
+            context,
+            &file_path,
+            &stored_path,
+            &metadata,
+        )
+        .await;
+    }
+
+    let mut file = StoredContent::open(&stored_path, &context.chunks).await?;
+    let file_stats = stored_content::describe(&file_path, &stored_path, &metadata, true).await?;
+    let size = file_stats.size;
+    let offset = resume_offset(context, &stored_path, size, &request.partial).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    // The client only states a preference, skip it for files that won't shrink
+    let compression =
+        compression::choose(request.compression(), &context.capabilities, &virtual_path);
+
+    let response_sync = protos::create_response_sync(
+        file_stats,
+        size - offset,
+        signature.is_some(),
+        offset,
+        compression,
+        request.request_id,
+    );
 
+    envelope::send(stream, Body::ResponseSync(response_sync)).await?;
+
+    // The client now waits for the payload, a failure past this point can't be reported
     let mut file_reader = BufReader::new(file);
+    let mut compressor = Compressor::new(compression);
+    let result = if let Some(signature) = signature {
+        send_delta(stream, &mut file_reader, signature, &mut compressor).await
+    } else {
+        send_payload(stream, &mut file_reader, &mut compressor).await
+    };
 
-    let mut read_buf = [0u8; 66536];
+    result.map_err(|e| RequestError::Interrupted(format!("{:#}", e)).into())
+}
+
+async fn send_payload(
+    stream: &mut mux::Stream,
+    file_reader: &mut (impl AsyncRead + Unpin),
+    compressor: &mut Compressor,
+) -> anyhow::Result<()> {
+    let mut read_buf = [0u8; 32768];
     loop {
         let n = file_reader.read(&mut read_buf).await?;
         if n == 0 {
             break;
         }
 
-        handler.write_packet(&read_buf[..n]).await?;
+        envelope::send(stream, Body::Data(compressor.encode(&read_buf[..n]))).await?;
     }
 
+    envelope::send(stream, Body::EndOfFile(protos::EndOfFile {})).await?;
+
     Ok(())
 }
 
-async fn handle_client(
-    stream: TcpStream,
-    noise: snow::HandshakeState,
-    save_path: String,
-    peer_checker: PeerChecker,
-) -> anyhow::Result<()> {
-    let mut buf_reader = BufReader::new(stream);
-    let noise = handshake_handler::handle_handshake(&mut buf_reader, &peer_checker, noise).await?;
+async fn send_ack(stream: &mut mux::Stream, request_id: u64) -> anyhow::Result<()> {
+    let response = protos::create_response_ack(request_id);
+    envelope::send(stream, Body::ResponseAck(response)).await?;
 
-    let client_key = match noise.get_remote_static() {
-        Some(key) => key,
-        None => return Err(anyhow::anyhow!("No remote public key")),
+    Ok(())
+}
+
+async fn handle_request(
+    stream: &mut mux::Stream,
+    body: Body,
+    context: &ClientContext,
+) -> anyhow::Result<()> {
+    let changes_tree = matches!(
+        body,
+        Body::RequestAdd(_)
+            | Body::RequestChunks(_)
+            | Body::RequestOffer(_)
+            | Body::RequestMove(_)
+            | Body::RequestRemove(_)
+            | Body::RequestRestoreTrash(_)
+    );
+    let _tree = if changes_tree {
+        context.modified.store(true, Ordering::Relaxed);
+        Some(context.snapshots.change_tree().await)
+    } else {
+        None
     };
-    let public_key = BASE64_STANDARD.encode(client_key);
-    let user = peer_checker.get_peer(&public_key).unwrap();
-    println!("Client authenticated as: {}", user.username);
 
-    let user_path = Path::new(&save_path).join(&user.username);
-    if !fs::try_exists(&user_path).await? {
-        fs::create_dir(&user_path).await?;
+    match body {
+        Body::RequestAdd(request) => {
+            println!("Received add request: {:?}", request);
+
+            let request_id = request.request_id;
+            handle_add_request(stream, request, context).await?;
+            send_ack(stream, request_id).await?;
+
+            println!("File received");
+        }
+        Body::RequestChunks(request) => {
+            println!("Received chunks request: {:?}", request.file);
+
+            handle_chunks(stream, &request, context).await?;
+            send_ack(stream, request.request_id).await?;
+
+            println!("File received");
+        }
+        Body::RequestOffer(request) => {
+            println!("Received offer request: {:?}", request);
+
+            handle_offer(stream, &request, context).await?;
+        }
+        Body::RequestMove(request) => {
+            println!("Received move request: {:?}", request);
+
+            moves::handle_move(stream, &request, context).await?;
+        }
+        Body::RequestRemove(request) => {
+            println!("Received remove request: {:?}", request);
+
+            handle_delete(&request, context).await?;
+            send_ack(stream, request.request_id).await?;
+        }
+        Body::RequestGet(request) => {
+            println!("Received get request: {:?}", request);
+
+            handle_get(stream, context, &request).await?;
+        }
+        Body::RequestList(request) => {
+            println!("Received list request: {:?}", request);
+
+            listing::handle_list(stream, context, &request).await?;
+        }
+        Body::RequestStat(request) => {
+            println!("Received stat request: {:?}", request);
+
+            handle_stat(stream, context, &request).await?;
+        }
+        Body::RequestSync(request) => {
+            println!("Received sync request: {:?}", request);
+
+            handle_sync(stream, &request, context).await?;
+        }
+        Body::RequestVersions(request) => {
+            println!("Received versions request: {:?}", request);
+
+            versions::handle_versions(stream, context, &request).await?;
+        }
+        Body::RequestSnapshot(request) => {
+            println!("Received snapshot request: {:?}", request);
+
+            snapshots::handle_snapshot(stream, context, &request).await?;
+        }
+        Body::RequestSnapshots(request) => {
+            println!("Received snapshots request: {:?}", request);
+
+            snapshots::handle_snapshots(stream, context, &request).await?;
+        }
+        Body::RequestTrash(request) => {
+            println!("Received trash request: {:?}", request);
+
+            trash::handle_trash(stream, context, &request).await?;
+        }
+        Body::RequestRestoreTrash(request) => {
+            println!("Received trash restore request: {:?}", request);
+
+            trash::handle_restore_trash(stream, context, &request).await?;
+        }
+        Body::RequestEmptyTrash(request) => {
+            println!("Received empty trash request: {:?}", request);
+
+            trash::handle_empty_trash(stream, context, &request).await?;
+        }
+        Body::RequestResume(request) => {
+            println!("Received resume request: {:?}", request);
+
+            handle_resume(stream, &request, context).await?;
+        }
+        body => {
+            println!("Received {} instead of a request", envelope::name(&body));
+
+            // Whatever follows it can't be told apart from a new request
+            bail!(unexpected_message("a request", &body));
+        }
     }
 
-    let mut handler = packeter::Handler::new(buf_reader, noise);
-    let virtualizer = Virtualizer::new(user_path);
+    Ok(())
+}
 
-    while let Ok(msg) = handler.read_packet().await {
-        let request = deserialize_request(&msg)?;
+async fn handle_stream(mut stream: mux::Stream, context: &ClientContext) -> anyhow::Result<()> {
+    let body = envelope::receive(&mut stream).await;
+    let request_id = body
+        .as_ref()
+        .ok()
+        .and_then(envelope::request_id)
+        .unwrap_or_default();
+
+    let result = match body {
+        Ok(body) => handle_request(&mut stream, body, context).await,
+        Err(e) => Err(e.into()),
+    };
 
-        match RequestType::try_from(request.request_type) {
-            Ok(RequestType::Add) => {
-                let request = deserialize_request_add(&msg)?;
+    if let Err(e) = result {
+        if request_error::is_fatal(&e) {
+            return Err(e);
+        }
 
-                println!("Received add request: {:?}", request);
+        eprintln!("Request failed: {:#}", e);
 
-                handle_add_request(&mut handler, request, &virtualizer).await?;
+        let response = protos::create_response_error(
+            request_error::error_code(&e),
+            format!("{:#}", e),
+            request_id,
+        );
+        envelope::send(&mut stream, Body::ResponseError(response)).await?;
+    }
 
-                println!("File received");
-            }
-            Ok(RequestType::Move) => {
-                let request = deserialize_request_move(&msg)?;
+    Ok(())
+}
+
+async fn handle_client(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    noise: snow::HandshakeState,
+    config: ServerConfig,
+    peer_checker: PeerChecker,
+    pre_auth: Option<PreAuthPermit>,
+    session_limiter: SessionLimiter,
+    tree_locks: TreeLocks,
+) -> anyhow::Result<()> {
+    let save_path = &config.folder;
+    let session_limits = config.session;
+
+    // Clients that haven't authenticated get little time and no more than a few connections
+    let (handler, username, capabilities) = tokio::time::timeout(
+        session_limits.handshake_timeout(),
+        authenticate(
+            stream,
+            noise,
+            &peer_checker,
+            config.max_message_size,
+            &session_limits,
+        ),
+    )
+    .await
+    .context("Client didn't complete the handshake in time")??;
+    drop(pre_auth);
+    let _session = session_limiter
+        .acquire(&username)
+        .with_context(|| format!("Refused a session of {}, too many are open", username))?;
+
+    let user_path = Path::new(&save_path).join(&username);
+    if !fs::try_exists(&user_path).await? {
+        fs::create_dir(&user_path).await?;
+    }
 
-                println!("Received move request: {:?}", request);
+    let transfers_path = Path::new(&save_path).join(TRANSFERS_FOLDER).join(&username);
+    let transfers = TransferStore::open(transfers_path).await?;
+    if let Err(e) = transfers.prune().await {
+        eprintln!("Failed to prune partial transfers: {}", e);
+    }
 
-                handle_move(&request).await?;
-            }
-            Ok(RequestType::Remove) => {
-                let request = deserialize_request_remove(&msg)?;
+    let index_path = Path::new(&save_path).join(INDEX_FOLDER).join(&username);
+    let content_index = ContentIndex::open(index_path).await?;
 
-                println!("Received remove request: {:?}", request);
+    let chunks_path = Path::new(&save_path).join(CHUNKS_FOLDER).join(&username);
+    let chunks = ChunkStore::open(chunks_path).await?;
 
-                handle_delete(&request, &virtualizer).await?;
-            }
-            Ok(RequestType::Get) => {
-                let request = deserialize_request_get(&msg)?;
+    let versions_path = Path::new(&save_path).join(VERSIONS_FOLDER).join(&username);
+    let versions = VersionStore::open(versions_path, config.max_versions_per_file).await?;
 
-                println!("Received get request: {:?}", request);
+    let snapshots_path = Path::new(&save_path).join(SNAPSHOTS_FOLDER).join(&username);
+    let snapshots = SnapshotStore::open(
+        snapshots_path,
+        user_path.clone(),
+        tree_locks.get(&username),
+        config.max_snapshots,
+    )
+    .await?;
 
-                handle_get(&mut handler, &virtualizer, &request).await?;
+    let trash_path = Path::new(&save_path).join(TRASH_FOLDER).join(&username);
+    let trash = Trash::open(trash_path, config.trash_retention()).await?;
+    {
+        let chunks = chunks.clone();
+        // Versions, snapshots and the trash refer to chunks as much as the files themselves
+        let roots = [
+            user_path.clone(),
+            versions.dir().to_path_buf(),
+            snapshots.dir().to_path_buf(),
+            trash.dir().to_path_buf(),
+        ];
+        tokio::spawn(async move {
+            if let Err(e) = chunks.collect_garbage(&roots).await {
+                eprintln!("Failed to collect unused chunks: {:#}", e);
             }
-            Ok(RequestType::Sync) => {
-                let request = deserialize_request_sync(&msg)?;
+        });
+    }
 
-                println!("Received sync request: {:?}", request);
+    let keepalive = capabilities.has(Capability::Keepalive);
+    let context = Arc::new(ClientContext {
+        virtualizer: Virtualizer::new(&user_path)?,
+        capabilities,
+        transfers,
+        content_index,
+        chunks,
+        versions,
+        snapshots,
+        trash,
+        modified: AtomicBool::new(false),
+    });
+
+    // A client that doesn't answer pings is still dropped once it goes silent or idle
+    let mux =
+        Multiplexer::new(handler, Side::Responder).with_max_lifetime(session_limits.max_lifetime());
+    let mux = if keepalive {
+        mux.with_keepalive(session_limits.keepalive())
+    } else {
+        mux.with_timeouts(session_limits.keepalive())
+    };
+    while let Some(stream) = mux.accept().await {
+        let context = context.clone();
 
-                handle_sync(&mut handler, &request, &virtualizer).await?;
-            }
-            Err(_) => {
-                println!("Received unknown request type: {:?}", request.request_type);
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(stream, &context).await {
+                eprintln!("Error handling stream: {:#}", e);
             }
+        });
+    }
+
+    if let Some(ending) = mux.ending() {
+        println!("Session of {} ended: {}", username, ending);
+    }
+
+    if config.snapshot_sessions && context.modified.load(Ordering::Relaxed) {
+        match context.snapshots.take().await {
+            Ok(snapshot) => println!("Took snapshot {} of the session", snapshot.id),
+            Err(e) => eprintln!("Failed to take a snapshot of the session: {:#}", e),
         }
     }
 
     Ok(())
 }
 
+/// Runs the handshake and the hello, returning the session once the client is known.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    noise: snow::HandshakeState,
+    peer_checker: &PeerChecker,
+    max_message_size: usize,
+    session_limits: &SessionLimits,
+) -> anyhow::Result<(packeter::Handler<BufReader<S>>, String, Capabilities)> {
+    let mut buf_reader = BufReader::new(stream);
+    let noise = handshake_handler::handle_handshake(&mut buf_reader, peer_checker, noise).await?;
+
+    let client_key = match noise.get_remote_static() {
+        Some(key) => key,
+        None => return Err(anyhow::anyhow!("No remote public key")),
+    };
+    let public_key = BASE64_STANDARD.encode(client_key);
+    let username = peer_checker.get_peer(&public_key).unwrap().username.clone();
+    println!("Client authenticated as: {}", username);
+
+    let mut handler = packeter::Handler::new(buf_reader, noise)
+        .with_max_message_len(max_message_size)
+        .with_rekey_policy(session_limits.rekey_policy());
+    let capabilities = handshake_handler::handle_hello(&mut handler).await?;
+
+    Ok((handler, username, capabilities))
+}
+
+/// Serves a single session over stdin and stdout, for clients that bring their own transport.
+async fn serve_stdio(config: &Config, private_key: &[u8]) -> anyhow::Result<()> {
+    // Stdout carries the session, what we print goes to stderr instead
+    let session_out = std::io::stdout().as_fd().try_clone_to_owned()?;
+    nix::unistd::dup2(std::io::stderr().as_raw_fd(), std::io::stdout().as_raw_fd())?;
+    let transport = tokio::io::join(
+        tokio::io::stdin(),
+        fs::File::from_std(std::fs::File::from(session_out)),
+    );
+
+    let noise = snow::Builder::new(commons::NOISE_PARAMS.clone())
+        .local_private_key(private_key)
+        .build_responder()?;
+
+    handle_client(
+        transport,
+        noise,
+        config.server.clone(),
+        PeerChecker::new(config.peer.clone()),
+        None,
+        SessionLimiter::new(
+            config.server.max_sessions_per_user,
+            config.server.max_sessions,
+        ),
+        TreeLocks::default(),
+    )
+    .await
+}
+
 async fn init(_args: &Args, config: &ServerConfig) -> anyhow::Result<()> {
     let save_path = Path::new(&config.folder);
 
     if !fs::try_exists(&save_path).await? {
         fs::create_dir_all(&save_path).await?;
     }
+    stored_content::check_xattr_support(save_path).await?;
 
     Ok(())
 }
@@ -362,37 +1584,72 @@ async fn main() -> anyhow::Result<()> {
 
     init(&args, &config.server).await?;
 
+    let trash_path = Path::new(&config.server.folder).join(TRASH_FOLDER);
+    tokio::spawn(trash::purge_periodically(
+        trash_path,
+        config.server.trash_retention(),
+    ));
+
+    let private_key = BASE64_STANDARD.decode(&config.server.private_key)?;
+
+    if args.stdio {
+        return serve_stdio(&config, &private_key).await;
+    }
+
     let addr = format!("{}:{}", &args.addr, &config.server.port);
     let listener: TcpListener = TcpListener::bind(&addr)
         .await
         .with_context(|| format!("Failed to bind to {}", &addr))?;
     println!("Listening on {}", &addr);
 
-    let private_key = BASE64_STANDARD.decode(&config.server.private_key)?;
-
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_CONNECTIONS));
+    let pre_auth_limiter = PreAuthLimiter::new(
+        config.server.max_unauthenticated_per_address,
+        config.server.max_unauthenticated,
+    );
+    let session_limiter = SessionLimiter::new(
+        config.server.max_sessions_per_user,
+        config.server.max_sessions,
+    );
+    let tree_locks = TreeLocks::default();
 
     loop {
         match listener.accept().await {
-            Ok((stream, _addr)) => {
+            Ok((stream, addr)) => {
+                let Some(pre_auth) = pre_auth_limiter.acquire(addr.ip()) else {
+                    eprintln!(
+                        "Refused a connection from {}, too many are still authenticating",
+                        addr.ip()
+                    );
+                    continue;
+                };
+
                 println!("New client connected");
-                let permit = semaphore.clone().acquire_owned().await?;
 
                 let peer_checker = PeerChecker::new(config.peer.clone());
+                let session_limiter = session_limiter.clone();
+                let tree_locks = tree_locks.clone();
 
                 // Unwrap for now as it is unrecoverable if it happens
                 let noise = snow::Builder::new(commons::NOISE_PARAMS.clone())
                     .local_private_key(&private_key)
                     .build_responder()
                     .unwrap();
-                let save_folder = config.server.folder.clone();
+                let server_config = config.server.clone();
 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, noise, save_folder, peer_checker).await {
+                    if let Err(e) = handle_client(
+                        stream,
+                        noise,
+                        server_config,
+                        peer_checker,
+                        Some(pre_auth),
+                        session_limiter,
+                        tree_locks,
+                    )
+                    .await
+                    {
                         eprintln!("Error handling client: {}", e);
                     }
-
-                    drop(permit);
                 });
             }
             Err(err) => {
@@ -0,0 +1,251 @@
+use std::{ffi::OsStr, io, path::Path};
+
+use anyhow::bail;
+use commons::{envelope, file_manager, mux};
+use protos::{Body, Capability, ConflictPolicy, FileMove, MoveStatus, RequestMove};
+use tokio::fs;
+
+use crate::{
+    request_error::{self, RequestError},
+    send_ack, trash,
+    virtualizer::VirtualPath,
+    ClientContext,
+};
+
+/// Free names tried next to a taken one before the move fails.
+const MAX_RENAME_ATTEMPTS: u32 = 1000;
+
+/// A move of the batch that was applied, with what it takes to undo it.
+struct Applied {
+    source: VirtualPath,
+    destination: VirtualPath,
+    /// Where the entry the move replaced was set aside until the batch is done.
+    replaced: Option<VirtualPath>,
+    /// Folders created to hold the destination, outermost first.
+    created: Vec<VirtualPath>,
+}
+
+pub async fn exists(path: &Path) -> io::Result<bool> {
+    match fs::symlink_metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The name of `path` with ` (n)` added to it, before the extension.
+fn numbered(path: &Path, n: u32) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, n, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    }
+}
+
+pub async fn free_path(path: &VirtualPath) -> anyhow::Result<VirtualPath> {
+    for n in 1..=MAX_RENAME_ATTEMPTS {
+        let candidate = path.with_name(OsStr::new(&numbered(path.true_path(), n)));
+        if !exists(&candidate).await? {
+            return Ok(candidate);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("No free name left next to {}", path.true_path().display()),
+    )
+    .into())
+}
+
+pub async fn remove_created(created: &[VirtualPath]) {
+    for dir in created.iter().rev() {
+        let _ = fs::remove_dir(dir).await;
+    }
+}
+
+/// Applies a single move, leaving everything as it was if it fails.
+async fn apply(
+    context: &ClientContext,
+    file: &FileMove,
+    conflict: ConflictPolicy,
+) -> anyhow::Result<Applied> {
+    let virtualizer = &context.virtualizer;
+    let source = virtualizer.v_path(Path::new(&file.old_path))?;
+    let mut destination = virtualizer.v_path(Path::new(&file.new_path))?;
+    if source.true_path() == Path::new("/") || destination.true_path() == Path::new("/") {
+        bail!(RequestError::InvalidRequest(String::from(
+            "The root folder can't be moved or replaced"
+        )));
+    }
+
+    // Fails with `NotFound` when there is nothing to move
+    fs::symlink_metadata(&source).await?;
+    if source.true_path() == destination.true_path() {
+        return Ok(Applied {
+            source,
+            destination,
+            replaced: None,
+            created: Vec::new(),
+        });
+    }
+
+    if destination.true_path().starts_with(source.true_path()) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "A folder can't be moved into itself"
+        )));
+    }
+
+    let mut replaced = None;
+    if exists(&destination).await? {
+        match conflict {
+            ConflictPolicy::Fail => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", file.new_path),
+                )
+                .into());
+            }
+            ConflictPolicy::Overwrite => {
+                if source.true_path().starts_with(destination.true_path()) {
+                    bail!(RequestError::InvalidRequest(String::from(
+                        "An entry can't replace a folder it is in"
+                    )));
+                }
+
+                let aside = file_manager::temporary_path(&destination)?;
+                let aside = destination.with_name(aside.file_name().unwrap());
+                fs::rename(&destination, &aside).await?;
+                replaced = Some(aside);
+            }
+            ConflictPolicy::Rename => destination = free_path(&destination).await?,
+        }
+    }
+
+    let result = async {
+        let created = destination.create_parents()?;
+        if let Err(e) = fs::rename(&source, &destination).await {
+            remove_created(&created).await;
+            return Err(e);
+        }
+
+        Ok(created)
+    }
+    .await;
+
+    match result {
+        Ok(created) => Ok(Applied {
+            source,
+            destination,
+            replaced,
+            created,
+        }),
+        Err(e) => {
+            if let Some(aside) = &replaced {
+                let _ = fs::rename(aside, &destination).await;
+            }
+
+            Err(e.into())
+        }
+    }
+}
+
+/// Puts back what a move changed, once the moves applied after it were undone.
+async fn undo(applied: &Applied) -> io::Result<()> {
+    if applied.source.true_path() != applied.destination.true_path() {
+        fs::rename(&applied.destination, &applied.source).await?;
+    }
+    if let Some(aside) = &applied.replaced {
+        fs::rename(aside, &applied.destination).await?;
+    }
+    remove_created(&applied.created).await;
+
+    Ok(())
+}
+
+/// Applies the moves of the request in order, then keeps them all or none of them.
+pub async fn handle_move(
+    stream: &mut mux::Stream,
+    request: &RequestMove,
+    context: &ClientContext,
+) -> anyhow::Result<()> {
+    let conflict = ConflictPolicy::try_from(request.conflict).map_err(|_| {
+        RequestError::InvalidRequest(format!("Unknown conflict policy {}", request.conflict))
+    })?;
+
+    let mut applied = Vec::new();
+    let mut failure = None;
+    for file in &request.files {
+        match apply(context, file, conflict).await {
+            Ok(entry) => applied.push(entry),
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        }
+    }
+
+    if failure.is_some() {
+        for entry in applied.iter().rev() {
+            if let Err(e) = undo(entry).await {
+                eprintln!(
+                    "Failed to undo the move of {}: {}",
+                    entry.source.true_path().display(),
+                    e
+                );
+            }
+        }
+    } else {
+        for entry in &applied {
+            let Some(aside) = &entry.replaced else {
+                continue;
+            };
+
+            // Only what the kept batch replaced goes to the trash
+            let path = entry.destination.true_path();
+            if let Err(e) = trash::discard(context, path, aside).await {
+                eprintln!("Failed to remove replaced {}: {:#}", path.display(), e);
+            }
+        }
+    }
+
+    if !context.capabilities.has(Capability::AtomicMove) {
+        return match failure {
+            Some(e) => Err(e),
+            None => send_ack(stream, request.request_id).await,
+        };
+    }
+
+    let mut results = Vec::new();
+    for (index, file) in request.files.iter().enumerate() {
+        let result = match (&failure, applied.get(index)) {
+            (None, Some(entry)) => protos::create_move_result(
+                file.old_path.clone(),
+                entry.destination.true_path().to_string_lossy().into_owned(),
+                MoveStatus::Moved,
+                None,
+            ),
+            (Some(e), None) if index == applied.len() => protos::create_move_result(
+                file.old_path.clone(),
+                file.new_path.clone(),
+                MoveStatus::Failed,
+                Some((request_error::error_code(e), format!("{:#}", e))),
+            ),
+            _ => protos::create_move_result(
+                file.old_path.clone(),
+                file.new_path.clone(),
+                MoveStatus::Cancelled,
+                None,
+            ),
+        };
+        results.push(result);
+    }
+
+    if let Some(e) = &failure {
+        eprintln!("Move failed, none of the batch was kept: {:#}", e);
+    }
+
+    let response = protos::create_response_move(results, request.request_id);
+    envelope::send(stream, Body::ResponseMove(response)).await?;
+
+    Ok(())
+}
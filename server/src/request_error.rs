@@ -0,0 +1,100 @@
+use std::io;
+
+use commons::{
+    file_manager::CreateTemporaryFileError,
+    integrity::IntegrityError,
+    packeter::{ReadPacketError, WritePacketError},
+};
+use protos::ErrorCode;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error("Invalid path: {0}")]
+    PathInvalid(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Protocol violation: {0}")]
+    Protocol(String),
+    #[error("Transfer interrupted: {0}")]
+    Interrupted(String),
+}
+
+impl RequestError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            RequestError::PathInvalid(_) => ErrorCode::PathInvalid,
+            RequestError::InvalidRequest(_) | RequestError::Protocol(_) => {
+                ErrorCode::InvalidRequest
+            }
+            RequestError::Interrupted(_) => ErrorCode::Unknown,
+        }
+    }
+}
+
+fn io_error_code(error: &io::Error) -> ErrorCode {
+    match error.kind() {
+        io::ErrorKind::NotFound => ErrorCode::NotFound,
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+            ErrorCode::PermissionDenied
+        }
+        io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::FileTooLarge => {
+            ErrorCode::QuotaExceeded
+        }
+        io::ErrorKind::InvalidFilename
+        | io::ErrorKind::NotADirectory
+        | io::ErrorKind::IsADirectory
+        | io::ErrorKind::DirectoryNotEmpty => ErrorCode::PathInvalid,
+        _ => ErrorCode::Unknown,
+    }
+}
+
+/// Picks the error code reported to the client from the first cause that carries one.
+pub fn error_code(error: &anyhow::Error) -> ErrorCode {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<RequestError>() {
+            return error.code();
+        }
+
+        if cause.is::<IntegrityError>() {
+            return ErrorCode::HashMismatch;
+        }
+
+        if let Some(error) = cause.downcast_ref::<CreateTemporaryFileError>() {
+            match error {
+                CreateTemporaryFileError::ParentPathDoesNotExist => return ErrorCode::NotFound,
+                CreateTemporaryFileError::InvalidPath
+                | CreateTemporaryFileError::InvalidFileName => return ErrorCode::PathInvalid,
+                CreateTemporaryFileError::CreateError(_) => {}
+            }
+        }
+
+        if let Some(error) = cause.downcast_ref::<io::Error>() {
+            return io_error_code(error);
+        }
+
+        if cause.downcast_ref::<prost::DecodeError>().is_some() {
+            return ErrorCode::InvalidRequest;
+        }
+    }
+
+    ErrorCode::Unknown
+}
+
+/// Whether the client went away mid-request, as opposed to the request itself failing.
+pub fn is_interrupted(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<ReadPacketError>())
+}
+
+/// Errors after which the stream can't be trusted anymore and has to be dropped.
+pub fn is_fatal(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<ReadPacketError>()
+            || cause.is::<WritePacketError>()
+            || matches!(
+                cause.downcast_ref::<RequestError>(),
+                Some(RequestError::Protocol(_)) | Some(RequestError::Interrupted(_))
+            )
+    })
+}
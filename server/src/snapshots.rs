@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::bail;
+use commons::{attributes, envelope, file_manager, mux};
+use nix::{
+    errno::Errno,
+    sys::{
+        stat::{utimensat, UtimensatFlags},
+        time::TimeSpec,
+    },
+};
+use protos::{Body, Capability, RequestSnapshot, RequestSnapshots};
+use tokio::{
+    fs,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+use crate::{request_error::RequestError, virtualizer::Virtualizer, ClientContext};
+
+/// Digits of the creation time that makes a snapshot id, enough for any time in nanoseconds.
+const ID_DIGITS: usize = 20;
+
+/// The tree of a user as it was when it was taken.
+pub struct Snapshot {
+    pub id: String,
+    /// When it was taken, since the Unix epoch.
+    pub created: Duration,
+    /// Root of its tree.
+    pub path: PathBuf,
+}
+
+/// When the snapshot `id` was taken, `None` if it isn't a snapshot id.
+fn parse_snapshot_id(id: &str) -> Option<Duration> {
+    if id.len() != ID_DIGITS || !id.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(Duration::from_nanos(id.parse().ok()?))
+}
+
+/// Gives `target` the owner, mode, extended attributes and times of `source`.
+fn copy_metadata(source: &Path, target: &Path, metadata: &std::fs::Metadata) -> io::Result<()> {
+    std::os::unix::fs::lchown(target, Some(metadata.uid()), Some(metadata.gid()))?;
+    std::fs::set_permissions(target, Permissions::from_mode(metadata.mode()))?;
+    attributes::write_xattrs(target, &attributes::read_xattrs(source)?)?;
+
+    let accessed = TimeSpec::new(metadata.atime(), metadata.atime_nsec());
+    let modified = TimeSpec::new(metadata.mtime(), metadata.mtime_nsec());
+    utimensat(
+        None,
+        target,
+        &accessed,
+        &modified,
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+
+    Ok(())
+}
+
+/// Makes `target` another name of the entry at `source`.
+fn link_entry(source: &Path, target: &Path, metadata: &std::fs::Metadata) -> io::Result<()> {
+    match std::fs::hard_link(source, target) {
+        // A file with as many names as its file system allows is copied instead
+        Err(e) if e.raw_os_error() == Some(Errno::EMLINK as i32) && metadata.is_file() => {
+            std::fs::copy(source, target)?;
+            copy_metadata(source, target, metadata)
+        }
+        result => result,
+    }
+}
+
+/// Recreates the folders of the tree at `source` under `target`, every other entry being
+/// another name of the stored one. Entries removed during the walk are left out.
+fn link_tree(source: &Path, target: &Path) -> io::Result<()> {
+    let mut pending = vec![(source.to_path_buf(), target.to_path_buf())];
+    // Folders get their metadata once nothing is added to them anymore, innermost first
+    let mut dirs = Vec::new();
+    while let Some((source, target)) = pending.pop() {
+        let metadata = match std::fs::symlink_metadata(&source) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        if !metadata.is_dir() {
+            match link_entry(&source, &target, &metadata) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => continue,
+            }
+        }
+
+        std::fs::create_dir(&target)?;
+        match std::fs::read_dir(&source) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    pending.push((entry.path(), target.join(entry.file_name())));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        dirs.push((source, target, metadata));
+    }
+
+    for (source, target, metadata) in dirs.iter().rev() {
+        copy_metadata(source, target, metadata)?;
+    }
+
+    Ok(())
+}
+
+/// Locks of the trees of every user, shared by all their sessions.
+#[derive(Clone, Default)]
+pub struct TreeLocks {
+    locks: Arc<Mutex<HashMap<String, Arc<RwLock<()>>>>>,
+}
+
+impl TreeLocks {
+    /// Lock of the tree of `username`, held shared by requests changing it and exclusively
+    /// while a snapshot is taken.
+    pub fn get(&self, username: &str) -> Arc<RwLock<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.entry(String::from(username)).or_default().clone()
+    }
+}
+
+/// Point-in-time copies of the tree of a user.
+///
+/// Stored files are only ever replaced as a whole, never written in place, so a snapshot only
+/// needs its own folders, every other entry is another name of the stored one.
+#[derive(Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    /// The tree snapshots are taken of.
+    tree: PathBuf,
+    tree_lock: Arc<RwLock<()>>,
+    max_snapshots: usize,
+}
+
+impl SnapshotStore {
+    /// Keeps up to `max_snapshots` snapshots of `tree`, none at all with 0. Requests changing
+    /// the tree hold `tree_lock` through `change_tree`.
+    pub async fn open(
+        dir: PathBuf,
+        tree: PathBuf,
+        tree_lock: Arc<RwLock<()>>,
+        max_snapshots: usize,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self {
+            dir,
+            tree,
+            tree_lock,
+            max_snapshots,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Held by a request for as long as it changes the tree, so that snapshots never record it
+    /// half done.
+    pub async fn change_tree(&self) -> RwLockReadGuard<'_, ()> {
+        self.tree_lock.read().await
+    }
+
+    /// Records the tree as it is now, dropping the oldest snapshots past the ones to keep.
+    ///
+    /// Requests changing the tree are waited for, and held back until the walk is done.
+    pub async fn take(&self) -> anyhow::Result<Snapshot> {
+        if self.max_snapshots == 0 {
+            bail!(RequestError::InvalidRequest(String::from(
+                "The server keeps no snapshots"
+            )));
+        }
+
+        let created = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let id = format!("{:0width$}", created.as_nanos(), width = ID_DIGITS);
+        let path = self.dir.join(&id);
+
+        // Built aside so that a snapshot is listed only once it is complete
+        let temp_path = file_manager::temporary_path(&path)?;
+        let (source, target) = (self.tree.clone(), temp_path.clone());
+        // Held by the walk itself, which goes on even if we are cancelled
+        let tree = self.tree_lock.clone().write_owned().await;
+        let linked = tokio::task::spawn_blocking(move || {
+            let _tree = tree;
+            link_tree(&source, &target)
+        })
+        .await?;
+        let result = match linked {
+            Ok(()) => fs::rename(&temp_path, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&temp_path).await;
+            return Err(e.into());
+        }
+
+        if let Err(e) = self.prune().await {
+            eprintln!("Failed to drop old snapshots: {}", e);
+        }
+
+        Ok(Snapshot { id, created, path })
+    }
+
+    async fn prune(&self) -> io::Result<()> {
+        let snapshots = self.list().await?;
+        let excess = snapshots.len().saturating_sub(self.max_snapshots);
+        for snapshot in &snapshots[..excess] {
+            fs::remove_dir_all(&snapshot.path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Snapshots kept, oldest first.
+    pub async fn list(&self) -> io::Result<Vec<Snapshot>> {
+        let mut entries = fs::read_dir(&self.dir).await?;
+
+        let mut snapshots = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(id) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            // Snapshots still being taken are no snapshots yet
+            let Some(created) = parse_snapshot_id(&id) else {
+                continue;
+            };
+
+            snapshots.push(Snapshot {
+                id,
+                created,
+                path: entry.path(),
+            });
+        }
+        snapshots.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(snapshots)
+    }
+
+    /// The snapshot `id`, `None` if there is no such snapshot.
+    pub async fn find(&self, id: &str) -> io::Result<Option<Snapshot>> {
+        let Some(created) = parse_snapshot_id(id) else {
+            return Ok(None);
+        };
+
+        let path = self.dir.join(id);
+        match fs::symlink_metadata(&path).await {
+            Ok(_) => Ok(Some(Snapshot {
+                id: String::from(id),
+                created,
+                path,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn check_negotiated(context: &ClientContext) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Snapshots) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Snapshots were not negotiated"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Resolves paths in the snapshot `id` instead of the current tree, for requests reading from
+/// it.
+pub async fn tree(context: &ClientContext, id: &str) -> anyhow::Result<Virtualizer> {
+    check_negotiated(context)?;
+
+    let snapshot =
+        context.snapshots.find(id).await?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No snapshot {}", id))
+        })?;
+
+    Ok(Virtualizer::new(&snapshot.path)?)
+}
+
+/// Takes a snapshot of the tree of the user.
+pub async fn handle_snapshot(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &RequestSnapshot,
+) -> anyhow::Result<()> {
+    check_negotiated(context)?;
+
+    let snapshot = context.snapshots.take().await?;
+    println!("Took snapshot {}", snapshot.id);
+
+    let snapshot = protos::create_snapshot(snapshot.id, snapshot.created);
+    let response = protos::create_response_snapshot(snapshot, request.request_id);
+    envelope::send(stream, Body::ResponseSnapshot(response)).await?;
+
+    Ok(())
+}
+
+/// Lists the snapshots kept of the tree of the user, oldest first.
+pub async fn handle_snapshots(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &RequestSnapshots,
+) -> anyhow::Result<()> {
+    check_negotiated(context)?;
+
+    let snapshots = context
+        .snapshots
+        .list()
+        .await?
+        .into_iter()
+        .map(|snapshot| protos::create_snapshot(snapshot.id, snapshot.created))
+        .collect();
+
+    let response = protos::create_response_snapshots(snapshots, request.request_id);
+    envelope::send(stream, Body::ResponseSnapshots(response)).await?;
+
+    Ok(())
+}
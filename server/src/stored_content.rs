@@ -0,0 +1,217 @@
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use commons::{attributes, file_manager};
+use protos::{ExtendedAttribute, File, Manifest};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWriteExt, ReadBuf},
+};
+
+use crate::{
+    chunk_store::{ChunkReader, ChunkStore},
+    stored_flags,
+};
+
+/// Marks the stored files that hold the manifest of their content rather than the content.
+const MANIFEST_XATTR: &str = "trusted.zen-sync.manifest";
+/// Set on a throwaway file to check that the store can hold our attributes.
+const PROBE_XATTR: &str = "trusted.zen-sync.probe";
+
+/// Fails unless files in `folder` can be given `trusted.*` attributes, without them what we
+/// store would pass for plain content.
+pub async fn check_xattr_support(folder: &Path) -> anyhow::Result<()> {
+    let (_, temp_path) = file_manager::open_temporary_file(&folder.join("xattr-probe")).await?;
+    let marker = protos::create_extended_attribute(String::from(PROBE_XATTR), Vec::new());
+    let result = mark(&temp_path, marker).await;
+    let _ = fs::remove_file(&temp_path).await;
+
+    result.with_context(|| {
+        format!(
+            "Can't set trusted.* extended attributes in {}, which needs CAP_SYS_ADMIN and a \
+             filesystem that supports them",
+            folder.display()
+        )
+    })
+}
+
+/// Sets the attribute that tells what a stored file stands for on its temporary file, checked
+/// as a file left without it would pass for plain content.
+async fn mark(temp_path: &Path, marker: ExtendedAttribute) -> io::Result<()> {
+    let path = temp_path.to_path_buf();
+    tokio::task::spawn_blocking(move || attributes::write_xattrs(&path, &[marker])).await?
+}
+
+/// Manifest of a stored file kept as chunks, `None` for one stored whole.
+pub async fn read_manifest(virtual_path: &Path) -> anyhow::Result<Option<Manifest>> {
+    let path = virtual_path.to_path_buf();
+    let marker = tokio::task::spawn_blocking(move || attributes::read_xattr(&path, MANIFEST_XATTR))
+        .await??;
+    if marker.is_none() {
+        return Ok(None);
+    }
+
+    let mut manifest = Vec::new();
+    file_manager::open_noatime_nofollow(virtual_path)
+        .await?
+        .read_to_end(&mut manifest)
+        .await?;
+
+    Ok(Some(protos::deserialize_manifest(&manifest)?))
+}
+
+/// Stores `file` at `virtual_path` as the chunks of `manifest`, which must all be stored.
+pub async fn write_manifest(
+    virtual_path: &PathBuf,
+    file: &File,
+    manifest: &Manifest,
+) -> anyhow::Result<()> {
+    let stored = stored_flags::to_stored(file);
+    let marker = protos::create_extended_attribute(String::from(MANIFEST_XATTR), Vec::new());
+
+    let (mut temp_file, temp_path) = file_manager::open_temporary_file(virtual_path).await?;
+    let result = async {
+        temp_file
+            .write_all(&protos::serialize_manifest(manifest))
+            .await?;
+        mark(&temp_path, marker).await?;
+        let temp_file = temp_file.into_std().await;
+        file_manager::close_temporary_file(temp_file, &temp_path, virtual_path, &stored).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+
+    Ok(result?)
+}
+
+/// Whether the stored file at `virtual_path` stands for a FIFO or device node.
+pub async fn is_node(virtual_path: &Path) -> anyhow::Result<bool> {
+    let path = virtual_path.to_path_buf();
+    let marker = tokio::task::spawn_blocking(move || {
+        attributes::read_xattr(&path, stored_flags::NODE_XATTR)
+    })
+    .await??;
+
+    Ok(marker.is_some())
+}
+
+/// Stores the FIFO or device node `file` at `virtual_path` as an empty file that stands for
+/// it, a client could otherwise have the server make any device it likes.
+pub async fn write_node(virtual_path: &PathBuf, file: &File) -> anyhow::Result<()> {
+    let mut stored = stored_flags::to_stored(file);
+    let marker = stored
+        .xattrs
+        .iter()
+        .position(|xattr| xattr.name == stored_flags::NODE_XATTR)
+        .map(|index| stored.xattrs.remove(index))
+        .context("Entry isn't a FIFO or device node")?;
+
+    let (temp_file, temp_path) = file_manager::open_temporary_file(virtual_path).await?;
+    let result = async {
+        mark(&temp_path, marker).await?;
+        let temp_file = temp_file.into_std().await;
+        file_manager::close_temporary_file(temp_file, &temp_path, virtual_path, &stored).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+
+    Ok(result?)
+}
+
+/// Describes a stored entry as the client sent it, with the size and hash of its content
+/// rather than of its manifest for a file kept as chunks.
+pub async fn describe(
+    file_path: &PathBuf,
+    virtual_path: &PathBuf,
+    metadata: &std::fs::Metadata,
+    hash_content: bool,
+) -> anyhow::Result<File> {
+    let manifest = if metadata.is_file() {
+        read_manifest(virtual_path).await?
+    } else {
+        None
+    };
+
+    let mut file_stats =
+        file_manager::create_from_metadata(file_path, Some(virtual_path), metadata, false).await?;
+    match manifest {
+        Some(manifest) => {
+            file_stats.size = manifest.size;
+            if hash_content {
+                file_stats.hash = Some(manifest.hash);
+            }
+        }
+        None if hash_content && metadata.is_file() => {
+            let file = file_manager::open_noatime_nofollow(virtual_path).await?;
+            file_stats.hash = Some(file_manager::hash_reader(file).await?);
+        }
+        None => {}
+    }
+    stored_flags::from_stored(&mut file_stats);
+
+    Ok(file_stats)
+}
+
+/// Content of a stored file, whichever way it is kept.
+pub enum StoredContent {
+    Whole(fs::File),
+    Chunked(ChunkReader),
+}
+
+impl StoredContent {
+    pub async fn open(virtual_path: &Path, chunks: &ChunkStore) -> anyhow::Result<Self> {
+        match read_manifest(virtual_path).await? {
+            Some(manifest) => Ok(Self::Chunked(chunks.reader(manifest))),
+            None => Ok(Self::Whole(
+                file_manager::open_noatime_nofollow(virtual_path).await?,
+            )),
+        }
+    }
+
+    pub async fn size(&mut self) -> io::Result<u64> {
+        match self {
+            Self::Whole(file) => Ok(file.metadata().await?.len()),
+            Self::Chunked(reader) => Ok(reader.size()),
+        }
+    }
+}
+
+impl AsyncRead for StoredContent {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Whole(file) => Pin::new(file).poll_read(cx, buf),
+            Self::Chunked(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncSeek for StoredContent {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        match self.get_mut() {
+            Self::Whole(file) => Pin::new(file).start_seek(position),
+            Self::Chunked(reader) => Pin::new(reader).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        match self.get_mut() {
+            Self::Whole(file) => Pin::new(file).poll_complete(cx),
+            Self::Chunked(reader) => Pin::new(reader).poll_complete(cx),
+        }
+    }
+}
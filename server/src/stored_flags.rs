@@ -0,0 +1,155 @@
+use std::time::SystemTime;
+
+use commons::attributes::{FS_APPEND_FL, FS_IMMUTABLE_FL};
+use protos::{File, FileType};
+
+/// Flags that would keep us from ever replacing or removing our copy of a file.
+const LOCKING_FLAGS: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL;
+/// Attributes the server keeps on its copies for itself, never taken from or shown to clients.
+pub const INTERNAL_XATTR_PREFIX: &str = "trusted.zen-sync.";
+/// Where the flags of a stored copy are recorded when they can't be applied to it.
+const FLAGS_XATTR: &str = "trusted.zen-sync.flags";
+/// When a stored file was uploaded, in nanoseconds since the Unix epoch.
+pub const UPLOADED_XATTR: &str = "trusted.zen-sync.uploaded";
+/// Hash of the content of a stored file, as it was checked when uploaded.
+pub const HASH_XATTR: &str = "trusted.zen-sync.hash";
+/// Marks the stored files that stand for a FIFO or device node, recording its type, device
+/// number and mode. Only clients ever make the real node.
+pub const NODE_XATTR: &str = "trusted.zen-sync.node";
+/// Setuid, setgid and sticky bits, never applied to our copies.
+const SPECIAL_MODE_BITS: u32 = 0o7000;
+/// Where the mode of a stored copy is recorded when it has bits that aren't applied.
+const MODE_XATTR: &str = "trusted.zen-sync.mode";
+/// File capabilities, never applied to our copies either.
+const CAPABILITY_XATTR: &str = "security.capability";
+/// Where the file capabilities of a stored copy are recorded.
+const RECORDED_CAPABILITY_XATTR: &str = "trusted.zen-sync.capability";
+/// The only privileged attribute applied to our copies, the other `trusted.*` and
+/// `security.*` ones could grant or change privileges on the server.
+const SELINUX_XATTR: &str = "security.selinux";
+
+fn is_privileged(name: &str) -> bool {
+    (name.starts_with("trusted.") || name.starts_with("security.")) && name != SELINUX_XATTR
+}
+
+/// Metadata to give our copy of `file`, with its locking flags, special mode bits and file
+/// capabilities recorded instead of applied, its other privileged attributes left out, a FIFO
+/// or device node kept as an empty file, and a file stamped with when it was uploaded and its
+/// hash.
+pub fn to_stored(file: &File) -> File {
+    let mut stored = file.clone();
+    stored.xattrs.retain(|xattr| !is_privileged(&xattr.name));
+
+    if let Some(capability) = file
+        .xattrs
+        .iter()
+        .find(|xattr| xattr.name == CAPABILITY_XATTR)
+    {
+        stored.xattrs.push(protos::create_extended_attribute(
+            String::from(RECORDED_CAPABILITY_XATTR),
+            capability.value.clone(),
+        ));
+    }
+
+    if matches!(
+        stored.file_type(),
+        FileType::Fifo | FileType::CharDevice | FileType::BlockDevice
+    ) {
+        stored.xattrs.push(protos::create_extended_attribute(
+            String::from(NODE_XATTR),
+            format!(
+                "{} {} {}",
+                stored.file_type, stored.device, stored.file_permissions
+            )
+            .into_bytes(),
+        ));
+        stored.set_file_type(FileType::File);
+        stored.device = 0;
+        stored.size = 0;
+        stored.hash = None;
+        stored.file_permissions &= !SPECIAL_MODE_BITS;
+    } else if stored.file_permissions & SPECIAL_MODE_BITS != 0 {
+        stored.xattrs.push(protos::create_extended_attribute(
+            String::from(MODE_XATTR),
+            stored.file_permissions.to_string().into_bytes(),
+        ));
+        stored.file_permissions &= !SPECIAL_MODE_BITS;
+    }
+
+    if stored.file_type() == FileType::File {
+        let uploaded = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        stored.xattrs.push(protos::create_extended_attribute(
+            String::from(UPLOADED_XATTR),
+            uploaded.as_nanos().to_string().into_bytes(),
+        ));
+
+        if let Some(hash) = &file.hash {
+            stored.xattrs.push(protos::create_extended_attribute(
+                String::from(HASH_XATTR),
+                hash.clone(),
+            ));
+        }
+    }
+
+    if stored.flags & LOCKING_FLAGS != 0 {
+        stored.xattrs.push(protos::create_extended_attribute(
+            String::from(FLAGS_XATTR),
+            stored.flags.to_string().into_bytes(),
+        ));
+        stored.flags &= !LOCKING_FLAGS;
+    }
+
+    stored
+}
+
+/// Value of the internal attribute `name` of a stored copy, if it has one.
+fn recorded<'a>(file: &'a File, name: &str) -> Option<&'a str> {
+    let xattr = file.xattrs.iter().find(|xattr| xattr.name == name)?;
+    std::str::from_utf8(&xattr.value).ok()
+}
+
+/// Type, device number and mode of the node a stored copy stands for.
+fn recorded_node(file: &File) -> Option<(FileType, u64, u32)> {
+    let mut fields = recorded(file, NODE_XATTR)?.split(' ');
+    let file_type = FileType::try_from(fields.next()?.parse::<i32>().ok()?).ok()?;
+    let device = fields.next()?.parse().ok()?;
+    let mode = fields.next()?.parse().ok()?;
+
+    Some((file_type, device, mode))
+}
+
+/// Turns the description of a stored copy back into the one of the client's file.
+pub fn from_stored(file: &mut File) {
+    if let Some(flags) = recorded(file, FLAGS_XATTR).and_then(|flags| flags.parse().ok()) {
+        file.flags = flags;
+    }
+
+    if let Some(mode) = recorded(file, MODE_XATTR).and_then(|mode| mode.parse().ok()) {
+        file.file_permissions = mode;
+    }
+
+    let capability = file
+        .xattrs
+        .iter()
+        .find(|xattr| xattr.name == RECORDED_CAPABILITY_XATTR)
+        .map(|xattr| xattr.value.clone());
+    if let Some(capability) = capability {
+        file.xattrs.push(protos::create_extended_attribute(
+            String::from(CAPABILITY_XATTR),
+            capability,
+        ));
+    }
+
+    if let Some((file_type, device, mode)) = recorded_node(file) {
+        file.set_file_type(file_type);
+        file.device = device;
+        file.file_permissions = mode;
+        file.size = 0;
+        file.hash = None;
+    }
+
+    file.xattrs
+        .retain(|xattr| !xattr.name.starts_with(INTERNAL_XATTR_PREFIX));
+}
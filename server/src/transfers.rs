@@ -0,0 +1,129 @@
+use std::{
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::bail;
+use commons::file_manager;
+use protos::PartialTransfer;
+use tokio::fs;
+
+use crate::request_error::RequestError;
+
+/// Partial uploads nobody came back for are dropped after this long.
+const PARTIAL_TRANSFER_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+async fn remove_if_exists(path: &PathBuf) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Interrupted uploads of one user, kept under their transfer id until the client resumes them.
+pub struct TransferStore {
+    dir: PathBuf,
+}
+
+impl TransferStore {
+    pub async fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self { dir })
+    }
+
+    fn transfer_path(&self, transfer_id: &str) -> Result<PathBuf, RequestError> {
+        // Ids end up in file names, only accept the hex digests clients derive them from
+        if transfer_id.len() != 64 || !transfer_id.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(RequestError::InvalidRequest(String::from(
+                "Invalid transfer id",
+            )));
+        }
+
+        Ok(self.dir.join(transfer_id))
+    }
+
+    fn state_path(&self, transfer_id: &str) -> Result<PathBuf, RequestError> {
+        Ok(self.transfer_path(transfer_id)?.with_extension("state"))
+    }
+
+    /// Where an interrupted upload stopped, if it can still be resumed.
+    pub async fn lookup(&self, transfer_id: &str) -> anyhow::Result<Option<PartialTransfer>> {
+        let state_path = self.state_path(transfer_id)?;
+        let state = match fs::read(&state_path).await {
+            Ok(state) => state,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let partial = protos::deserialize_partial_transfer(&state)?;
+
+        let partial_path = file_manager::partial_file_path(&self.transfer_path(transfer_id)?)?;
+        match fs::metadata(&partial_path).await {
+            Ok(metadata) if metadata.len() >= partial.offset => Ok(Some(partial)),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens the partial file of a transfer to append from `offset`, dropping anything past it.
+    pub async fn open_partial(
+        &self,
+        transfer_id: &str,
+        offset: u64,
+    ) -> anyhow::Result<(fs::File, PathBuf)> {
+        if offset > 0 {
+            match self.lookup(transfer_id).await? {
+                Some(partial) if partial.offset >= offset => {}
+                _ => bail!(RequestError::InvalidRequest(format!(
+                    "Transfer can't be resumed from offset {}",
+                    offset
+                ))),
+            }
+        }
+
+        // The transfer is running again, its state is only written back if it gets interrupted
+        remove_if_exists(&self.state_path(transfer_id)?).await?;
+
+        let partial =
+            file_manager::open_partial_file(&self.transfer_path(transfer_id)?, offset).await?;
+
+        Ok(partial)
+    }
+
+    /// Records how far an interrupted upload got so the client can resume it later.
+    pub async fn suspend(&self, transfer_id: &str, file: fs::File) -> anyhow::Result<()> {
+        let file = file.into_std().await;
+        tokio::task::spawn_blocking(move || file.sync_all()).await??;
+
+        let partial_path = file_manager::partial_file_path(&self.transfer_path(transfer_id)?)?;
+        let offset = fs::metadata(&partial_path).await?.len();
+        let hash = file_manager::hash_prefix(&partial_path, offset).await?;
+        let state = protos::create_partial_transfer(offset, hash);
+
+        let state_path = self.state_path(transfer_id)?;
+        let temp_path = state_path.with_extension("state.tmp");
+        fs::write(&temp_path, protos::serialize_partial_transfer(state)).await?;
+        fs::rename(&temp_path, &state_path).await?;
+
+        Ok(())
+    }
+
+    /// Drops the partial uploads that haven't been touched for `PARTIAL_TRANSFER_LIFETIME`.
+    pub async fn prune(&self) -> io::Result<()> {
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+
+            if age > PARTIAL_TRANSFER_LIFETIME {
+                remove_if_exists(&entry.path()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
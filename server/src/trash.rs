@@ -0,0 +1,397 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::bail;
+use commons::{envelope, file_manager, mux};
+use protos::{
+    Body, Capability, ConflictPolicy, RequestEmptyTrash, RequestRestoreTrash, RequestTrash,
+};
+use tokio::fs;
+
+use crate::{
+    moves, request_error::RequestError, send_ack, stored_content, virtualizer::VirtualPath,
+    ClientContext,
+};
+
+/// Digits of the deletion time that starts a trash id, enough for any time in nanoseconds.
+const DELETED_DIGITS: usize = 20;
+/// Where the entry was in the tree of the user, next to the entry in its trash folder.
+const PATH_FILE: &str = "path";
+/// Name of the removed entry in its trash folder.
+const ENTRY_NAME: &str = "entry";
+/// How often the trash of every user is purged of expired entries.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// An entry removed from the tree of a user.
+pub struct TrashEntry {
+    pub id: String,
+    /// When it was removed, since the Unix epoch.
+    pub deleted: Duration,
+    /// Where the client knew it.
+    pub path: PathBuf,
+    /// Its trash folder, holding the entry and where it was.
+    dir: PathBuf,
+}
+
+impl TrashEntry {
+    /// Where the entry itself is kept.
+    pub fn stored_path(&self) -> PathBuf {
+        self.dir.join(ENTRY_NAME)
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// When the entry `id` was removed, `None` if it isn't a trash id.
+fn parse_trash_id(id: &str) -> Option<Duration> {
+    let (deleted, suffix) = id.split_once('-')?;
+    if deleted.len() != DELETED_DIGITS
+        || !deleted.bytes().all(|digit| digit.is_ascii_digit())
+        || suffix.is_empty()
+        || !suffix.bytes().all(|c| c.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+
+    Some(Duration::from_nanos(deleted.parse().ok()?))
+}
+
+/// Entries removed from the tree of a user, kept for `retention` before they are purged.
+#[derive(Clone)]
+pub struct Trash {
+    dir: PathBuf,
+    retention: Duration,
+}
+
+impl Trash {
+    /// Keeps removed entries for `retention`, with a zero retention they are removed right away.
+    pub async fn open(dir: PathBuf, retention: Duration) -> io::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self { dir, retention })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Moves the entry stored at `stored_path` into the trash, recording that the client knew
+    /// it as `path`.
+    async fn put(&self, path: &Path, stored_path: &Path) -> anyhow::Result<()> {
+        let id = format!(
+            "{:0width$}-{}",
+            now().as_nanos(),
+            file_manager::random_suffix(),
+            width = DELETED_DIGITS
+        );
+        let dir = self.dir.join(id);
+
+        // Filled aside so that an entry is listed only once it is complete
+        let temp_dir = file_manager::temporary_path(&dir)?;
+        fs::create_dir(&temp_dir).await?;
+        let entry_path = temp_dir.join(ENTRY_NAME);
+        let result = async {
+            fs::write(
+                temp_dir.join(PATH_FILE),
+                path.as_os_str().as_encoded_bytes(),
+            )
+            .await?;
+            fs::rename(stored_path, &entry_path).await
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&temp_dir).await;
+            return Err(e.into());
+        }
+
+        if let Err(e) = fs::rename(&temp_dir, &dir).await {
+            // The entry is only dropped along with its folder once it is back in place
+            if fs::rename(&entry_path, stored_path).await.is_ok() {
+                let _ = fs::remove_dir_all(&temp_dir).await;
+            }
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    async fn read_entry(&self, id: String, deleted: Duration) -> io::Result<Option<TrashEntry>> {
+        let dir = self.dir.join(&id);
+        let path = match fs::read(dir.join(PATH_FILE)).await {
+            Ok(path) => path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Ok(path) = String::from_utf8(path) else {
+            return Ok(None);
+        };
+
+        Ok(Some(TrashEntry {
+            id,
+            deleted,
+            path: PathBuf::from(path),
+            dir,
+        }))
+    }
+
+    /// Entries in the trash, oldest first.
+    pub async fn list(&self) -> io::Result<Vec<TrashEntry>> {
+        let mut dirs = fs::read_dir(&self.dir).await?;
+
+        let mut entries = Vec::new();
+        while let Some(dir) = dirs.next_entry().await? {
+            let Some(id) = dir.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            // Entries still being moved in aren't in the trash yet
+            let Some(deleted) = parse_trash_id(&id) else {
+                continue;
+            };
+
+            if let Some(entry) = self.read_entry(id, deleted).await? {
+                entries.push(entry);
+            }
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(entries)
+    }
+
+    /// The entry `id`, `None` if it isn't in the trash.
+    pub async fn find(&self, id: &str) -> io::Result<Option<TrashEntry>> {
+        match parse_trash_id(id) {
+            Some(deleted) => self.read_entry(String::from(id), deleted).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Drops an entry for good.
+    pub async fn remove(&self, entry: &TrashEntry) -> io::Result<()> {
+        match fs::remove_dir_all(&entry.dir).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops the entries removed longer than the retention period ago.
+    pub async fn purge(&self) -> io::Result<()> {
+        let now = now();
+        for entry in self.list().await? {
+            if now.saturating_sub(entry.deleted) > self.retention {
+                self.remove(&entry).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Purges the trash of every user under `dir` every `PURGE_INTERVAL`, for as long as the
+/// server runs.
+pub async fn purge_periodically(dir: PathBuf, retention: Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let mut users = match fs::read_dir(&dir).await {
+            Ok(users) => users,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                eprintln!("Failed to list the trash folders: {}", e);
+                continue;
+            }
+        };
+
+        while let Ok(Some(user)) = users.next_entry().await {
+            let trash = Trash {
+                dir: user.path(),
+                retention,
+            };
+            if let Err(e) = trash.purge().await {
+                eprintln!("Failed to purge {}: {}", user.path().display(), e);
+            }
+        }
+    }
+}
+
+/// Takes the entry stored at `virtual_path` out of the tree of the user, into the trash unless
+/// it keeps nothing. Removing the root folder removes everything in it instead.
+pub async fn remove(context: &ClientContext, virtual_path: &VirtualPath) -> anyhow::Result<()> {
+    let path = virtual_path.true_path();
+    if path == Path::new("/") {
+        for entry in virtual_path.entries().await? {
+            Box::pin(remove(context, &entry)).await?;
+        }
+
+        return Ok(());
+    }
+
+    discard(context, path, virtual_path).await
+}
+
+/// Takes the entry stored at `stored_path`, that the client knew as `path`, into the trash.
+/// Without a trash its files are kept as versions instead, never both.
+pub async fn discard(
+    context: &ClientContext,
+    path: &Path,
+    stored_path: &VirtualPath,
+) -> anyhow::Result<()> {
+    if !context.trash.retention.is_zero() {
+        return context.trash.put(path, stored_path).await;
+    }
+
+    context.versions.keep_tree(path, stored_path).await?;
+
+    if fs::symlink_metadata(stored_path).await?.is_dir() {
+        fs::remove_dir_all(stored_path).await?;
+    } else {
+        fs::remove_file(stored_path).await?;
+    }
+
+    Ok(())
+}
+
+fn check_negotiated(context: &ClientContext) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Trash) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "The trash was not negotiated"
+        )));
+    }
+
+    Ok(())
+}
+
+async fn find(context: &ClientContext, id: &str) -> anyhow::Result<TrashEntry> {
+    let entry = context.trash.find(id).await?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No entry {} in the trash", id),
+        )
+    })?;
+
+    Ok(entry)
+}
+
+/// Lists the entries in the trash of the user, oldest first.
+pub async fn handle_trash(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &RequestTrash,
+) -> anyhow::Result<()> {
+    check_negotiated(context)?;
+
+    let mut entries = Vec::new();
+    for entry in context.trash.list().await? {
+        let stored_path = entry.stored_path();
+        // Restored or emptied since it was listed
+        let metadata = match fs::symlink_metadata(&stored_path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let file = stored_content::describe(&entry.path, &stored_path, &metadata, false).await?;
+        entries.push(protos::create_trash_entry(
+            entry.id,
+            entry.path.to_string_lossy().into_owned(),
+            entry.deleted,
+            file,
+        ));
+    }
+
+    let response = protos::create_response_trash(entries, request.request_id);
+    envelope::send(stream, Body::ResponseTrash(response)).await?;
+
+    Ok(())
+}
+
+/// Puts an entry of the trash back in the tree of the user.
+pub async fn handle_restore_trash(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &RequestRestoreTrash,
+) -> anyhow::Result<()> {
+    check_negotiated(context)?;
+
+    let conflict = ConflictPolicy::try_from(request.conflict).map_err(|_| {
+        RequestError::InvalidRequest(format!("Unknown conflict policy {}", request.conflict))
+    })?;
+
+    let entry = find(context, &request.id).await?;
+    let path = match &request.path {
+        Some(path) => PathBuf::from(path),
+        None => entry.path.clone(),
+    };
+
+    let mut destination = context.virtualizer.v_path(&path)?;
+    if destination.true_path() == Path::new("/") {
+        bail!(RequestError::InvalidRequest(String::from(
+            "The root folder can't be replaced"
+        )));
+    }
+
+    if moves::exists(&destination).await? {
+        match conflict {
+            ConflictPolicy::Fail => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                )
+                .into());
+            }
+            // What is replaced goes to the trash in turn
+            ConflictPolicy::Overwrite => remove(context, &destination).await?,
+            ConflictPolicy::Rename => destination = moves::free_path(&destination).await?,
+        }
+    }
+
+    let created = destination.create_parents()?;
+    if let Err(e) = fs::rename(entry.stored_path(), &destination).await {
+        moves::remove_created(&created).await;
+        return Err(e.into());
+    }
+
+    if let Err(e) = context.trash.remove(&entry).await {
+        eprintln!("Failed to clear restored trash entry {}: {}", entry.id, e);
+    }
+
+    let response = protos::create_response_restore_trash(
+        destination.true_path().to_string_lossy().into_owned(),
+        request.request_id,
+    );
+    envelope::send(stream, Body::ResponseRestoreTrash(response)).await?;
+
+    Ok(())
+}
+
+/// Drops entries of the trash for good, all of them without ids.
+pub async fn handle_empty_trash(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &RequestEmptyTrash,
+) -> anyhow::Result<()> {
+    check_negotiated(context)?;
+
+    let entries = if request.ids.is_empty() {
+        context.trash.list().await?
+    } else {
+        let mut entries = Vec::new();
+        for id in &request.ids {
+            entries.push(find(context, id).await?);
+        }
+        entries
+    };
+
+    for entry in &entries {
+        context.trash.remove(entry).await?;
+    }
+
+    send_ack(stream, request.request_id).await
+}
@@ -0,0 +1,280 @@
+use std::{
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use commons::{attributes, envelope, mux};
+use protos::{Body, Capability, RequestVersions};
+use tokio::fs;
+
+use crate::{
+    chunk_store::{self, HASH_LEN},
+    request_error::RequestError,
+    stored_content, stored_flags,
+    virtualizer::VirtualPath,
+    ClientContext,
+};
+
+/// Digits of the upload time that starts a version id, enough for any time in nanoseconds.
+const UPLOADED_DIGITS: usize = 20;
+
+/// A kept version of a file.
+pub struct Version {
+    pub id: String,
+    /// When its content was uploaded, since the Unix epoch.
+    pub uploaded: Duration,
+    pub hash: Vec<u8>,
+    /// Where it is stored.
+    pub path: PathBuf,
+}
+
+fn version_id(uploaded: Duration, hash: &[u8]) -> String {
+    format!(
+        "{:0width$}-{}",
+        uploaded.as_nanos(),
+        chunk_store::hex(hash),
+        width = UPLOADED_DIGITS
+    )
+}
+
+/// Upload time and hash a version id stands for, `None` if it isn't one.
+fn parse_version_id(id: &str) -> Option<(Duration, Vec<u8>)> {
+    let (uploaded, hash) = id.split_once('-')?;
+    if uploaded.len() != UPLOADED_DIGITS
+        || !uploaded.bytes().all(|digit| digit.is_ascii_digit())
+        || hash.len() != HASH_LEN * 2
+    {
+        return None;
+    }
+
+    let hash = (0..hash.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hash.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    Some((Duration::from_nanos(uploaded.parse().ok()?), hash))
+}
+
+/// When the stored file at `stored_path` was uploaded and the hash of its content.
+async fn stamp(
+    path: &Path,
+    stored_path: &Path,
+    metadata: &std::fs::Metadata,
+) -> anyhow::Result<(Duration, Vec<u8>)> {
+    let clone = stored_path.to_path_buf();
+    let (uploaded, hash) = tokio::task::spawn_blocking(move || {
+        Ok::<_, io::Error>((
+            attributes::read_xattr(&clone, stored_flags::UPLOADED_XATTR)?,
+            attributes::read_xattr(&clone, stored_flags::HASH_XATTR)?,
+        ))
+    })
+    .await??;
+
+    let uploaded = uploaded
+        .and_then(|uploaded| String::from_utf8(uploaded).ok())
+        .and_then(|uploaded| uploaded.parse().ok())
+        .map(Duration::from_nanos)
+        // Files stored before uploads were stamped last changed when they were renamed in place
+        .unwrap_or_else(|| Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32));
+
+    let hash = match hash.filter(|hash| hash.len() == HASH_LEN) {
+        Some(hash) => hash,
+        None => stored_content::describe(
+            &path.to_path_buf(),
+            &stored_path.to_path_buf(),
+            metadata,
+            true,
+        )
+        .await?
+        .hash
+        .context("Stored file without a hash")?,
+    };
+
+    Ok((uploaded, hash))
+}
+
+/// Earlier contents of the files of a user, kept when they are replaced or removed.
+///
+/// Stored files are only ever replaced as a whole, never written in place, so a version is
+/// another name of the stored file it was, taken just before that file goes away.
+#[derive(Clone)]
+pub struct VersionStore {
+    dir: PathBuf,
+    max_per_file: usize,
+}
+
+impl VersionStore {
+    /// Keeps up to `max_per_file` versions of each file, none at all with 0.
+    pub async fn open(dir: PathBuf, max_per_file: usize) -> io::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self { dir, max_per_file })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Folder of the versions of the file the client knows as `path`.
+    fn file_dir(&self, path: &Path) -> PathBuf {
+        let key = blake3::hash(path.as_os_str().as_encoded_bytes());
+        chunk_store::hash_path(&self.dir, key.as_bytes()).unwrap()
+    }
+
+    /// Keeps the file stored at `stored_path` as a version of `path`, before it is removed or
+    /// replaced by content hashing to `replacement`. Only files have versions.
+    pub async fn keep(
+        &self,
+        path: &Path,
+        stored_path: &Path,
+        replacement: Option<&[u8]>,
+    ) -> anyhow::Result<()> {
+        if self.max_per_file == 0 {
+            return Ok(());
+        }
+
+        let metadata = match fs::symlink_metadata(stored_path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let (uploaded, hash) = stamp(path, stored_path, &metadata).await?;
+        // Sending the same content again isn't a new version
+        if replacement == Some(hash.as_slice()) {
+            return Ok(());
+        }
+
+        let dir = self.file_dir(path);
+        fs::create_dir_all(&dir).await?;
+        match fs::hard_link(stored_path, dir.join(version_id(uploaded, &hash))).await {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+
+        self.prune(&dir).await?;
+
+        Ok(())
+    }
+
+    /// Keeps every file under `stored_path` as a version of where it is under `path`, before
+    /// they are all removed.
+    pub async fn keep_tree(&self, path: &Path, stored_path: &VirtualPath) -> anyhow::Result<()> {
+        if self.max_per_file == 0 {
+            return Ok(());
+        }
+
+        let mut pending = vec![(path.to_path_buf(), stored_path.clone())];
+        while let Some((path, stored_path)) = pending.pop() {
+            if !fs::symlink_metadata(&stored_path).await?.is_dir() {
+                self.keep(&path, &stored_path, None).await?;
+                continue;
+            }
+
+            for entry in stored_path.entries().await? {
+                let name = entry.true_path().file_name().unwrap_or_default();
+                pending.push((path.join(name), entry));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the oldest versions in `dir` past the ones to keep.
+    async fn prune(&self, dir: &Path) -> io::Result<()> {
+        let versions = self.list_dir(dir).await?;
+        let excess = versions.len().saturating_sub(self.max_per_file);
+        for version in &versions[..excess] {
+            fs::remove_file(&version.path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_dir(&self, dir: &Path) -> io::Result<Vec<Version>> {
+        let mut entries = match fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut versions = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(id) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            // Temporary files of interrupted writes are no versions
+            let Some((uploaded, hash)) = parse_version_id(&id) else {
+                continue;
+            };
+
+            versions.push(Version {
+                id,
+                uploaded,
+                hash,
+                path: entry.path(),
+            });
+        }
+        versions.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(versions)
+    }
+
+    /// Versions of the file the client knows as `path`, oldest first.
+    pub async fn list(&self, path: &Path) -> io::Result<Vec<Version>> {
+        self.list_dir(&self.file_dir(path)).await
+    }
+
+    /// Where version `id` of `path` is stored, `None` if there is no such version.
+    pub async fn find(&self, path: &Path, id: &str) -> io::Result<Option<PathBuf>> {
+        if parse_version_id(id).is_none() {
+            return Ok(None);
+        }
+
+        let version_path = self.file_dir(path).join(id);
+        match fs::symlink_metadata(&version_path).await {
+            Ok(_) => Ok(Some(version_path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Lists the versions kept of a file, oldest first.
+pub async fn handle_versions(
+    stream: &mut mux::Stream,
+    context: &ClientContext,
+    request: &RequestVersions,
+) -> anyhow::Result<()> {
+    if !context.capabilities.has(Capability::Versions) {
+        bail!(RequestError::InvalidRequest(String::from(
+            "Versions were not negotiated"
+        )));
+    }
+
+    let virtual_path = context.virtualizer.v_path(Path::new(&request.path))?;
+    let path = virtual_path.true_path().to_path_buf();
+
+    let mut versions = Vec::new();
+    for version in context.versions.list(&path).await? {
+        // Pruned since it was listed
+        let metadata = match fs::symlink_metadata(&version.path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut file = stored_content::describe(&path, &version.path, &metadata, false).await?;
+        file.hash = Some(version.hash);
+        versions.push(protos::create_version(version.id, version.uploaded, file));
+    }
+
+    let response = protos::create_response_versions(versions, request.request_id);
+    envelope::send(stream, Body::ResponseVersions(response)).await?;
+
+    Ok(())
+}
@@ -0,0 +1,601 @@
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    ops::Deref,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{self, OFlag},
+    sys::stat::{self, Mode, SFlag},
+};
+
+use crate::request_error::RequestError;
+
+/// Symlinks followed while resolving a single path, the kernel gives up after as many.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+fn invalid(reason: &str) -> RequestError {
+    RequestError::PathInvalid(String::from(reason))
+}
+
+/// Path that reaches whatever `fd` is open on, wherever it was moved since.
+fn fd_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// Opens the folder `name` in `dir`, failing if it is anything else, a symlink included.
+fn open_dir(dir: &OwnedFd, name: &OsStr) -> io::Result<OwnedFd> {
+    let fd = fcntl::openat(
+        Some(dir.as_raw_fd()),
+        name,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// What a component of a path is, looked up without following it.
+enum Step {
+    Dir(OwnedFd),
+    Symlink(PathBuf),
+    Other,
+}
+
+fn step(dir: &OwnedFd, name: &OsStr) -> nix::Result<Step> {
+    let fd = fcntl::openat(
+        Some(dir.as_raw_fd()),
+        name,
+        OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // Checked on what was opened, it can't have been swapped since
+    let file_type = SFlag::from_bits_truncate(stat::fstat(fd.as_raw_fd())?.st_mode) & SFlag::S_IFMT;
+    match file_type {
+        SFlag::S_IFDIR => Ok(Step::Dir(fd)),
+        SFlag::S_IFLNK => Ok(Step::Symlink(PathBuf::from(fcntl::readlinkat(
+            Some(fd.as_raw_fd()),
+            "",
+        )?))),
+        _ => Ok(Step::Other),
+    }
+}
+
+/// Maps the paths a client sends to where their entries are kept, never outside the user's
+/// folder.
+pub struct Virtualizer {
+    root: Arc<OwnedFd>,
+}
+
+impl Virtualizer {
+    /// `user_path` must exist, it is opened once and every path is resolved beneath it.
+    pub fn new(user_path: &Path) -> io::Result<Self> {
+        let fd = fcntl::open(
+            user_path,
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+
+        Ok(Self {
+            root: Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+
+    /// Resolves a client path the way `openat2` does with `RESOLVE_BENEATH`: `.` and `..`
+    /// are applied, symlinks on the way are followed as long as they stay in the user's
+    /// folder, and anything leading out of it is refused. The last component is never
+    /// followed, a stored symlink is an entry of its own.
+    ///
+    /// Every folder on the way is opened without following it, so the entry can't be taken
+    /// out of the user's folder by swapping one of them for a symlink once it is resolved.
+    pub fn v_path(&self, path: &Path) -> Result<VirtualPath, RequestError> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| invalid("Path isn't valid UTF-8"))?;
+        if path.contains('\0') {
+            return Err(invalid("Path contains a NUL byte"));
+        }
+        if !path.starts_with('/') {
+            return Err(invalid("Path must start with /"));
+        }
+
+        // Components left to resolve, the next one last
+        let mut pending = Vec::new();
+        push_components(&mut pending, Path::new(path));
+
+        // Folders opened on the way with their names, the user's folder first
+        let mut dirs = vec![(self.root.clone(), OsString::new())];
+        // Components past the last folder, nothing under a missing entry is looked up
+        let mut rest = Vec::new();
+        let mut hops = 0;
+        while let Some(component) = pending.pop() {
+            if component == ".." {
+                if rest.pop().is_none() {
+                    if dirs.len() == 1 {
+                        return Err(invalid("Path leads out of the user's folder"));
+                    }
+                    dirs.pop();
+                }
+                continue;
+            }
+
+            if pending.is_empty() || !rest.is_empty() {
+                rest.push(component);
+                continue;
+            }
+
+            let (dir, _) = dirs.last().unwrap();
+            match step(dir, &component) {
+                Ok(Step::Dir(fd)) => dirs.push((Arc::new(fd), component)),
+                Ok(Step::Symlink(target)) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(invalid("Path goes through too many symlinks"));
+                    }
+
+                    if target.has_root() {
+                        return Err(invalid(
+                            "Path goes through a symlink leading out of the user's folder",
+                        ));
+                    }
+
+                    push_components(&mut pending, &target);
+                }
+                Ok(Step::Other) | Err(_) => rest.push(component),
+            }
+        }
+
+        // A path naming a folder that was opened is the entry of that folder in its parent
+        if rest.is_empty() && dirs.len() > 1 {
+            let (_, name) = dirs.pop().unwrap();
+            rest.push(name);
+        }
+
+        let dir_path = dirs
+            .iter()
+            .skip(1)
+            .fold(PathBuf::from("/"), |path, (_, name)| path.join(name));
+        let (dir, _) = dirs.pop().unwrap();
+        let name = rest.pop();
+
+        Ok(VirtualPath::new(dir, dir_path, rest, name))
+    }
+}
+
+/// An entry of the user's folder, as the name it has in a folder that is held open.
+///
+/// It derefs to a path going through that folder's descriptor, which file operations take
+/// without following its last component. Under folders that don't exist yet the path is
+/// empty, on which every operation fails with `NotFound`, until `create_parents` makes them.
+#[derive(Clone, Debug)]
+pub struct VirtualPath {
+    dir: Arc<OwnedFd>,
+    /// Path of `dir` as the client knows it.
+    dir_path: PathBuf,
+    /// Folders missing between `dir` and the entry.
+    missing: Vec<OsString>,
+    /// `None` for the user's folder itself.
+    name: Option<OsString>,
+    true_path: PathBuf,
+    path: PathBuf,
+}
+
+impl VirtualPath {
+    fn new(
+        dir: Arc<OwnedFd>,
+        dir_path: PathBuf,
+        missing: Vec<OsString>,
+        name: Option<OsString>,
+    ) -> Self {
+        let mut true_path = dir_path.clone();
+        true_path.extend(&missing);
+        true_path.extend(&name);
+
+        let path = match &name {
+            None => fd_path(&dir).join("."),
+            Some(name) if missing.is_empty() => fd_path(&dir).join(name),
+            Some(_) => PathBuf::new(),
+        };
+
+        Self {
+            dir,
+            dir_path,
+            missing,
+            name,
+            true_path,
+            path,
+        }
+    }
+
+    /// The path of the entry as the client knows it, symlinks on the way resolved.
+    pub fn true_path(&self) -> &Path {
+        &self.true_path
+    }
+
+    /// The entry named `name` next to this one.
+    pub fn with_name(&self, name: &OsStr) -> VirtualPath {
+        VirtualPath::new(
+            self.dir.clone(),
+            self.dir_path.clone(),
+            self.missing.clone(),
+            Some(name.to_os_string()),
+        )
+    }
+
+    /// Creates the folders missing above the entry, returning them outermost first.
+    pub fn create_parents(&mut self) -> io::Result<Vec<VirtualPath>> {
+        let mut dir = self.dir.clone();
+        let mut dir_path = self.dir_path.clone();
+        let mut created = Vec::new();
+        for name in &self.missing {
+            let folder = VirtualPath::new(
+                dir.clone(),
+                dir_path.clone(),
+                Vec::new(),
+                Some(name.clone()),
+            );
+            let result = match stat::mkdirat(
+                Some(dir.as_raw_fd()),
+                name.as_os_str(),
+                Mode::from_bits_truncate(0o777),
+            ) {
+                Ok(()) => {
+                    created.push(folder);
+                    Ok(())
+                }
+                // Made by another request in the meantime
+                Err(Errno::EEXIST) => Ok(()),
+                Err(e) => Err(io::Error::from(e)),
+            }
+            .and_then(|()| open_dir(&dir, name));
+
+            match result {
+                Ok(fd) => {
+                    dir = Arc::new(fd);
+                    dir_path.push(name);
+                }
+                Err(e) => {
+                    for folder in created.iter().rev() {
+                        let _ = std::fs::remove_dir(&folder.path);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        *self = VirtualPath::new(dir, dir_path, Vec::new(), self.name.take());
+
+        Ok(created)
+    }
+
+    /// The entries of this folder, each held through the folder as it is opened now.
+    pub async fn entries(&self) -> io::Result<Vec<VirtualPath>> {
+        let folder = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let dir = match &folder.name {
+                None => folder.dir.clone(),
+                Some(name) if folder.missing.is_empty() => Arc::new(open_dir(&folder.dir, name)?),
+                Some(_) => return Err(io::Error::from(io::ErrorKind::NotFound)),
+            };
+
+            let mut entries = Vec::new();
+            for entry in std::fs::read_dir(fd_path(&dir))? {
+                entries.push(VirtualPath::new(
+                    dir.clone(),
+                    folder.true_path.clone(),
+                    Vec::new(),
+                    Some(entry?.file_name()),
+                ));
+            }
+
+            Ok(entries)
+        })
+        .await?
+    }
+}
+
+impl Deref for VirtualPath {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for VirtualPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Queues the components of `path` in front of the ones already pending, `.` and roots
+/// dropped.
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    let components = path.components().filter_map(|component| match component {
+        Component::Normal(name) => Some(name.to_os_string()),
+        Component::ParentDir => Some(OsString::from("..")),
+        Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+    });
+
+    let start = pending.len();
+    pending.extend(components);
+    pending[start..].reverse();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::{ffi::OsStrExt, fs::symlink},
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A user folder holding `dir/file`, next to another user's `secret`.
+    fn setup() -> (TempDir, Virtualizer) {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("user/dir")).unwrap();
+        fs::write(root.path().join("user/dir/file"), "").unwrap();
+        fs::create_dir_all(root.path().join("other")).unwrap();
+        fs::write(root.path().join("other/secret"), "").unwrap();
+
+        let virtualizer = Virtualizer::new(&root.path().join("user")).unwrap();
+        (root, virtualizer)
+    }
+
+    fn user_path(root: &TempDir) -> PathBuf {
+        fs::canonicalize(root.path().join("user")).unwrap()
+    }
+
+    /// Where the entry `path` resolves to is, going by where its folder is now.
+    fn resolved(virtualizer: &Virtualizer, path: &str) -> PathBuf {
+        let virtual_path = virtualizer.v_path(Path::new(path)).unwrap();
+        let mut resolved = fs::read_link(fd_path(&virtual_path.dir)).unwrap();
+        resolved.extend(&virtual_path.missing);
+        resolved.extend(&virtual_path.name);
+        resolved
+    }
+
+    fn assert_invalid(virtualizer: &Virtualizer, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        assert!(
+            matches!(virtualizer.v_path(path), Err(RequestError::PathInvalid(_))),
+            "{} resolved to {:?}",
+            path.display(),
+            virtualizer.v_path(path)
+        );
+    }
+
+    #[test]
+    fn resolves_plain_paths() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+
+        assert_eq!(resolved(&virtualizer, "/"), user);
+        assert_eq!(resolved(&virtualizer, "/dir/file"), user.join("dir/file"));
+        assert_eq!(
+            resolved(&virtualizer, "/missing/deeper/file"),
+            user.join("missing/deeper/file")
+        );
+    }
+
+    #[test]
+    fn normalizes_dot_components() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+
+        for path in [
+            "/./dir/./file",
+            "//dir//file",
+            "/dir/file/",
+            "/dir/../dir/file",
+            "/missing/../dir/file",
+        ] {
+            assert_eq!(
+                resolved(&virtualizer, path),
+                user.join("dir/file"),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(resolved(&virtualizer, "/dir/.."), user);
+    }
+
+    #[test]
+    fn refuses_parent_components_leaving_the_folder() {
+        let (_root, virtualizer) = setup();
+
+        for path in [
+            "/..",
+            "/../other/secret",
+            "/dir/../../other/secret",
+            "/dir/../..",
+            "/./../user/dir/file",
+            "/missing/../../other/secret",
+            "/../../../../../../etc/passwd",
+        ] {
+            assert_invalid(&virtualizer, path);
+        }
+    }
+
+    #[test]
+    fn refuses_relative_paths() {
+        let (_root, virtualizer) = setup();
+
+        for path in ["", "dir/file", "../other/secret", "./dir", "~/dir"] {
+            assert_invalid(&virtualizer, path);
+        }
+    }
+
+    #[test]
+    fn refuses_nul_bytes_and_non_utf8() {
+        let (_root, virtualizer) = setup();
+
+        assert_invalid(&virtualizer, "/dir/fi\0le");
+        assert_invalid(&virtualizer, "/\0/../other/secret");
+        assert_invalid(&virtualizer, OsStr::from_bytes(b"/dir/\xff\xfe"));
+    }
+
+    #[test]
+    fn follows_symlinks_staying_in_the_folder() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+        symlink("dir", user.join("alias")).unwrap();
+        symlink("../dir", user.join("dir/up")).unwrap();
+        symlink("alias", user.join("chain")).unwrap();
+
+        assert_eq!(resolved(&virtualizer, "/alias/file"), user.join("dir/file"));
+        assert_eq!(
+            resolved(&virtualizer, "/dir/up/file"),
+            user.join("dir/file")
+        );
+        assert_eq!(resolved(&virtualizer, "/chain/file"), user.join("dir/file"));
+        // `..` applies to where the symlink led
+        assert_eq!(resolved(&virtualizer, "/dir/up/../dir"), user.join("dir"));
+    }
+
+    #[test]
+    fn keeps_the_last_component_a_symlink() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+        symlink("../other/secret", user.join("leak")).unwrap();
+        symlink("/etc/passwd", user.join("dir/absolute")).unwrap();
+
+        assert_eq!(resolved(&virtualizer, "/leak"), user.join("leak"));
+        assert_eq!(
+            resolved(&virtualizer, "/dir/absolute"),
+            user.join("dir/absolute")
+        );
+    }
+
+    #[test]
+    fn refuses_symlinks_leaving_the_folder() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+        symlink("../other", user.join("escape")).unwrap();
+        symlink("../../other", user.join("dir/escape")).unwrap();
+        symlink("/", user.join("absolute")).unwrap();
+        symlink(user.join("dir"), user.join("absolute_inside")).unwrap();
+        symlink("escape", user.join("chain")).unwrap();
+        symlink(".", user.join("dir/here")).unwrap();
+
+        for path in [
+            "/escape/secret",
+            "/dir/escape/secret",
+            "/absolute/etc/passwd",
+            "/absolute_inside/file",
+            "/chain/secret",
+            "/dir/here/here/../../other/secret",
+            "/missing/../escape/secret",
+        ] {
+            assert_invalid(&virtualizer, path);
+        }
+    }
+
+    #[test]
+    fn refuses_symlink_loops() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+        symlink("loop", user.join("loop")).unwrap();
+        symlink("pong", user.join("ping")).unwrap();
+        symlink("ping", user.join("pong")).unwrap();
+
+        assert_invalid(&virtualizer, "/loop/file");
+        assert_invalid(&virtualizer, "/ping/file");
+    }
+
+    #[test]
+    fn resolves_beneath_a_user_folder_reached_through_a_symlink() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("real/dir")).unwrap();
+        symlink("real", root.path().join("user")).unwrap();
+        let virtualizer = Virtualizer::new(&root.path().join("user")).unwrap();
+        let real = fs::canonicalize(root.path().join("real")).unwrap();
+
+        assert_eq!(resolved(&virtualizer, "/dir/file"), real.join("dir/file"));
+        assert_eq!(
+            virtualizer
+                .v_path(Path::new("/dir/file"))
+                .unwrap()
+                .true_path(),
+            Path::new("/dir/file")
+        );
+    }
+
+    #[test]
+    fn reports_the_paths_the_client_knows() {
+        let (root, virtualizer) = setup();
+        symlink("dir", user_path(&root).join("alias")).unwrap();
+
+        for (path, true_path) in [
+            ("/", "/"),
+            ("/dir/..", "/"),
+            ("/dir", "/dir"),
+            ("/alias/file", "/dir/file"),
+            ("/alias", "/alias"),
+            ("/missing/../alias/deeper/file", "/dir/deeper/file"),
+        ] {
+            let virtual_path = virtualizer.v_path(Path::new(path)).unwrap();
+            assert_eq!(virtual_path.true_path(), Path::new(true_path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn keeps_to_the_folders_it_resolved() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+        let virtual_path = virtualizer.v_path(Path::new("/dir/file")).unwrap();
+
+        // Swapped once resolved, the way a client could from another stream
+        fs::rename(user.join("dir"), user.join("moved")).unwrap();
+        symlink("../other", user.join("dir")).unwrap();
+        fs::write(user.join("moved/file"), "kept").unwrap();
+
+        assert_eq!(fs::read_to_string(&*virtual_path).unwrap(), "kept");
+        assert_eq!(
+            fs::read_to_string(root.path().join("other/secret")).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn creates_missing_parents_without_following_symlinks() {
+        let (root, virtualizer) = setup();
+        let user = user_path(&root);
+
+        let mut virtual_path = virtualizer
+            .v_path(Path::new("/dir/new/deeper/file"))
+            .unwrap();
+        assert_eq!(*virtual_path, PathBuf::new());
+        let created = virtual_path.create_parents().unwrap();
+        assert_eq!(created.len(), 2);
+        fs::write(&*virtual_path, "").unwrap();
+        assert!(user.join("dir/new/deeper/file").exists());
+
+        let mut virtual_path = virtualizer.v_path(Path::new("/escape/file")).unwrap();
+        symlink("../other", user.join("escape")).unwrap();
+        assert!(virtual_path.create_parents().is_err());
+    }
+
+    #[tokio::test]
+    async fn lists_entries_through_their_folder() {
+        let (root, virtualizer) = setup();
+        symlink("../other", user_path(&root).join("escape")).unwrap();
+
+        let dir = virtualizer.v_path(Path::new("/dir")).unwrap();
+        let entries = dir.entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].true_path(), Path::new("/dir/file"));
+
+        let escape = virtualizer.v_path(Path::new("/escape")).unwrap();
+        assert!(escape.entries().await.is_err());
+    }
+}